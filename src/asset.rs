@@ -0,0 +1,204 @@
+use std::collections::{BTreeMap, HashMap};
+use serde::{Serialize, Deserialize};
+
+use crate::coin::Stack;
+use crate::database::Collection;
+
+// COLLECTION stores the assets, next to coin_info
+pub const COLLECTION: &str = "assets";
+
+// Asset is a canonical asset and the ids providers know it by
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Asset {
+  pub id: String,
+  pub symbol: String,
+  // ids maps a provider name to the asset's id at that provider
+  #[serde(default)]
+  pub ids: HashMap<String, String>,
+}
+
+// Collision lists the canonical assets sharing a symbol
+#[derive(Debug, Clone, PartialEq)]
+pub struct Collision {
+  pub symbol: String,
+  pub assets: Vec<String>,
+}
+
+// Registry resolves provider ids to canonical asset ids
+#[derive(Debug, Clone, Default)]
+pub struct Registry {
+  canonical: HashMap<(String, String), String>,
+}
+
+impl Registry {
+  pub fn new(assets: &[Asset]) -> Self {
+    let mut canonical = HashMap::new();
+    for asset in assets {
+      for (provider, id) in &asset.ids {
+        canonical.insert((provider.to_owned(), id.to_owned()), asset.id.to_owned());
+      }
+    }
+    Self { canonical }
+  }
+
+  pub fn load(coll: &impl Collection<Asset>) -> Result<Self, String> {
+    Ok(Self::new(&coll.find_all()?))
+  }
+
+  // canonical_id gives the canonical id of a provider's coin id.
+  // Unknown ids are their own canonical id.
+  pub fn canonical_id(&self, provider: &str, id: &str) -> String {
+    match self.canonical.get(&(provider.to_string(), id.to_string())) {
+      Some(c) => c.to_owned(),
+      None => id.to_string(),
+    }
+  }
+
+  // canonicalize rewrites the coins of a stack fetched
+  // from provider with their canonical ids
+  pub fn canonicalize(&self, provider: &str, mut stack: Stack) -> Stack {
+    stack.coins = stack
+      .coins
+      .drain()
+      .map(|(id, mut coin)| {
+        let canonical = self.canonical_id(provider, &id);
+        coin.id = canonical.to_owned();
+        (canonical, coin)
+      })
+      .collect();
+    stack
+  }
+}
+
+// collisions finds the symbols held by more than one asset
+pub fn collisions(assets: &[Asset]) -> Vec<Collision> {
+  let mut by_symbol: BTreeMap<String, Vec<String>> = BTreeMap::new();
+  for asset in assets {
+    by_symbol.entry(asset.symbol.to_lowercase()).or_default().push(asset.id.to_owned());
+  }
+
+  by_symbol
+    .into_iter()
+    .filter(|(_, ids)| ids.len() > 1)
+    .map(|(symbol, mut assets)| {
+      assets.sort();
+      Collision { symbol, assets }
+    })
+    .collect()
+}
+
+// register adds the coins tracked by provider to the registry.
+// A coin id no asset maps yet becomes its own canonical asset: symbols
+// are never used to join assets, as tickers get reused across chains.
+// Returns the symbol collisions, to be solved with map.
+pub fn register(
+  coll: &impl Collection<Asset>,
+  provider: &str,
+  coins: &HashMap<String, String>,
+) -> Result<Vec<Collision>, String> {
+  let mut assets = coll.find_all()?;
+  let registry = Registry::new(&assets);
+
+  for (id, symbol) in coins {
+    if registry.canonical.contains_key(&(provider.to_string(), id.to_owned())) {
+      continue;
+    }
+    let asset = match assets.iter_mut().find(|a| &a.id == id) {
+      Some(a) => {
+        a.ids.insert(provider.to_string(), id.to_owned());
+        a.to_owned()
+      },
+      None => {
+        let mut ids = HashMap::new();
+        ids.insert(provider.to_string(), id.to_owned());
+        let asset = Asset { id: id.to_owned(), symbol: symbol.to_owned(), ids };
+        assets.push(asset.to_owned());
+        asset
+      },
+    };
+    coll.save(asset.id.to_owned(), &asset)?;
+  }
+
+  Ok(collisions(&assets))
+}
+
+// map makes provider's id resolve to the canonical asset id,
+// creating the asset if needed. An asset left without any
+// provider id by the move is removed.
+pub fn map(coll: &impl Collection<Asset>, id: &str, provider: &str, provider_id: &str) -> Result<(), String> {
+  let assets = coll.find_all()?;
+  let mut symbol = provider_id.to_string();
+
+  for mut asset in assets.iter().filter(|a| a.id != id).cloned() {
+    if asset.ids.get(provider).map(|i| i.as_str()) != Some(provider_id) {
+      continue;
+    }
+    symbol = asset.symbol.to_owned();
+    asset.ids.remove(provider);
+    if asset.ids.is_empty() {
+      coll.delete(asset.id)?;
+    } else {
+      coll.save(asset.id.to_owned(), &asset)?;
+    }
+  }
+
+  let mut asset = match assets.into_iter().find(|a| a.id == id) {
+    Some(a) => a,
+    None => Asset { id: id.to_string(), symbol, ids: HashMap::new() },
+  };
+  asset.ids.insert(provider.to_string(), provider_id.to_string());
+  coll.save(id.to_string(), &asset)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+  use crate::coin::{Coin, Stack};
+  use crate::database::{Collection, MemoryDB};
+  use super::{map, register, Asset, Collision, Registry};
+
+  fn coins_of(coins: Vec<(&str, &str)>) -> HashMap<String, String> {
+    coins.into_iter().map(|(id, s)| (id.to_string(), s.to_string())).collect()
+  }
+
+  #[test]
+  fn i_should_register_and_map_provider_ids() {
+    let coll = MemoryDB::new().new_collection::<Asset>("assets");
+    register(&coll, "coingecko", &coins_of(vec![("avalanche-2", "avax")])).unwrap();
+    register(&coll, "exchange", &coins_of(vec![("AVAX", "avax")])).unwrap();
+    assert_eq!(coll.find_all().unwrap().len(), 2);
+
+    map(&coll, "avalanche-2", "exchange", "AVAX").unwrap();
+    assert_eq!(coll.find_all().unwrap().len(), 1);
+
+    let registry = Registry::load(&coll).unwrap();
+    assert_eq!(registry.canonical_id("exchange", "AVAX"), "avalanche-2");
+    assert_eq!(registry.canonical_id("coingecko", "avalanche-2"), "avalanche-2");
+    assert_eq!(registry.canonical_id("exchange", "BTC"), "BTC");
+
+    // registering again leaves the mapping in place
+    register(&coll, "exchange", &coins_of(vec![("AVAX", "avax")])).unwrap();
+    assert_eq!(coll.find_all().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn i_should_report_symbol_collisions() {
+    let coll = MemoryDB::new().new_collection::<Asset>("assets");
+    let found = register(&coll, "coingecko", &coins_of(vec![("thorchain", "rune"), ("rune", "RUNE"), ("bitcoin", "btc")])).unwrap();
+
+    assert_eq!(found, vec![Collision { symbol: "rune".into(), assets: vec!["rune".into(), "thorchain".into()] }]);
+  }
+
+  #[test]
+  fn i_should_canonicalize_stacks() {
+    let coll = MemoryDB::new().new_collection::<Asset>("assets");
+    map(&coll, "avalanche-2", "exchange", "AVAX").unwrap();
+
+    let mut stack = Stack::new();
+    stack.coins.insert("AVAX".into(), Coin { id: "AVAX".into(), symbol: "avax".into(), prices: HashMap::new(), derived: vec![], last_updated_at: None });
+    let stack = Registry::load(&coll).unwrap().canonicalize("exchange", stack);
+
+    assert_eq!(stack.coins["avalanche-2"].id, "avalanche-2");
+    assert!(!stack.coins.contains_key("AVAX"));
+  }
+}
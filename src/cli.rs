@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use chrono::NaiveDate;
+
+use crate::export::Format;
+use crate::portfolio::Portfolio;
+
+pub const USAGE: &str = "Usage: coinrd [COMMAND] [OPTIONS] [CONFIG FLAGS]
+
+Commands:
+    run                                 collect prices forever (default)
+    fetch-once                          fetch prices once and print them
+    validate-config [PROVIDERS FILE]    check the config and the providers file
+    backfill --coins ID,.. --from DATE --to DATE
+                                        store daily prices from DATE to DATE (YYYY-MM-DD)
+    export [--coins ID,..] [--from DATE] [--to DATE] [--format csv|jsonl|parquet|dump] [--output FILE]
+                                        dump price history rows, as CSV by default
+    gaps [backfill] [--coins ID,..] [--from DATE] [--to DATE]
+                                        report gaps in price history since yesterday by default,
+                                        backfilling the days they span with backfill
+    import [--format csv|jsonl|dump] [--input FILE]
+                                        upsert price history from an export, as CSV by default
+    coins list                          list the coin_info collection
+    coins add ID SYMBOL                 add a coin to the coin_info collection
+    coins remove ID                     remove a coin from the coin_info collection
+    coins map ASSET PROVIDER ID         map a provider's coin id to the canonical asset id
+    providers push [PROVIDERS FILE]     store the providers file in the providers collection
+    portfolio list                      list portfolios
+    portfolio set ID COIN=QTY,..        create or replace a portfolio's holdings
+    portfolio remove ID                 remove a portfolio
+    portfolio show ID [--currency CUR] [--since DATE]
+                                        print a portfolio's value, P&L since DATE and allocation
+
+Config flags (--config FILE, --mongodb-uri URI, ..) apply to every command.";
+
+#[derive(Debug, PartialEq)]
+pub enum CoinsAction {
+    List,
+    Add { id: String, symbol: String },
+    Remove { id: String },
+    Map { id: String, provider: String, provider_id: String },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PortfolioAction {
+    List,
+    Set { portfolio: Portfolio },
+    Remove { id: String },
+    Show { id: String, currency: String, since: Option<NaiveDate> },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Run,
+    FetchOnce,
+    ValidateConfig { ref_file: Option<String> },
+    Backfill { coins: Vec<String>, from: NaiveDate, to: NaiveDate },
+    Export {
+        coins: Vec<String>,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+        format: Format,
+        output: Option<String>,
+    },
+    Import { format: Format, input: Option<String> },
+    Gaps {
+        coins: Vec<String>,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+        backfill: bool,
+    },
+    Coins(CoinsAction),
+    PushProviders { ref_file: Option<String> },
+    Portfolio(PortfolioAction),
+}
+
+// Cli is a parsed command line. config_args are the flags
+// left for the configuration layers
+#[derive(Debug)]
+pub struct Cli {
+    pub command: Command,
+    pub config_args: Vec<String>,
+}
+
+// options lists the flags owned by a command
+fn options(command: &str) -> &'static [&'static str] {
+    match command {
+        "backfill" => &["--coins", "--from", "--to"],
+        "export" => &["--coins", "--from", "--to", "--format", "--output"],
+        "import" => &["--format", "--input"],
+        "gaps" => &["--coins", "--from", "--to"],
+        "portfolio" => &["--currency", "--since"],
+        _ => &[],
+    }
+}
+
+fn parse_date(flag: &str, value: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|err| format!("{}: invalid date \"{}\": {}", flag, value, err))
+}
+
+fn parse_list(value: Option<String>) -> Vec<String> {
+    match value {
+        Some(v) => v.split(',').filter(|s| !s.is_empty()).map(|s| s.to_string()).collect(),
+        None => vec![],
+    }
+}
+
+// parse splits args in a command, its own options and positional
+// arguments, and the remaining config flags
+pub fn parse(args: &[String]) -> Result<Cli, String> {
+    let mut positionals: Vec<String> = vec![];
+    let mut opts: HashMap<String, String> = HashMap::new();
+    let mut config_args = vec![];
+    let mut it = args.iter();
+
+    while let Some(arg) = it.next() {
+        if !arg.starts_with("--") {
+            positionals.push(arg.to_owned());
+            continue;
+        }
+        let (flag, value) = match arg.split_once('=') {
+            Some((f, v)) => (f.to_string(), v.to_string()),
+            None => match it.next() {
+                Some(v) => (arg.to_owned(), v.to_owned()),
+                None => return Err(format!("{}: missing value", arg)),
+            },
+        };
+        let command = positionals.first().map(|s| s.as_str()).unwrap_or("run");
+        if options(command).contains(&flag.as_str()) {
+            opts.insert(flag, value);
+        } else {
+            config_args.push(flag);
+            config_args.push(value);
+        }
+    }
+
+    let mut positionals = positionals.into_iter();
+    let name = positionals.next().unwrap_or_else(|| "run".to_string());
+    let rest: Vec<String> = positionals.collect();
+    let no_args = |command: Command| {
+        if let Some(arg) = rest.first() {
+            return Err(format!("{}: unexpected argument {}", name, arg));
+        }
+        Ok(command)
+    };
+
+    let command = match name.as_str() {
+        "run" => no_args(Command::Run)?,
+        "fetch-once" => no_args(Command::FetchOnce)?,
+        "validate-config" => match rest.as_slice() {
+            [] => Command::ValidateConfig { ref_file: None },
+            [ref_file] => Command::ValidateConfig { ref_file: Some(ref_file.to_owned()) },
+            _ => return Err(format!("{}: unexpected argument {}", name, rest[1])),
+        },
+        "backfill" => {
+            let coins = parse_list(opts.remove("--coins"));
+            if coins.is_empty() {
+                return Err("backfill: --coins is required".into());
+            }
+            let from = match opts.remove("--from") {
+                Some(v) => parse_date("--from", &v)?,
+                None => return Err("backfill: --from is required".into()),
+            };
+            let to = match opts.remove("--to") {
+                Some(v) => parse_date("--to", &v)?,
+                None => return Err("backfill: --to is required".into()),
+            };
+            if to < from {
+                return Err("backfill: --to must not be before --from".into());
+            }
+            no_args(Command::Backfill { coins, from, to })?
+        },
+        "export" => {
+            let from = opts.remove("--from").map(|v| parse_date("--from", &v)).transpose()?;
+            let to = opts.remove("--to").map(|v| parse_date("--to", &v)).transpose()?;
+            let format = match opts.remove("--format") {
+                Some(f) => f.parse::<Format>()?,
+                None => Format::Csv,
+            };
+            no_args(Command::Export {
+                coins: parse_list(opts.remove("--coins")),
+                from,
+                to,
+                format,
+                output: opts.remove("--output"),
+            })?
+        },
+        "import" => {
+            let format = match opts.remove("--format") {
+                Some(f) => f.parse::<Format>()?,
+                None => Format::Csv,
+            };
+            if format == Format::Parquet {
+                return Err("import: parquet files can't be imported".into());
+            }
+            no_args(Command::Import { format, input: opts.remove("--input") })?
+        },
+        "gaps" => {
+            let backfill = match rest.as_slice() {
+                [] => false,
+                [action] if action == "backfill" => true,
+                _ => return Err("gaps: expected no argument or backfill".into()),
+            };
+            Command::Gaps {
+                coins: parse_list(opts.remove("--coins")),
+                from: opts.remove("--from").map(|v| parse_date("--from", &v)).transpose()?,
+                to: opts.remove("--to").map(|v| parse_date("--to", &v)).transpose()?,
+                backfill,
+            }
+        },
+        "coins" => match rest.as_slice() {
+            [action] if action == "list" => Command::Coins(CoinsAction::List),
+            [action, id, symbol] if action == "add" => Command::Coins(CoinsAction::Add {
+                id: id.to_owned(),
+                symbol: symbol.to_owned(),
+            }),
+            [action, id] if action == "remove" => Command::Coins(CoinsAction::Remove { id: id.to_owned() }),
+            [action, id, provider, provider_id] if action == "map" => Command::Coins(CoinsAction::Map {
+                id: id.to_owned(),
+                provider: provider.to_owned(),
+                provider_id: provider_id.to_owned(),
+            }),
+            _ => return Err("coins: expected list, add ID SYMBOL, remove ID or map ASSET PROVIDER ID".into()),
+        },
+        "providers" => match rest.as_slice() {
+            [action] if action == "push" => Command::PushProviders { ref_file: None },
+            [action, ref_file] if action == "push" => Command::PushProviders { ref_file: Some(ref_file.to_owned()) },
+            _ => return Err("providers: expected push [PROVIDERS FILE]".into()),
+        },
+        "portfolio" => match rest.as_slice() {
+            [action] if action == "list" => Command::Portfolio(PortfolioAction::List),
+            [action, id, holdings] if action == "set" => Command::Portfolio(PortfolioAction::Set {
+                portfolio: Portfolio::parse(id, holdings).map_err(|err| format!("portfolio: {}", err))?,
+            }),
+            [action, id] if action == "remove" => Command::Portfolio(PortfolioAction::Remove { id: id.to_owned() }),
+            [action, id] if action == "show" => Command::Portfolio(PortfolioAction::Show {
+                id: id.to_owned(),
+                currency: opts.remove("--currency").unwrap_or_else(|| "usd".to_string()),
+                since: opts.remove("--since").map(|v| parse_date("--since", &v)).transpose()?,
+            }),
+            _ => return Err("portfolio: expected list, set ID COIN=QTY,.., remove ID or show ID".into()),
+        },
+        _ => return Err(format!("unknown command {}", name)),
+    };
+
+    Ok(Cli { command, config_args })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use crate::portfolio::Portfolio;
+    use super::{parse, CoinsAction, Command, PortfolioAction};
+
+    fn args_of(args: Vec<&str>) -> Vec<String> {
+        args.into_iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn i_should_run_by_default() {
+        let cli = parse(&args_of(vec!["--chunk-size", "2"])).unwrap();
+        assert_eq!(cli.command, Command::Run);
+        assert_eq!(cli.config_args, args_of(vec!["--chunk-size", "2"]));
+    }
+
+    #[test]
+    fn i_should_parse_backfill_options() {
+        let cli = parse(&args_of(vec![
+            "backfill", "--coins", "bitcoin,ethereum", "--from=2021-01-01", "--to", "2021-01-03", "--ref-file", "p.toml",
+        ])).unwrap();
+
+        assert_eq!(cli.command, Command::Backfill {
+            coins: vec!["bitcoin".into(), "ethereum".into()],
+            from: NaiveDate::from_ymd_opt(2021, 1, 1).unwrap(),
+            to: NaiveDate::from_ymd_opt(2021, 1, 3).unwrap(),
+        });
+        assert_eq!(cli.config_args, args_of(vec!["--ref-file", "p.toml"]));
+    }
+
+    #[test]
+    fn i_should_parse_gaps_options() {
+        assert_eq!(
+            parse(&args_of(vec!["gaps", "backfill", "--coins", "bitcoin", "--from", "2021-01-01"])).unwrap().command,
+            Command::Gaps {
+                coins: vec!["bitcoin".into()],
+                from: NaiveDate::from_ymd_opt(2021, 1, 1),
+                to: None,
+                backfill: true,
+            }
+        );
+        assert!(parse(&args_of(vec!["gaps", "fill"])).is_err());
+    }
+
+    #[test]
+    fn i_should_parse_coins_actions() {
+        assert_eq!(parse(&args_of(vec!["coins", "list"])).unwrap().command, Command::Coins(CoinsAction::List));
+        assert_eq!(
+            parse(&args_of(vec!["coins", "add", "bitcoin", "btc"])).unwrap().command,
+            Command::Coins(CoinsAction::Add { id: "bitcoin".into(), symbol: "btc".into() })
+        );
+        assert!(parse(&args_of(vec!["coins", "add", "bitcoin"])).is_err());
+        assert_eq!(
+            parse(&args_of(vec!["coins", "map", "avalanche-2", "exchange", "AVAX"])).unwrap().command,
+            Command::Coins(CoinsAction::Map { id: "avalanche-2".into(), provider: "exchange".into(), provider_id: "AVAX".into() })
+        );
+        assert_eq!(
+            parse(&args_of(vec!["providers", "push", "p.toml"])).unwrap().command,
+            Command::PushProviders { ref_file: Some("p.toml".into()) }
+        );
+        assert!(parse(&args_of(vec!["providers", "pull"])).is_err());
+    }
+
+    #[test]
+    fn i_should_parse_portfolio_actions() {
+        assert_eq!(
+            parse(&args_of(vec!["portfolio", "set", "main", "bitcoin=0.5"])).unwrap().command,
+            Command::Portfolio(PortfolioAction::Set { portfolio: Portfolio::parse("main", "bitcoin=0.5").unwrap() })
+        );
+        assert_eq!(
+            parse(&args_of(vec!["portfolio", "show", "main", "--since", "2021-01-01"])).unwrap().command,
+            Command::Portfolio(PortfolioAction::Show {
+                id: "main".into(),
+                currency: "usd".into(),
+                since: NaiveDate::from_ymd_opt(2021, 1, 1),
+            })
+        );
+        assert!(parse(&args_of(vec!["portfolio", "set", "main", "bitcoin"])).is_err());
+        assert!(parse(&args_of(vec!["portfolio", "show"])).is_err());
+    }
+
+    #[test]
+    fn i_should_reject_bad_commands() {
+        assert!(parse(&args_of(vec!["pouet"])).is_err());
+        assert!(parse(&args_of(vec!["fetch-once", "more"])).is_err());
+        assert!(parse(&args_of(vec!["backfill", "--coins", "bitcoin", "--from", "2021-01-02", "--to", "2021-01-01"])).is_err());
+        assert!(parse(&args_of(vec!["export", "--from", "01/01/2021"])).is_err());
+        assert!(parse(&args_of(vec!["export", "--format", "xls"])).is_err());
+        assert!(parse(&args_of(vec!["import", "--format", "parquet"])).is_err());
+    }
+}
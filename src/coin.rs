@@ -6,28 +6,75 @@ pub struct Coin {
     pub id: String,
     pub symbol: String,
     pub prices: HashMap<String, f32>,
+    // derived lists the currencies of prices converted from another
+    // currency rather than quoted by the provider
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub derived: Vec<String>,
+    // last_updated_at is when the provider last updated prices, in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_updated_at: Option<i64>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Stack {
+    // id is empty for stacks stored before ids were given
+    #[serde(default)]
+    pub id: String,
     pub coins: HashMap<String, Coin>,
     pub created_at: i64,
+    // unchanged lists the coins fetched at the tick whose price didn't
+    // change, marking them seen as their prices aren't stored again
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unchanged: Vec<String>,
+}
+
+impl Default for Stack {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Stack {
     pub fn new() -> Stack {
         Stack {
+            id: String::new(),
             coins: HashMap::new(),
             created_at: 0,
+            unchanged: vec![],
         }
     }
+
+    // align_to_tick moves created_at to the start of the interval_ms
+    // tick it falls in, and keys the stack with provider and that tick.
+    // Writing a same tick twice then leaves a single document.
+    pub fn align_to_tick(&mut self, provider: &str, interval_ms: i64) {
+        self.created_at -= self.created_at.rem_euclid(interval_ms.max(1));
+        self.id = tick_id(provider, self.created_at);
+    }
+}
+
+// DEFAULT_PROVIDER fetched the stacks stored before ids were given
+pub const DEFAULT_PROVIDER: &str = "coingecko";
+
+// tick_id is the key of the stack of a provider's tick
+pub fn tick_id(provider: &str, tick: i64) -> String {
+    format!("{}:{}", provider, tick)
+}
+
+// tick_provider gives the provider of a stack from its id
+pub fn tick_provider(stack: &Stack) -> &str {
+    match stack.id.rsplit_once(':') {
+        Some((provider, _)) => provider,
+        None => DEFAULT_PROVIDER,
+    }
 }
 
 
 // trim_nonupdated_coins compare with previously retrieved coins and filters out
-// ones that price hasn't change
+// ones that price hasn't change, listing them in unchanged
 pub fn trim_nonupdated_coins(cache: &Stack, future: &Stack) -> Stack {
     let mut trimmed = Stack::new();
+    trimmed.id = future.id.to_owned();
     trimmed.created_at = future.created_at;
 
     for (name, coin) in future.coins.iter() {
@@ -47,6 +94,8 @@ pub fn trim_nonupdated_coins(cache: &Stack, future: &Stack) -> Stack {
             },
         };
     }
+    trimmed.unchanged = future.coins.keys().filter(|id| !trimmed.coins.contains_key(*id)).cloned().collect();
+    trimmed.unchanged.sort();
 
     trimmed
 }
@@ -56,7 +105,7 @@ mod tests {
     use std::collections::HashMap;
     use super::{Stack, Coin, trim_nonupdated_coins};
 
-    fn gen_hashmap<'a, T>(keys: Vec<&str>, items: Vec<T>) -> HashMap<String, T>
+    fn gen_hashmap<T>(keys: Vec<&str>, items: Vec<T>) -> HashMap<String, T>
     where T: Clone {
         let mut hmap: HashMap<String, T> = HashMap::new();
 
@@ -73,6 +122,7 @@ mod tests {
     #[test]
     fn trim_should_return_empty_coins_hashmap() {
         let trial = Stack {
+            id: String::new(),
             coins: gen_hashmap(
                 vec!["coinoyaro"], 
                 vec![
@@ -80,9 +130,12 @@ mod tests {
                         id: "coinoyaro".to_string(),
                         symbol: "con".to_string(),
                         prices: gen_hashmap(vec!["wsh"], vec![4.20f32]),
+                        derived: vec![],
+                        last_updated_at: None,
                     }
                 ]),
             created_at: 0,
+            unchanged: vec![],
         };
         let cache = trial.clone();
         let goal = trim_nonupdated_coins(&cache, &trial);
@@ -100,14 +153,19 @@ mod tests {
             id: "cached1".to_string(),
             symbol: "cac".to_string(),
             prices: gen_hashmap(vec!["wsh"], vec![4.20f32]),
+            derived: vec![],
+            last_updated_at: None,
         };
         let og_coin = Coin {
             id: "og1".to_string(),
             symbol: "og".to_string(),
             prices: gen_hashmap(vec!["wsh"], vec![6.969f32]),
+            derived: vec![],
+            last_updated_at: None,
         };
 
         let trial = Stack {
+            id: String::new(),
             coins: gen_hashmap(
                 vec!["cached1", "og1"],
                 vec![
@@ -116,17 +174,31 @@ mod tests {
                 ]
             ),
             created_at: 0,
+            unchanged: vec![],
         };
 
         let cache = Stack {
+            id: String::new(),
             coins: gen_hashmap(vec!["cached1"], vec![cached_coin]),
             created_at: 0,
+            unchanged: vec![],
         };
 
         let goal = trim_nonupdated_coins(&cache, &trial);
         assert_eq!(goal.coins.len(), 1);
         assert_eq!(goal.coins.get("og1").unwrap().id, "og1");
+        assert_eq!(goal.unchanged, vec!["cached1".to_string()]);
         assert_eq!(cache.coins.get("cached1").unwrap().id, "cached1");
         assert_eq!(trial.coins.get("og1").unwrap().id, "og1");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn i_should_align_stacks_to_ticks() {
+        let mut stack = Stack::new();
+        stack.created_at = 130_500;
+        stack.align_to_tick("coingecko", 64_000);
+
+        assert_eq!(stack.created_at, 128_000);
+        assert_eq!(stack.id, "coingecko:128000");
+    }
+}
@@ -1,20 +1,204 @@
+use std::collections::HashMap;
 use chrono::Utc;
 use serde::{Serialize, Deserialize};
 
-#[derive(Deserialize, Serialize)]
+use crate::database::Collection;
+
+// MANUAL is the provider name of coins added by hand
+pub const MANUAL: &str = "manual";
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+  #[default]
+  Active,
+  Delisted,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct CoinInfo {
-  id: String,
-  symbol: String,
-  created_at: i64,
+  pub id: String,
+  pub symbol: String,
+  #[serde(default)]
+  pub name: String,
+  // documents stored before first_seen existed hold created_at
+  #[serde(alias = "created_at")]
+  pub first_seen: i64,
+  #[serde(default)]
+  pub last_seen: i64,
+  #[serde(default)]
+  pub status: Status,
+  // providers lists the providers tracking the coin
+  #[serde(default)]
+  pub providers: Vec<String>,
 }
 
 
 impl CoinInfo {
   pub fn new(id: &str, symbol: &str) -> Self {
+      let now = Utc::now().timestamp_millis();
       Self {
         id: id.to_string(),
         symbol: symbol.to_string(),
-        created_at: Utc::now().timestamp_millis(),
+        name: String::new(),
+        first_seen: now,
+        last_seen: now,
+        status: Status::Active,
+        providers: vec![MANUAL.to_string()],
       }
   }
-}
\ No newline at end of file
+
+  // seen_by merges a sighting of the coin by provider,
+  // keeping the original first_seen
+  pub fn seen_by(&mut self, provider: &str, symbol: &str, name: Option<&str>, now: i64) {
+    self.symbol = symbol.to_string();
+    if let Some(n) = name {
+      self.name = n.to_string();
+    }
+    self.last_seen = now;
+    self.status = Status::Active;
+    if !self.providers.iter().any(|p| p == provider) {
+      self.providers.push(provider.to_string());
+    }
+  }
+
+  // dropped_by removes provider from the coin's providers.
+  // The coin is delisted once no provider tracks it anymore.
+  pub fn dropped_by(&mut self, provider: &str) {
+    self.providers.retain(|p| p != provider);
+    if self.providers.is_empty() {
+      self.status = Status::Delisted;
+    }
+  }
+
+  // tracked_by tells if provider tracks the coin. Documents stored
+  // before providers existed are considered tracked by any provider.
+  pub fn tracked_by(&self, provider: &str) -> bool {
+    self.providers.is_empty() || self.providers.iter().any(|p| p == provider)
+  }
+}
+
+// refresh stores the coins tracked by provider in coin_info, merging them with
+// stored ones, and delists the stored coins provider doesn't track anymore
+pub fn refresh(
+  coll: &impl Collection<CoinInfo>,
+  provider: &str,
+  coins: &HashMap<String, String>,
+  names: &HashMap<String, String>,
+  now: i64,
+) -> Result<(), String> {
+  let mut res = Ok(());
+
+  for mut info in coll.find_all()? {
+    if info.status == Status::Delisted || !info.tracked_by(provider) || coins.contains_key(&info.id) {
+      continue;
+    }
+    info.dropped_by(provider);
+    if let Err(err) = coll.save(info.id.to_owned(), &info) {
+      res = Err(err);
+    }
+  }
+
+  for (id, symbol) in coins {
+    let mut info = match coll.find_one(id.to_owned()) {
+      Some(i) => i,
+      None => CoinInfo {
+        id: id.to_owned(),
+        symbol: symbol.to_owned(),
+        name: String::new(),
+        first_seen: now,
+        last_seen: now,
+        status: Status::Active,
+        providers: vec![],
+      },
+    };
+    info.seen_by(provider, symbol, names.get(id).map(|n| n.as_str()), now);
+    if let Err(err) = coll.save(id.to_owned(), &info) {
+      res = Err(err);
+    }
+  }
+
+  res
+}
+
+// add tracks a coin by hand, merging it into its stored document
+// so its first_seen and providers are kept
+pub fn add(coll: &impl Collection<CoinInfo>, id: &str, symbol: &str, now: i64) -> Result<(), String> {
+  let info = match coll.find_one(id.to_owned()) {
+    Some(mut info) => {
+      info.seen_by(MANUAL, symbol, None, now);
+      info
+    },
+    None => CoinInfo::new(id, symbol),
+  };
+  coll.save(id.to_owned(), &info)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+  use crate::database::{Collection, MemoryDB};
+  use super::{add, refresh, CoinInfo, Status, MANUAL};
+
+  fn coins_of(coins: Vec<(&str, &str)>) -> HashMap<String, String> {
+    coins.into_iter().map(|(id, s)| (id.to_string(), s.to_string())).collect()
+  }
+
+  #[test]
+  fn i_should_keep_first_seen_on_refresh() {
+    let coll = MemoryDB::new().new_collection::<CoinInfo>("coin_info");
+    let coins = coins_of(vec![("bitcoin", "btc")]);
+    let mut names = HashMap::new();
+    names.insert("bitcoin".to_string(), "Bitcoin".to_string());
+
+    refresh(&coll, "coingecko", &coins, &HashMap::new(), 1000).unwrap();
+    refresh(&coll, "coingecko", &coins, &names, 2000).unwrap();
+
+    let info = coll.find_one("bitcoin".into()).unwrap();
+    assert_eq!(info.first_seen, 1000);
+    assert_eq!(info.last_seen, 2000);
+    assert_eq!(info.name, "Bitcoin");
+    assert_eq!(info.providers, vec!["coingecko".to_string()]);
+  }
+
+  #[test]
+  fn i_should_delist_dropped_coins() {
+    let coll = MemoryDB::new().new_collection::<CoinInfo>("coin_info");
+    refresh(&coll, "coingecko", &coins_of(vec![("bitcoin", "btc"), ("terra-luna", "luna")]), &HashMap::new(), 1000).unwrap();
+    coll.save("manual-coin".into(), &CoinInfo::new("manual-coin", "man")).unwrap();
+    refresh(&coll, "coingecko", &coins_of(vec![("bitcoin", "btc")]), &HashMap::new(), 2000).unwrap();
+
+    let luna = coll.find_one("terra-luna".into()).unwrap();
+    assert_eq!(luna.status, Status::Delisted);
+    assert_eq!(luna.last_seen, 1000);
+    assert_eq!(coll.find_one("manual-coin".into()).unwrap().status, Status::Active);
+
+    refresh(&coll, "coingecko", &coins_of(vec![("terra-luna", "luna")]), &HashMap::new(), 3000).unwrap();
+    let luna = coll.find_one("terra-luna".into()).unwrap();
+    assert_eq!(luna.status, Status::Active);
+    assert_eq!(luna.first_seen, 1000);
+  }
+
+  #[test]
+  fn i_should_merge_coins_added_by_hand() {
+    let coll = MemoryDB::new().new_collection::<CoinInfo>("coin_info");
+    refresh(&coll, "coingecko", &coins_of(vec![("bitcoin", "btc")]), &HashMap::new(), 1000).unwrap();
+    add(&coll, "bitcoin", "btc", 2000).unwrap();
+
+    let info = coll.find_one("bitcoin".into()).unwrap();
+    assert_eq!(info.first_seen, 1000);
+    assert_eq!(info.last_seen, 2000);
+    assert_eq!(info.providers, vec!["coingecko".to_string(), MANUAL.to_string()]);
+
+    add(&coll, "dogecoin", "doge", 3000).unwrap();
+    assert_eq!(coll.find_one("dogecoin".into()).unwrap().providers, vec![MANUAL.to_string()]);
+  }
+
+  #[test]
+  fn i_should_read_legacy_documents() {
+    let info: CoinInfo = serde_json::from_str(r#"{"id":"bitcoin","symbol":"btc","created_at":42}"#).unwrap();
+    assert_eq!(info.first_seen, 42);
+    assert_eq!(info.status, Status::Active);
+    assert!(info.tracked_by("coingecko"));
+  }
+}
@@ -0,0 +1,559 @@
+use core::time;
+use std::collections::HashMap;
+use std::sync::Arc;
+use mongodb::sync::{Client};
+use log::{info, warn, error};
+use chrono::Utc;
+
+use crate::{asset, coin, discovery, fx, gecko, http, import, indicators, portfolio, provider, quality, provider_source, window};
+use crate::config::{Config, ProvidersSource, SinksConfig};
+use crate::asset::{Asset, Registry};
+use crate::coin::Stack;
+use crate::coin_info::{self, CoinInfo, Status};
+use crate::database::{Collection, MongoCollection, MongoDB};
+use crate::fetch_status::{FetchStatus, Outcome, Tracker};
+use crate::health::Health;
+use crate::leader::{Election, Lease};
+use crate::latest_coins_data::{get_coin_latest_data, LatestCoinData};
+use crate::provider::{Provide, Provider};
+use crate::provider_source::ProviderSource;
+use crate::shutdown::{Due, Shutdown};
+use crate::validation::Diagnostic;
+use crate::watcher::FileWatch;
+
+// db_connection returns a MongoDB struct wrapping
+// around Mongo DB connector.
+pub fn db_connection (mongodb_uri: &str, db_name: &str) -> MongoDB {
+    let client = match Client::with_uri_str(mongodb_uri) {
+        Ok(c) => c,
+        Err(err) => {
+            error!("Could not establish connection to DB: {}", err);
+            panic!("{}", err);
+        },
+    };
+    
+    MongoDB::new(client.database(db_name))
+}
+
+// save_coins_stack upserts a batch of trimmed coins in a single
+// document keyed by the stack's id. A tick fetched twice, as when the
+// watcher wakes the collector up, is merged into the stored document.
+pub fn save_coins_stack(coins: &Stack, coll: &impl Collection<Stack>) -> Result<(), String> {
+    import::merge_stack(coins.to_owned(), coll)
+}
+
+// save_latest_entries stores the prices of the window the provider's policies
+// give each coin (prices_max_len by default) into a single document
+// organized by currency id. A coin whose window already holds the stack's tick being left as is.
+// Every coin is saved even if one fails, the first error is returned.
+pub fn save_latest_entries(coins: &Stack, provider: &Provider, db: &MongoDB, sinks: &SinksConfig) -> Result<(), String> {
+    let coll = db.new_collection::<LatestCoinData>(&sinks.latest_entries);
+    let tick = coins.created_at;
+    let mut first_err = None;
+
+    for coin in coins.coins.values().cloned() {
+        let window = window::resolve(provider.get_windows(), &coin.id, sinks.prices_max_len);
+        let mut latest_coins = match get_coin_latest_data(coin.id.to_owned(), &coll) {
+            Some(mut lcd) => {
+                lcd.set_window(window);
+                lcd
+            },
+            None => LatestCoinData::new(coin.id.to_owned(), coin.symbol.to_owned(), window),
+        };
+
+        if !latest_coins.update_with_coin(coin, tick, provider.get_name()) {
+            continue;
+        }
+        latest_coins.updated_at = Utc::now().timestamp_millis();
+        if let Err(err) = coll.save(latest_coins.id.to_owned(), &latest_coins) {
+            first_err.get_or_insert(err);
+        }
+    }
+    match first_err {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+// fetch_fx_rates stores the rates of every fx provider whose interval
+// elapsed since its last fetch, fetched holding the time of last fetches
+fn fetch_fx_rates(fx_providers: &HashMap<String, fx::FxProvider>, fetched: &mut HashMap<String, i64>, db: &MongoDB, sinks: &SinksConfig) {
+    let coll = db.new_collection::<fx::FxRates>(&sinks.fx_rates);
+    let now = Utc::now().timestamp_millis();
+
+    for (name, provider) in fx_providers {
+        let interval_ms = (provider.get_interval_secs() * 1000) as i64;
+        if fetched.get(name).map(|at| now - at < interval_ms).unwrap_or(false) {
+            continue;
+        }
+        let res = gecko::fx_rates(provider).and_then(|rates| {
+            let at = now - now.rem_euclid(interval_ms.max(1000));
+            let rates = fx::FxRates::new(provider, &rates, at)
+                .ok_or(format!("no {} rate", provider.get_base()))?;
+            fx::save_rates(&coll, &rates)
+        });
+        match res {
+            Ok(_) => {
+                fetched.insert(name.to_owned(), now);
+            },
+            Err(err) => warn!("Could not store {} fx rates: {}", name, err),
+        }
+    }
+}
+
+// derive_currencies fills the currencies of provider missing from coins
+// with the rates its fx provider stored, keeping base prices only if
+// there are none of the last day
+pub fn derive_currencies(coins: &mut Stack, provider: &Provider, db: &MongoDB, sinks: &SinksConfig) {
+    let (base, fx_provider) = match (provider.get_base_currency(), provider.get_fx_provider()) {
+        (Some(b), Some(f)) => (b, f),
+        _ => return,
+    };
+    let coll = db.new_collection::<fx::FxRates>(&sinks.fx_rates);
+    match fx::derive_at(&coll, fx_provider, coins, base, provider.get_currencies()) {
+        Ok(true) => {},
+        Ok(false) => warn!("No {} rates of the last day, keeping {} prices only", fx_provider, base),
+        Err(err) => warn!("Could not read {} rates, keeping {} prices only: {}", fx_provider, base, err),
+    }
+}
+
+// run_indicator_jobs upserts the configured indicators
+// next to price history
+fn run_indicator_jobs(config: &Config, db: &MongoDB) {
+    let jobs = &config.indicators.jobs;
+    if jobs.0.is_empty() {
+        return;
+    }
+    let res = indicators::run_jobs(
+        &db.new_collection::<Stack>(&config.sinks.price_history),
+        &db.new_collection::<indicators::Point>(&config.indicators.collection),
+        jobs,
+        (config.indicators.candle_secs * 1000) as i64,
+        config.indicators.lookback_candles,
+        Utc::now().timestamp_millis(),
+    );
+    match res {
+        Ok(saved) => info!("Saved {} indicator points", saved),
+        Err(err) => warn!("Could not save indicators: {}", err),
+    }
+}
+
+// record_fetch_status updates the fetch status of the provider's coins
+// with the outcome of the tick at and stores it, logging the coins
+// reaching alert_misses consecutive misses
+fn record_fetch_status(tracker: &mut Tracker, provider: &Provider, outcome: &Outcome, at: i64, db: &MongoDB, config: &Config) {
+    for untracked in &outcome.untracked {
+        info!("{} returned untracked coin {}", provider.get_name(), untracked);
+    }
+    for status in tracker.record(provider.get_coins(), outcome, at) {
+        error!(
+            "{} missed {} ticks in a row, last error: {}",
+            status.coin,
+            status.consecutive_misses,
+            status.last_error.as_deref().unwrap_or_default(),
+        );
+    }
+    let coll = db.new_collection::<FetchStatus>(&config.status.collection);
+    if let Err(err) = tracker.save(&coll, at) {
+        warn!("Could not save fetch statuses: {}", err);
+    }
+}
+
+fn should_update_providers(c_f: u32, refresh_ticks: u32) -> bool {
+    c_f == refresh_ticks
+}
+
+// update_coingecko_list_provider_routine reloads coingecko provider, resolving
+// its coins with discovery rules if enabled, and stores them in coin_info,
+// delisting the stored coins it doesn't track anymore.
+// If discovery fails, previously stored active coins are used.
+// Returns the diagnostics of invalid providers.
+pub fn update_coingecko_list_provider_routine(source: &dyn ProviderSource, collection: impl Collection<CoinInfo>) -> Result<Provider, Vec<Diagnostic>> {
+    let mut coingecko = provider_source::load_provider(source, "coingecko")?;
+
+    let mut names = HashMap::new();
+    if let Some(discovery) = coingecko.get_discovery().cloned() {
+        match discovery::discover(&coingecko, &discovery) {
+            Ok(listed) => {
+                info!("Discovered {} coins", listed.len());
+                let mut coins = HashMap::new();
+                for (id, coin) in listed {
+                    names.insert(id.to_owned(), coin.name);
+                    coins.insert(id, coin.symbol);
+                }
+                coingecko.merge_coins(coins);
+            },
+            Err(err) => {
+                warn!("Coins discovery failed, using stored coins: {}", err);
+                let name = coingecko.get_name().to_owned();
+                match collection.find_all() {
+                    Ok(infos) => coingecko.merge_coins(infos
+                        .into_iter()
+                        .filter(|ci| ci.status == Status::Active && ci.tracked_by(&name))
+                        .map(|ci| (ci.id, ci.symbol))
+                        .collect()),
+                    Err(err) => warn!("Could not read stored coins: {}", err),
+                }
+            },
+        }
+    }
+
+    let now = Utc::now().timestamp_millis();
+    if let Err(err) = coin_info::refresh(&collection, coingecko.get_name(), coingecko.get_coins(), &names, now) {
+        warn!("Could not save coin info: {}", err);
+    }
+
+    Ok(coingecko)
+}
+
+// new_election sets up the leader election of this process
+fn new_election(config: &Config, db: &MongoDB) -> Election<MongoCollection<Lease>> {
+    if config.leader.lease_ttl_secs <= config.scheduler.interval_secs {
+        warn!(
+            "leader.lease_ttl_secs ({}) should be greater than scheduler.interval_secs ({}), leadership will flap",
+            config.leader.lease_ttl_secs,
+            config.scheduler.interval_secs,
+        );
+    }
+    // concurrent first campaigns rely on a unique id
+    if let Err(err) = db.ensure_unique_index(&config.leader.collection, "id") {
+        warn!("Could not index {} collection: {}", config.leader.collection, err);
+    }
+    let holder = format!(
+        "{}-{}",
+        std::env::var("HOSTNAME").unwrap_or_else(|_| "coinrd".into()),
+        std::process::id(),
+    );
+    Election::new(
+        db.new_collection::<Lease>(&config.leader.collection),
+        &holder,
+        (config.leader.lease_ttl_secs * 1000) as i64,
+    )
+}
+
+// run collects prices every interval until a shutdown is requested
+pub fn run(config: &Config) {
+    // so should_update_providers triggers straight away
+    let mut cur_f = config.scheduler.provider_refresh_ticks;
+    let started_at = Utc::now();
+    let shutdown = match Shutdown::install() {
+        Ok(s) => s,
+        Err(err) => panic!("Could not install signal handlers: {}", err),
+    };
+
+    let db = db_connection(&config.database.uri, &config.database.name);
+    let interval = time::Duration::from_secs(config.scheduler.interval_secs);
+
+    let health = Arc::new(Health::new(interval.as_millis() as i64, config.http.ready_intervals));
+    let ctx = http::Context {
+        health: health.clone(),
+        db: db.clone(),
+        price_history: config.sinks.price_history.to_owned(),
+        portfolio_history: config.sinks.portfolio_history.to_owned(),
+        fetch_status: config.status.collection.to_owned(),
+        interval_ms: (config.scheduler.interval_secs * 1000) as i64,
+        gap_min_ticks: config.gaps.min_ticks,
+    };
+    if let Err(err) = http::serve(&config.http.addr, ctx) {
+        error!("Could not start HTTP server on {}: {}", config.http.addr, err);
+    }
+
+    let source = provider_source::from_config(config, &db);
+    let mut version = source.version();
+    let mut coingecko = match provider_source::load_provider(source.as_ref(), "coingecko") {
+        Ok(c) => c,
+        Err(diagnostics) => {
+            for d in diagnostics {
+                eprintln!("{}: {}", source.describe(), d);
+            }
+            std::process::exit(1);
+        },
+    };
+
+    // a watched file wakes the loop up as soon as it changes, other sources
+    // are checked every tick. Periodic refreshes remain, as discovery
+    // results change without the providers definitions.
+    let ref_file = &config.providers.ref_file;
+    let watch = match config.providers.source {
+        ProvidersSource::File => match FileWatch::new(ref_file) {
+            Ok(w) => Some(w),
+            Err(err) => {
+                warn!("Could not watch {}, providers reload every {} ticks only: {}", ref_file, config.scheduler.provider_refresh_ticks, err);
+                None
+            },
+        },
+        ProvidersSource::Database => None,
+    };
+
+    // documents are upserted by id, unique ids guard against duplicates
+    for coll in &[&config.sinks.price_history, &config.sinks.latest_entries, &config.sinks.fx_rates, &config.sinks.portfolio_history, &config.quality.collection, &config.status.collection] {
+        if let Err(err) = db.ensure_unique_index(coll, "id") {
+            warn!("Could not index {} collection: {}", coll, err);
+        }
+    }
+    // range reads sort on these fields
+    for (coll, field) in &[(&config.sinks.price_history, "created_at"), (&config.sinks.fx_rates, "created_at"), (&config.sinks.portfolio_history, "at")] {
+        if let Err(err) = db.ensure_index(coll, field) {
+            warn!("Could not index {} collection on {}: {}", coll, field, err);
+        }
+    }
+
+    let mut election = match config.leader.enabled {
+        true => Some(new_election(config, &db)),
+        false => None,
+    };
+
+    let mut fx_providers = provider_source::load_fx_providers(source.as_ref()).unwrap_or_default();
+    let mut fx_fetched = HashMap::new();
+
+    let assets = db.new_collection::<Asset>(asset::COLLECTION);
+    let mut registry = match Registry::load(&assets) {
+        Ok(r) => r,
+        Err(err) => {
+            error!("Could not load assets: {}", err);
+            std::process::exit(1);
+        },
+    };
+    let mut coins_cache = Stack::new();
+    // quarantined the tick before, for the next one to confirm
+    let mut pending: Vec<quality::Quarantined> = vec![];
+    let mut indicators_ticks = 0;
+    let rules = quality::Rules {
+        max_jump_pct: config.quality.max_jump_pct,
+        max_staleness_secs: config.quality.max_staleness_secs,
+    };
+    // loaded once leader, as followers don't track fetches
+    let mut tracker: Option<Tracker> = None;
+    let (mut ticks, mut saved_ticks, mut failed_ticks) = (0, 0, 0);
+
+    // a stop signal only raises the shutdown flag, so the current
+    // tick always goes through all of its writes before exiting.
+    // A change of the providers file wakes the loop up to reload
+    // them, fetches staying on the interval schedule.
+    let wake = || watch.as_ref().map(|w| w.pending()).unwrap_or(false);
+    shutdown.every(interval, wake, |due| {
+        if let Some(w) = &watch {
+            w.take();
+        }
+        if due == Due::Wake {
+            // followers reload once leader
+            if election.as_ref().is_some_and(|e| !e.holds(Utc::now().timestamp_millis())) {
+                return Ok(());
+            }
+        } else {
+            ticks += 1;
+            if let Some(e) = election.as_mut() {
+                let campaign = e.campaign(Utc::now().timestamp_millis());
+                // only a replica that read the lease held by another one stands by,
+                // one that can't reach the lease goes unready as fetches go stale
+                health.set_standby(campaign == Ok(None));
+                if !matches!(campaign, Ok(Some(_))) {
+                    // once leader, providers are reloaded and every coin written
+                    cur_f = config.scheduler.provider_refresh_ticks;
+                    coins_cache = Stack::new();
+                    pending.clear();
+                    tracker = None;
+                    return campaign.map(|_| ());
+                }
+            }
+        }
+        let current_version = source.version();
+        if current_version != version || should_update_providers(cur_f, config.scheduler.provider_refresh_ticks) {
+            // a rejected version is retried on the next change or refresh only
+            version = current_version;
+            match update_coingecko_list_provider_routine(
+                source.as_ref(),
+                db.new_collection::<CoinInfo>("coin_info"),
+            ) {
+                Ok(fresh_gecko) => {
+                    health.set_config_errors(vec![]);
+                    if let Ok(fresh_fx) = provider_source::load_fx_providers(source.as_ref()) {
+                        fx_providers = fresh_fx;
+                    }
+                    info!("Providers reloaded: {}", provider::diff(&coingecko, &fresh_gecko));
+                    coingecko = fresh_gecko;
+                },
+                Err(diagnostics) => {
+                    let errors: Vec<String> = diagnostics.iter().map(|d| format!("{}: {}", source.describe(), d)).collect();
+                    for err in &errors {
+                        error!("Providers reload rejected, keeping previous providers: {}", err);
+                    }
+                    health.set_config_errors(errors);
+                },
+            };
+            match asset::register(&assets, coingecko.get_name(), coingecko.get_coins()) {
+                Ok(collisions) => for c in collisions {
+                    warn!("Symbol {} is shared by assets {}", c.symbol, c.assets.join(", "));
+                },
+                Err(err) => warn!("Could not register assets: {}", err),
+            }
+            match Registry::load(&assets) {
+                Ok(r) => registry = r,
+                Err(err) => warn!("Could not reload assets, keeping previous ones: {}", err),
+            }
+            cur_f = 0;
+        }
+        if due == Due::Wake {
+            return Ok(());
+        }
+        cur_f += 1;
+
+        // fetch statuses are tracked once loaded, prices being fetched anyway
+        if tracker.is_none() {
+            let statuses = db.new_collection::<FetchStatus>(&config.status.collection);
+            match Tracker::load(&statuses, coingecko.get_name(), config.status.alert_misses) {
+                Ok(t) => tracker = Some(t),
+                Err(err) => warn!("Could not load fetch statuses, retrying next tick: {}", err),
+            }
+        }
+        // rates are stored first, for the tick's prices to be derived with
+        fetch_fx_rates(&fx_providers, &mut fx_fetched, &db, &config.sinks);
+        match gecko::simple_price(&coingecko, config.providers.chunk_size) {
+            Ok((mut coins, outcome)) => {
+                health.mark_fetch();
+                derive_currencies(&mut coins, &coingecko, &db, &config.sinks);
+                let mut coins = registry.canonicalize(coingecko.get_name(), coins);
+                coins.align_to_tick(coingecko.get_name(), interval.as_millis() as i64);
+                let (coins, quarantined) = quality::screen(coingecko.get_name(), coins, &coins_cache, &pending, &rules, Utc::now().timestamp_millis());
+                for q in &quarantined {
+                    warn!("Quarantined {} {} of {:?}: {:?}", q.coin, q.currency.as_deref().unwrap_or("prices"), q.reason, q.price);
+                }
+                let trimmed_coins = coin::trim_nonupdated_coins(&coins_cache, &coins);
+                info!("{:?}", &trimmed_coins);
+
+                let lease_lost = election.as_ref().map(|e| !e.holds(Utc::now().timestamp_millis())).unwrap_or(false);
+                if lease_lost {
+                    warn!("Leader lease lost before writing, dropping the tick");
+                    failed_ticks += 1;
+                } else if trimmed_coins.coins.is_empty() && trimmed_coins.unchanged.is_empty() {
+                    health.mark_write();
+                } else {
+                    let stack_res = save_coins_stack(&trimmed_coins, &db.new_collection::<Stack>(&config.sinks.price_history));
+                    let latest_res = save_latest_entries(&trimmed_coins, &coingecko, &db, &config.sinks);
+                    match stack_res.and(latest_res) {
+                        Ok(_) => {
+                            health.mark_write();
+                            saved_ticks += 1;
+                        },
+                        Err(err) => {
+                            warn!("Could not save tick: {}", err);
+                            failed_ticks += 1;
+                        },
+                    }
+                }
+                coins_cache = quality::keep_last_good(&coins_cache, coins, &quarantined);
+
+                if !lease_lost {
+                    if let Some(tracker) = tracker.as_mut() {
+                        record_fetch_status(tracker, &coingecko, &outcome, coins_cache.created_at, &db, config);
+                    }
+                    let quarantine = db.new_collection::<quality::Quarantined>(&config.quality.collection);
+                    if let Err(err) = quality::save_quarantined(&quarantine, &quarantined) {
+                        warn!("Could not save quarantined prices: {}", err);
+                    }
+                    // portfolios are valued with the last good price of every coin, not only updated ones
+                    let res = portfolio::value_all(
+                        &db.new_collection::<portfolio::Portfolio>(portfolio::COLLECTION),
+                        &db.new_collection::<portfolio::Snapshot>(&config.sinks.portfolio_history),
+                        &coins_cache,
+                        coingecko.get_currencies(),
+                    );
+                    if let Err(err) = res {
+                        warn!("Could not value portfolios: {}", err);
+                    }
+                }
+                pending = quarantined;
+
+                indicators_ticks += 1;
+                if !lease_lost && indicators_ticks >= config.indicators.every_ticks {
+                    indicators_ticks = 0;
+                    run_indicator_jobs(config, &db);
+                }
+            },
+            Err(err) => {
+                failed_ticks += 1;
+                let now = Utc::now().timestamp_millis();
+                let holds = election.as_ref().map(|e| e.holds(now)).unwrap_or(true);
+                if let Some(tracker) = tracker.as_mut().filter(|_| holds) {
+                    let tick = now - now.rem_euclid(interval.as_millis() as i64);
+                    record_fetch_status(tracker, &coingecko, &Outcome::failed(coingecko.get_coins(), &err), tick, &db, config);
+                }
+                return Err(err);
+            }
+        };
+
+        info!("Going for a siesta for {}s", interval.as_secs());
+        Ok(())
+    });
+
+    if let Some(e) = election.as_mut() {
+        e.resign(Utc::now().timestamp_millis());
+    }
+    info!(
+        "Shutting down after {} ticks ({} saved, {} failed) and {}s uptime",
+        ticks,
+        saved_ticks,
+        failed_ticks,
+        (Utc::now() - started_at).num_seconds(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::coin::{Coin, Stack};
+    use crate::database::{Collection, MemoryDB};
+    use super::save_coins_stack;
+
+    const F: u32 = 4;
+
+    fn gen_stack(at: i64, prices: Vec<(&str, f32)>) -> Stack {
+        let mut stack = Stack::new();
+        stack.created_at = at;
+        stack.align_to_tick("coingecko", 60_000);
+        for (id, usd) in prices {
+            let prices: HashMap<String, f32> = vec![("usd".to_string(), usd)].into_iter().collect();
+            stack.coins.insert(id.to_string(), Coin { id: id.to_string(), symbol: id[..3].to_string(), prices, derived: vec![], last_updated_at: None });
+        }
+        stack
+    }
+
+    #[test]
+    fn i_should_merge_stacks_of_a_same_tick() {
+        let coll = MemoryDB::new().new_collection::<Stack>("price_history");
+        save_coins_stack(&gen_stack(60_000, vec![("bitcoin", 42.0), ("ethereum", 4.0)]), &coll).unwrap();
+        save_coins_stack(&gen_stack(90_000, vec![("ethereum", 4.2)]), &coll).unwrap();
+
+        let stored = coll.find_all().unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].id, "coingecko:60000");
+        assert_eq!(stored[0].coins["bitcoin"].prices["usd"], 42.0);
+        assert_eq!(stored[0].coins["ethereum"].prices["usd"], 4.2);
+    }
+
+    #[test]
+    fn should_test_should_update_providers() {
+        let mut it = 0;
+        let mut trial = false;
+
+        while it < F {
+            trial = super::should_update_providers(it, F);
+            it += 1;
+        }
+        assert!(!trial);
+
+        it = 0;
+        while it <= F {
+            trial = super::should_update_providers(it, F);
+            it += 1;
+        }
+        assert!(trial);
+
+        it = 0;
+        while it <= 7 {
+            trial = super::should_update_providers(it, F);
+            it += 1;
+        }
+        assert!(!trial);
+    }
+}
\ No newline at end of file
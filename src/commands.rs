@@ -0,0 +1,318 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::thread;
+use std::time::Duration;
+use chrono::{NaiveDate, TimeZone, Utc};
+use log::{info, warn};
+
+use crate::{asset, coin, collector, export, gaps, gecko, import, portfolio, provider_source, validation};
+use crate::cli::{CoinsAction, PortfolioAction};
+use crate::coin::Stack;
+use crate::coin_info::{self, CoinInfo};
+use crate::config::Config;
+use crate::database::Collection;
+use crate::fetch_status::FetchStatus;
+use crate::export::{day_start, Format};
+use crate::provider::{Provide, Provider};
+use crate::provider_source::{ProviderSource, ProvidersDocument, TomlFile};
+use crate::validation::Diagnostic;
+
+// BACKFILL_PAUSE spaces history requests to stay under
+// CoinGecko's public rate limit
+const BACKFILL_PAUSE: Duration = Duration::from_millis(1500);
+
+fn load_coingecko(config: &Config) -> Result<Provider, String> {
+    let db = collector::db_connection(&config.database.uri, &config.database.name);
+    let source = provider_source::from_config(config, &db);
+    provider_source::load_provider(source.as_ref(), "coingecko").map_err(|diagnostics| {
+        diagnostics
+            .iter()
+            .map(|d| format!("{}: {}", source.describe(), d))
+            .collect::<Vec<String>>()
+            .join("\n")
+    })
+}
+
+// fetch_once fetches prices a single time and prints them
+pub fn fetch_once(config: &Config) -> Result<(), String> {
+    let coingecko = load_coingecko(config)?;
+    let (mut stack, _) = gecko::simple_price(&coingecko, config.providers.chunk_size)?;
+    let db = collector::db_connection(&config.database.uri, &config.database.name);
+    collector::derive_currencies(&mut stack, &coingecko, &db, &config.sinks);
+
+    let out = serde_json::to_string_pretty(&stack).map_err(|err| err.to_string())?;
+    println!("{}", out);
+    Ok(())
+}
+
+// validate_config checks the providers file, printing all its
+// diagnostics, and that it holds a usable coingecko provider
+pub fn validate_config(ref_file: &str) -> Result<(), String> {
+    let report = validation::validate_file(ref_file).map_err(|diagnostics| {
+        let count = diagnostics.len();
+        for d in diagnostics {
+            eprintln!("{}: {}", ref_file, d);
+        }
+        format!("{}: {} errors", ref_file, count)
+    })?;
+    for warning in &report.warnings {
+        eprintln!("{}: {}", ref_file, warning);
+    }
+
+    let providers = report.providers;
+    let coingecko = match providers.get("coingecko") {
+        Some(c) => c,
+        None => return Err(format!("{}: no coingecko provider", ref_file)),
+    };
+
+    println!(
+        "{}: ok, {} providers, coingecko tracks {} coins in {} currencies",
+        ref_file,
+        providers.len(),
+        coingecko.get_coins().len(),
+        coingecko.get_currencies().len(),
+    );
+    Ok(())
+}
+
+// backfill stores one stack per day from from to to, both
+// included, with the daily prices of coins
+pub fn backfill(config: &Config, coins: &[String], from: NaiveDate, to: NaiveDate) -> Result<(), String> {
+    let coingecko = load_coingecko(config)?;
+    let db = collector::db_connection(&config.database.uri, &config.database.name);
+    let registry = asset::Registry::load(&db.new_collection::<asset::Asset>(asset::COLLECTION))?;
+    let history = db.new_collection::<Stack>(&config.sinks.price_history);
+    let mut date = from;
+
+    while date <= to {
+        backfill_day(&coingecko, &registry, &history, coins, date)?;
+        date = match date.succ_opt() {
+            Some(d) => d,
+            None => break,
+        };
+    }
+
+    info!("Backfill from {} to {} done", from, to);
+    Ok(())
+}
+
+// backfill_day stores the stack of date with the daily prices of coins
+fn backfill_day(
+    coingecko: &Provider,
+    registry: &asset::Registry,
+    history: &impl Collection<Stack>,
+    coins: &[String],
+    date: NaiveDate,
+) -> Result<(), String> {
+    let mut stack = Stack::new();
+    stack.created_at = day_start(date);
+    stack.id = coin::tick_id(coingecko.get_name(), stack.created_at);
+
+    for id in coins {
+        match gecko::coins_history(coingecko, id, date) {
+            Ok(coin) => {
+                stack.coins.insert(id.to_owned(), coin);
+            },
+            Err(err) => warn!("backfill {} on {}: {}", id, date, err),
+        }
+        thread::sleep(BACKFILL_PAUSE);
+    }
+
+    let stack = registry.canonicalize(coingecko.get_name(), stack);
+    if stack.coins.is_empty() {
+        eprintln!("{}: no data", date);
+    } else {
+        let count = stack.coins.len();
+        // merged, as a live tick may fall on the same key
+        import::merge_stack(stack, history)?;
+        println!("{}: {} coins stored", date, count);
+    }
+    Ok(())
+}
+
+fn format_tick(tick: i64) -> String {
+    match Utc.timestamp_millis_opt(tick).single() {
+        Some(t) => t.to_rfc3339(),
+        None => tick.to_string(),
+    }
+}
+
+// gaps prints the gaps in price history of coins, every coin if none
+// is given, from from to to, since yesterday by default, and the ticks
+// coins missed since they were last fetched. With
+// backfill, the days gaps span are backfilled with daily prices.
+pub fn gaps(config: &Config, coins: &[String], from: Option<NaiveDate>, to: Option<NaiveDate>, backfill: bool) -> Result<(), String> {
+    let db = collector::db_connection(&config.database.uri, &config.database.name);
+    let yesterday = Utc::now().date_naive().pred_opt();
+    let (from, to) = export::date_range(from.or(yesterday), to);
+    let interval_ms = (config.scheduler.interval_secs * 1000) as i64;
+    let min_ticks = config.gaps.min_ticks;
+
+    let history = db.new_collection::<Stack>(&config.sinks.price_history);
+    let found = gaps::detect(history.find_range("created_at", from, to), coins, interval_ms, from, to, min_ticks)?;
+    for gap in &found {
+        println!("{}: {} ticks missing from {} to {}", gap.coin, gap.missing, format_tick(gap.from), format_tick(gap.to));
+    }
+
+    let now = Utc::now().timestamp_millis();
+    for status in db.new_collection::<FetchStatus>(&config.status.collection).find_all()? {
+        if status.untracked || (!coins.is_empty() && !coins.contains(&status.coin)) {
+            continue;
+        }
+        let missing = gaps::missing_since_fetch(&status, interval_ms, now);
+        if missing >= min_ticks {
+            println!("{}: not fetched for {} ticks", status.coin, missing);
+        }
+    }
+    info!("{} gaps found in price history", found.len());
+    if !backfill || found.is_empty() {
+        return Ok(());
+    }
+
+    // days whose start falls in a gap, with the coins missing then
+    let mut days: BTreeMap<NaiveDate, Vec<String>> = BTreeMap::new();
+    for gap in &found {
+        let mut date = match Utc.timestamp_millis_opt(gap.from).single() {
+            Some(t) => t.date_naive(),
+            None => continue,
+        };
+        if day_start(date) < gap.from {
+            date = match date.succ_opt() {
+                Some(d) => d,
+                None => continue,
+            };
+        }
+        while day_start(date) <= gap.to {
+            days.entry(date).or_default().push(gap.coin.to_owned());
+            date = match date.succ_opt() {
+                Some(d) => d,
+                None => break,
+            };
+        }
+    }
+
+    let coingecko = load_coingecko(config)?;
+    let registry = asset::Registry::load(&db.new_collection::<asset::Asset>(asset::COLLECTION))?;
+    for (date, coins) in &days {
+        backfill_day(&coingecko, &registry, &history, coins, *date)?;
+    }
+    info!("Backfilled {} days", days.len());
+    Ok(())
+}
+
+// export writes price history rows in format, keeping only
+// coins if any is given. to is included.
+pub fn export(
+    config: &Config,
+    coins: &[String],
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+    format: Format,
+    output: Option<&str>,
+) -> Result<(), String> {
+    let db = collector::db_connection(&config.database.uri, &config.database.name);
+    let (from, to) = export::date_range(from, to);
+
+    let out: BufWriter<Box<dyn Write + Send>> = match output {
+        Some(path) => BufWriter::new(Box::new(File::create(path).map_err(|err| format!("{}: {}", path, err))?)),
+        None => BufWriter::new(Box::new(io::stdout())),
+    };
+
+    let coll = db.new_collection::<Stack>(&config.sinks.price_history);
+    let count = export::export(coll.find_range("created_at", from, to), coins, format, out)?;
+    info!("Exported {} rows", count);
+    Ok(())
+}
+
+// import upserts price history from an export file, or stdin
+pub fn import(config: &Config, format: Format, input: Option<&str>) -> Result<(), String> {
+    let db = collector::db_connection(&config.database.uri, &config.database.name);
+    let input: Box<dyn BufRead> = match input {
+        Some(path) => Box::new(BufReader::new(File::open(path).map_err(|err| format!("{}: {}", path, err))?)),
+        None => Box::new(BufReader::new(io::stdin())),
+    };
+
+    let coll = db.new_collection::<Stack>(&config.sinks.price_history);
+    let summary = import::import(input, format, &coll)?;
+    println!("Imported {} lines in {} stacks", summary.lines, summary.stacks);
+    Ok(())
+}
+
+// coins manages the coin_info collection
+pub fn coins(config: &Config, action: &CoinsAction) -> Result<(), String> {
+    let db = collector::db_connection(&config.database.uri, &config.database.name);
+    let coll = db.new_collection::<CoinInfo>("coin_info");
+
+    match action {
+        CoinsAction::List => {
+            let mut infos = coll.find_all()?;
+            infos.sort_by(|a, b| a.id.cmp(&b.id));
+            for info in infos {
+                println!(
+                    "{}\t{}\t{}\t{:?}\t{}\t{}\t{}",
+                    info.id,
+                    info.symbol,
+                    info.name,
+                    info.status,
+                    info.first_seen,
+                    info.last_seen,
+                    info.providers.join(","),
+                );
+            }
+            Ok(())
+        },
+        CoinsAction::Add { id, symbol } => coin_info::add(&coll, id, symbol, Utc::now().timestamp_millis()),
+        CoinsAction::Remove { id } => coll.delete(id.to_owned()),
+        CoinsAction::Map { id, provider, provider_id } => {
+            asset::map(&db.new_collection::<asset::Asset>(asset::COLLECTION), id, provider, provider_id)
+        },
+    }
+}
+
+// push_providers validates a providers file and stores it in the
+// providers collection, for replicas reading providers from the database
+pub fn push_providers(config: &Config, ref_file: Option<&str>) -> Result<(), String> {
+    let ref_file = ref_file.unwrap_or(&config.providers.ref_file);
+    let db = collector::db_connection(&config.database.uri, &config.database.name);
+    let coll = db.new_collection::<ProvidersDocument>(&config.providers.collection);
+    let print = |diagnostics: Vec<Diagnostic>| {
+        let count = diagnostics.len();
+        for d in diagnostics {
+            eprintln!("{}: {}", ref_file, d);
+        }
+        format!("{}: {} errors", ref_file, count)
+    };
+
+    let report = TomlFile::new(ref_file).load().map_err(print)?;
+    let version = provider_source::publish(&coll, report.providers, report.fx_providers).map_err(print)?;
+    println!("{}: stored in {} collection, version {}", ref_file, config.providers.collection, version);
+    Ok(())
+}
+
+// portfolio manages portfolios and reports their latest valuation
+pub fn portfolio(config: &Config, action: &PortfolioAction) -> Result<(), String> {
+    let db = collector::db_connection(&config.database.uri, &config.database.name);
+    let coll = db.new_collection::<portfolio::Portfolio>(portfolio::COLLECTION);
+
+    match action {
+        PortfolioAction::List => {
+            let mut portfolios = coll.find_all()?;
+            portfolios.sort_by(|a, b| a.id.cmp(&b.id));
+            for p in portfolios {
+                let holdings: Vec<String> = p.holdings.iter().map(|h| format!("{}={}", h.coin, h.quantity)).collect();
+                println!("{}\t{}", p.id, holdings.join(","));
+            }
+            Ok(())
+        },
+        PortfolioAction::Set { portfolio } => coll.save(portfolio.id.to_owned(), portfolio),
+        PortfolioAction::Remove { id } => coll.delete(id.to_owned()),
+        PortfolioAction::Show { id, currency, since } => {
+            let history = db.new_collection::<portfolio::Snapshot>(&config.sinks.portfolio_history);
+            let report = portfolio::report(&history, id, currency, since.map(day_start), Utc::now().timestamp_millis())?;
+            let out = serde_json::to_string_pretty(&report).map_err(|err| err.to_string())?;
+            println!("{}", out);
+            Ok(())
+        },
+    }
+}
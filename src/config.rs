@@ -1,36 +1,551 @@
-use std::env;
-use log::warn;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::{env, process};
 
-pub struct Config {
+use crate::indicators::Jobs;
+
+// Source tells which configuration layer a value comes from
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    Default,
+    File(String),
+    Env(&'static str),
+    Flag(&'static str),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(path) => write!(f, "file {}", path),
+            Source::Env(var) => write!(f, "env {}", var),
+            Source::Flag(flag) => write!(f, "flag {}", flag),
+        }
+    }
+}
+
+// ConfigError describes a bad or missing configuration value
+#[derive(Debug, PartialEq)]
+pub struct ConfigError {
+    pub key: String,
+    pub source: Option<Source>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.source {
+            Some(source) => write!(f, "{} (from {}): {}", self.key, source, self.message),
+            None => write!(f, "{}: {}", self.key, self.message),
+        }
+    }
+}
+
+// Setting declares a configuration key, the env var and the
+// CLI flag overriding it, and its default value
+struct Setting {
+    key: &'static str,
+    env: &'static str,
+    flag: &'static str,
+    default: Option<&'static str>,
+}
+
+const SETTINGS: &[Setting] = &[
+    Setting { key: "database.uri", env: "MONGODB_URI", flag: "--mongodb-uri", default: Some("mongodb://localhost:27017") },
+    Setting { key: "database.name", env: "MONGODB_DB", flag: "--mongodb-db", default: Some("coins") },
+    Setting { key: "scheduler.interval_secs", env: "INTERVAL_SECS", flag: "--interval-secs", default: Some("64") },
+    Setting { key: "scheduler.provider_refresh_ticks", env: "PROVIDER_REFRESH_TICKS", flag: "--provider-refresh-ticks", default: Some("4") },
+    Setting { key: "providers.source", env: "PROVIDERS_SOURCE", flag: "--providers-source", default: Some("file") },
+    Setting { key: "providers.collection", env: "PROVIDERS_COLLECTION", flag: "--providers-collection", default: Some("providers") },
+    Setting { key: "providers.ref_file", env: "REF_FILE", flag: "--ref-file", default: Some("providers.toml") },
+    Setting { key: "providers.chunk_size", env: "CHUNK_SIZE", flag: "--chunk-size", default: Some("250") },
+    Setting { key: "sinks.price_history", env: "PRICE_HISTORY_COLLECTION", flag: "--price-history-collection", default: Some("price_history") },
+    Setting { key: "sinks.latest_entries", env: "LATEST_ENTRIES_COLLECTION", flag: "--latest-entries-collection", default: Some("latest_entries") },
+    Setting { key: "sinks.fx_rates", env: "FX_RATES_COLLECTION", flag: "--fx-rates-collection", default: Some("fx_rates") },
+    Setting { key: "sinks.portfolio_history", env: "PORTFOLIO_HISTORY_COLLECTION", flag: "--portfolio-history-collection", default: Some("portfolio_history") },
+    Setting { key: "sinks.prices_max_len", env: "PRICES_MAX_LEN", flag: "--prices-max-len", default: Some("2") },
+    Setting { key: "http.addr", env: "HTTP_ADDR", flag: "--http-addr", default: Some("0.0.0.0:8080") },
+    Setting { key: "leader.enabled", env: "LEADER_ELECTION", flag: "--leader-election", default: Some("false") },
+    Setting { key: "leader.lease_ttl_secs", env: "LEASE_TTL_SECS", flag: "--lease-ttl-secs", default: Some("200") },
+    Setting { key: "leader.collection", env: "LEASES_COLLECTION", flag: "--leases-collection", default: Some("leases") },
+    Setting { key: "indicators.jobs", env: "INDICATOR_JOBS", flag: "--indicator-jobs", default: Some("") },
+    Setting { key: "indicators.candle_secs", env: "INDICATOR_CANDLE_SECS", flag: "--indicator-candle-secs", default: Some("3600") },
+    Setting { key: "indicators.lookback_candles", env: "INDICATOR_LOOKBACK_CANDLES", flag: "--indicator-lookback-candles", default: Some("48") },
+    Setting { key: "indicators.every_ticks", env: "INDICATOR_EVERY_TICKS", flag: "--indicator-every-ticks", default: Some("16") },
+    Setting { key: "indicators.collection", env: "INDICATOR_HISTORY_COLLECTION", flag: "--indicator-history-collection", default: Some("indicator_history") },
+    Setting { key: "quality.max_jump_pct", env: "MAX_JUMP_PCT", flag: "--max-jump-pct", default: Some("50") },
+    Setting { key: "quality.max_staleness_secs", env: "MAX_STALENESS_SECS", flag: "--max-staleness-secs", default: Some("3600") },
+    Setting { key: "quality.collection", env: "QUARANTINE_COLLECTION", flag: "--quarantine-collection", default: Some("quarantine") },
+    Setting { key: "gaps.min_ticks", env: "GAP_MIN_TICKS", flag: "--gap-min-ticks", default: Some("3") },
+    Setting { key: "status.collection", env: "FETCH_STATUS_COLLECTION", flag: "--fetch-status-collection", default: Some("fetch_status") },
+    Setting { key: "status.alert_misses", env: "ALERT_MISSES", flag: "--alert-misses", default: Some("5") },
+    Setting { key: "http.ready_intervals", env: "READY_INTERVALS", flag: "--ready-intervals", default: Some("3") },
+];
+
+// CONFIG_ENV and CONFIG_FLAG point to the optional TOML config file
+const CONFIG_ENV: &str = "CONFIG_FILE";
+const CONFIG_FLAG: &str = "--config";
+
+pub struct DatabaseConfig {
+    pub uri: String,
+    pub name: String,
+}
+
+pub struct SchedulerConfig {
+    pub interval_secs: u64,
+    pub provider_refresh_ticks: u32,
+}
+
+// ProvidersSource tells where providers are read from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProvidersSource {
+    File,
+    Database,
+}
+
+impl FromStr for ProvidersSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "file" => Ok(ProvidersSource::File),
+            "database" => Ok(ProvidersSource::Database),
+            _ => Err("must be file or database".into()),
+        }
+    }
+}
+
+pub struct ProvidersConfig {
+    pub source: ProvidersSource,
+    // collection holds providers if source is database
+    pub collection: String,
     pub ref_file: String,
-    pub mongodb_uri: String,
+    pub chunk_size: usize,
+}
+
+pub struct SinksConfig {
+    pub price_history: String,
+    pub latest_entries: String,
+    pub fx_rates: String,
+    pub portfolio_history: String,
     pub prices_max_len: usize,
 }
 
-impl Config {
-    pub fn parse() -> Config {
-        let ref_file = match env::var("REF_FILE") {
-            Ok(rf) => rf,
-            Err(err) => panic!("Problem retrieving REF_FILE env var: {}", err),
+pub struct HttpConfig {
+    pub addr: String,
+    pub ready_intervals: i64,
+}
+
+pub struct LeaderConfig {
+    pub enabled: bool,
+    // lease_ttl_secs should span a few intervals, as the leader
+    // renews its lease once per tick
+    pub lease_ttl_secs: u64,
+    pub collection: String,
+}
+
+// IndicatorsConfig schedules indicator jobs every every_ticks ticks,
+// over the last lookback_candles candles of candle_secs
+pub struct IndicatorsConfig {
+    pub jobs: Jobs,
+    pub candle_secs: u64,
+    pub lookback_candles: usize,
+    pub every_ticks: u32,
+    pub collection: String,
+}
+
+// QualityConfig holds the thresholds prices are quarantined past,
+// 0 disabling a check
+pub struct QualityConfig {
+    pub max_jump_pct: f64,
+    pub max_staleness_secs: i64,
+    pub collection: String,
+}
+
+// GapsConfig holds how many missing ticks of a coin make a gap
+pub struct GapsConfig {
+    pub min_ticks: i64,
+}
+
+// StatusConfig holds where per coin fetch statuses are stored and
+// after how many consecutive misses a coin is alerted on
+pub struct StatusConfig {
+    pub collection: String,
+    pub alert_misses: u32,
+}
+
+pub struct Config {
+    pub database: DatabaseConfig,
+    pub scheduler: SchedulerConfig,
+    pub providers: ProvidersConfig,
+    pub sinks: SinksConfig,
+    pub http: HttpConfig,
+    pub leader: LeaderConfig,
+    pub indicators: IndicatorsConfig,
+    pub quality: QualityConfig,
+    pub gaps: GapsConfig,
+    pub status: StatusConfig,
+}
+
+// Layers holds the raw value of every key along with its source,
+// later layers overriding earlier ones
+struct Layers {
+    values: HashMap<&'static str, (String, Source)>,
+    errors: Vec<ConfigError>,
+}
+
+impl Layers {
+    fn set(&mut self, key: &'static str, value: String, source: Source) {
+        self.values.insert(key, (value, source));
+    }
+
+    fn get<T: FromStr>(&mut self, key: &'static str, check: fn(&T) -> Result<(), String>) -> Option<T>
+    where T::Err: fmt::Display {
+        let (raw, source) = match self.values.get(key) {
+            Some(v) => v.clone(),
+            None => {
+                self.errors.push(ConfigError {
+                    key: key.to_string(),
+                    source: None,
+                    message: "missing value".into(),
+                });
+                return None;
+            },
+        };
+
+        let res = raw
+            .parse::<T>()
+            .map_err(|err| format!("invalid value \"{}\": {}", raw, err))
+            .and_then(|v| check(&v).map(|_| v));
+
+        match res {
+            Ok(v) => Some(v),
+            Err(message) => {
+                self.errors.push(ConfigError { key: key.to_string(), source: Some(source), message });
+                None
+            },
+        }
+    }
+}
+
+fn any<T>(_: &T) -> Result<(), String> {
+    Ok(())
+}
+
+fn positive<T: Default + PartialOrd>(v: &T) -> Result<(), String> {
+    if *v > T::default() {
+        return Ok(());
+    }
+    Err("must be greater than 0".into())
+}
+
+fn non_negative<T: Default + PartialOrd>(v: &T) -> Result<(), String> {
+    if *v >= T::default() {
+        return Ok(());
+    }
+    Err("must not be negative".into())
+}
+
+fn not_empty<S: AsRef<str>>(v: &S) -> Result<(), String> {
+    if v.as_ref().is_empty() {
+        return Err("must not be empty".into());
+    }
+    Ok(())
+}
+
+fn mongodb_uri<S: AsRef<str>>(v: &S) -> Result<(), String> {
+    let v = v.as_ref();
+    if v.starts_with("mongodb://") || v.starts_with("mongodb+srv://") {
+        return Ok(());
+    }
+    Err("must start with mongodb:// or mongodb+srv://".into())
+}
+
+fn socket_addr<S: AsRef<str>>(v: &S) -> Result<(), String> {
+    SocketAddr::from_str(v.as_ref()).map(|_| ()).map_err(|err| err.to_string())
+}
+
+// parse_flags reads --flag value and --flag=value pairs
+fn parse_flags(args: &[String]) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut flags = vec![];
+    let mut it = args.iter();
+
+    while let Some(arg) = it.next() {
+        if !arg.starts_with("--") {
+            return Err(ConfigError { key: arg.to_owned(), source: None, message: "unexpected argument".into() });
+        }
+        let (flag, value) = match arg.split_once('=') {
+            Some((f, v)) => (f.to_string(), v.to_string()),
+            None => match it.next() {
+                Some(v) => (arg.to_owned(), v.to_owned()),
+                None => return Err(ConfigError { key: arg.to_owned(), source: None, message: "missing value".into() }),
+            },
         };
+        flags.push((flag, value));
+    }
+
+    Ok(flags)
+}
+
+// read_file flattens a TOML config file into section.key values
+fn read_file(path: &str, layers: &mut Layers) {
+    let source = Source::File(path.to_string());
+    let file_error = |message: String| ConfigError { key: CONFIG_FLAG.into(), source: Some(source.clone()), message };
+
+    let content = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(err) => return layers.errors.push(file_error(err.to_string())),
+    };
+    let root = match content.parse::<toml::Value>() {
+        Ok(toml::Value::Table(t)) => t,
+        Ok(_) => return layers.errors.push(file_error("expected a table".into())),
+        Err(err) => return layers.errors.push(file_error(err.to_string())),
+    };
 
-        let mongodb_uri = match env::var("MONGODB_URI") {
-            Ok(mu) => mu,
-            Err(err) => panic!("Problem retrieving MONGODB_URI env var: {}", err),
+    for (section, table) in root.iter() {
+        let table = match table.as_table() {
+            Some(t) => t,
+            None => {
+                layers.errors.push(ConfigError { key: section.to_owned(), source: Some(source.clone()), message: "expected a section".into() });
+                continue;
+            },
         };
+        for (name, value) in table.iter() {
+            let key = format!("{}.{}", section, name);
+            let setting = match SETTINGS.iter().find(|s| s.key == key) {
+                Some(s) => s,
+                None => {
+                    layers.errors.push(ConfigError { key, source: Some(source.clone()), message: "unknown key".into() });
+                    continue;
+                },
+            };
+            let raw = match value {
+                toml::Value::String(s) => s.to_owned(),
+                toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => value.to_string(),
+                _ => {
+                    layers.errors.push(ConfigError { key, source: Some(source.clone()), message: "expected a scalar value".into() });
+                    continue;
+                },
+            };
+            layers.set(setting.key, raw, source.clone());
+        }
+    }
+}
 
-        let prices_max_len = match env::var("PRICES_MAX_LEN") {
-            Ok(pml) => pml.parse::<usize>().unwrap(),
-            Err(err) => {
-                warn!("{}", err);
-                2
+impl Config {
+    // load builds a Config from defaults, then the TOML config file,
+    // then environment variables, then CLI flags
+    pub fn load(args: &[String], env_var: impl Fn(&str) -> Option<String>) -> Result<Config, Vec<ConfigError>> {
+        let mut layers = Layers { values: HashMap::new(), errors: vec![] };
+
+        for setting in SETTINGS {
+            if let Some(default) = setting.default {
+                layers.set(setting.key, default.to_string(), Source::Default);
+            }
+        }
+
+        let flags = match parse_flags(args) {
+            Ok(f) => f,
+            Err(err) => return Err(vec![err]),
+        };
+
+        let config_file = flags
+            .iter()
+            .rev()
+            .find(|(f, _)| f == CONFIG_FLAG)
+            .map(|(_, v)| v.to_owned())
+            .or_else(|| env_var(CONFIG_ENV));
+        if let Some(path) = config_file {
+            read_file(&path, &mut layers);
+        }
+
+        for setting in SETTINGS {
+            if let Some(value) = env_var(setting.env) {
+                layers.set(setting.key, value, Source::Env(setting.env));
+            }
+        }
+
+        for (flag, value) in flags {
+            if flag == CONFIG_FLAG {
+                continue;
+            }
+            match SETTINGS.iter().find(|s| s.flag == flag) {
+                Some(setting) => layers.set(setting.key, value, Source::Flag(setting.flag)),
+                None => layers.errors.push(ConfigError { key: flag, source: None, message: "unknown flag".into() }),
+            }
+        }
+
+        let config = Config {
+            database: DatabaseConfig {
+                uri: layers.get("database.uri", mongodb_uri).unwrap_or_default(),
+                name: layers.get("database.name", not_empty).unwrap_or_default(),
+            },
+            scheduler: SchedulerConfig {
+                interval_secs: layers.get("scheduler.interval_secs", positive).unwrap_or_default(),
+                provider_refresh_ticks: layers.get("scheduler.provider_refresh_ticks", positive).unwrap_or_default(),
+            },
+            providers: ProvidersConfig {
+                source: layers.get("providers.source", any).unwrap_or(ProvidersSource::File),
+                collection: layers.get("providers.collection", not_empty).unwrap_or_default(),
+                ref_file: layers.get("providers.ref_file", not_empty).unwrap_or_default(),
+                chunk_size: layers.get("providers.chunk_size", positive).unwrap_or_default(),
+            },
+            sinks: SinksConfig {
+                price_history: layers.get("sinks.price_history", not_empty).unwrap_or_default(),
+                latest_entries: layers.get("sinks.latest_entries", not_empty).unwrap_or_default(),
+                fx_rates: layers.get("sinks.fx_rates", not_empty).unwrap_or_default(),
+                portfolio_history: layers.get("sinks.portfolio_history", not_empty).unwrap_or_default(),
+                prices_max_len: layers.get("sinks.prices_max_len", any).unwrap_or_default(),
+            },
+            http: HttpConfig {
+                addr: layers.get("http.addr", socket_addr).unwrap_or_default(),
+                ready_intervals: layers.get("http.ready_intervals", positive).unwrap_or_default(),
+            },
+            leader: LeaderConfig {
+                enabled: layers.get("leader.enabled", any).unwrap_or_default(),
+                lease_ttl_secs: layers.get("leader.lease_ttl_secs", positive).unwrap_or_default(),
+                collection: layers.get("leader.collection", not_empty).unwrap_or_default(),
+            },
+            indicators: IndicatorsConfig {
+                jobs: layers.get("indicators.jobs", any).unwrap_or_default(),
+                candle_secs: layers.get("indicators.candle_secs", positive).unwrap_or_default(),
+                lookback_candles: layers.get("indicators.lookback_candles", positive).unwrap_or_default(),
+                every_ticks: layers.get("indicators.every_ticks", positive).unwrap_or_default(),
+                collection: layers.get("indicators.collection", not_empty).unwrap_or_default(),
+            },
+            quality: QualityConfig {
+                max_jump_pct: layers.get("quality.max_jump_pct", non_negative).unwrap_or_default(),
+                max_staleness_secs: layers.get("quality.max_staleness_secs", non_negative).unwrap_or_default(),
+                collection: layers.get("quality.collection", not_empty).unwrap_or_default(),
+            },
+            gaps: GapsConfig {
+                min_ticks: layers.get("gaps.min_ticks", positive).unwrap_or_default(),
+            },
+            status: StatusConfig {
+                collection: layers.get("status.collection", not_empty).unwrap_or_default(),
+                alert_misses: layers.get("status.alert_misses", positive).unwrap_or_default(),
             },
         };
 
-        Config {
-            ref_file,
-            mongodb_uri,
-            prices_max_len,
+        if !layers.errors.is_empty() {
+            return Err(layers.errors);
         }
+        Ok(config)
+    }
+
+    // parse loads the Config from the process env and config flags,
+    // and exits listing every error if the config is invalid
+    pub fn parse(args: &[String]) -> Config {
+        match Config::load(args, |var| env::var(var).ok()) {
+            Ok(config) => config,
+            Err(errors) => {
+                for err in errors {
+                    eprintln!("config: {}", err);
+                }
+                process::exit(1);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::{Config, ConfigError, ProvidersSource, Source};
+
+    fn env_of(vars: Vec<(&str, &str)>) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |var| vars.get(var).cloned()
+    }
+
+    fn args_of(args: Vec<&str>) -> Vec<String> {
+        args.into_iter().map(|a| a.to_string()).collect()
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn i_should_load_defaults_and_env() {
+        let env = env_of(vec![("MONGODB_URI", "mongodb://localhost"), ("REF_FILE", "providers.toml")]);
+        let config = Config::load(&[], env).unwrap();
+
+        assert_eq!(config.database.uri, "mongodb://localhost");
+        assert_eq!(config.database.name, "coins");
+        assert_eq!(config.scheduler.interval_secs, 64);
+        assert_eq!(config.providers.ref_file, "providers.toml");
+        assert_eq!(config.providers.source, ProvidersSource::File);
+        assert!(!config.leader.enabled);
+        assert_eq!(config.sinks.prices_max_len, 2);
+        assert_eq!(config.quality.max_jump_pct, 50.0);
+        assert_eq!(config.quality.collection, "quarantine");
+    }
+
+    #[test]
+    fn i_should_override_file_with_env_and_flags() {
+        let env = env_of(vec![("CONFIG_FILE", "./test/coinrd-test.toml"), ("PRICES_MAX_LEN", "5")]);
+        let args = args_of(vec!["--prices-max-len", "8", "--http-addr=127.0.0.1:9000", "--providers-source", "database"]);
+        let config = Config::load(&args, env).unwrap();
+
+        assert_eq!(config.database.uri, "mongodb://file:27017");
+        assert_eq!(config.scheduler.interval_secs, 30);
+        assert_eq!(config.sinks.prices_max_len, 8);
+        assert_eq!(config.http.addr, "127.0.0.1:9000");
+        assert_eq!(config.providers.source, ProvidersSource::Database);
+        assert!(config.indicators.jobs.0.is_empty());
+    }
+
+    #[test]
+    fn i_should_parse_indicator_jobs() {
+        let env = env_of(vec![("INDICATOR_JOBS", "rsi:bitcoin:usd:14,macd:ethereum:eur")]);
+        let config = Config::load(&[], env).unwrap();
+        assert_eq!(config.indicators.jobs.0.len(), 2);
+        assert_eq!(config.indicators.candle_secs, 3600);
+
+        let errors = Config::load(&args_of(vec!["--indicator-jobs", "rsi:bitcoin"]), env_of(vec![])).err().unwrap();
+        assert!(errors.iter().any(|e| e.key == "indicators.jobs"));
+    }
+
+    #[test]
+    fn i_should_reject_bad_chunk_sizes() {
+        let errors = Config::load(&[], env_of(vec![("CHUNK_SIZE", "many")])).err().unwrap();
+        assert!(errors.iter().any(|e| e.key == "providers.chunk_size"));
+    }
+
+    #[test]
+    fn i_should_reject_bad_ready_intervals() {
+        for value in &["three", "0"] {
+            let errors = Config::load(&[], env_of(vec![("READY_INTERVALS", value)])).err().unwrap();
+            assert!(errors.iter().any(|e| e.key == "http.ready_intervals" && e.source == Some(Source::Env("READY_INTERVALS"))));
+        }
+    }
+
+    #[test]
+    fn i_should_report_bad_values_with_their_source() {
+        let env = env_of(vec![("MONGODB_URI", "localhost"), ("INTERVAL_SECS", "0"), ("REF_FILE", "")]);
+        let args = args_of(vec!["--prices-max-len", "two", "--nope", "1"]);
+        let errors = Config::load(&args, env).err().unwrap();
+
+        assert!(errors.contains(&ConfigError {
+            key: "database.uri".into(),
+            source: Some(Source::Env("MONGODB_URI")),
+            message: "must start with mongodb:// or mongodb+srv://".into(),
+        }));
+        assert!(errors.iter().any(|e| e.key == "scheduler.interval_secs" && e.source == Some(Source::Env("INTERVAL_SECS"))));
+        assert!(errors.iter().any(|e| e.key == "sinks.prices_max_len" && e.source == Some(Source::Flag("--prices-max-len"))));
+        assert!(errors.iter().any(|e| e.key == "providers.ref_file" && e.message == "must not be empty"));
+        assert!(errors.iter().any(|e| e.key == "--nope" && e.message == "unknown flag"));
+    }
+
+    #[test]
+    fn i_should_report_unknown_file_keys() {
+        let env = env_of(vec![("CONFIG_FILE", "./test/coinrd-test-bad.toml")]);
+        let errors = Config::load(&[], env).err().unwrap();
+
+        assert!(errors.iter().any(|e| e.key == "scheduler.pouet"
+            && e.source == Some(Source::File("./test/coinrd-test-bad.toml".into()))));
+    }
+}
@@ -1,22 +1,69 @@
+use std::collections::{BTreeMap, HashMap};
 use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
 
-use mongodb::{bson::{Bson, doc, from_bson, to_bson, ser::Error, Document}, options::ReplaceOptions, sync::{Collection as MongoColl, Database as MongoDatabase}};
+use mongodb::{bson::{Bson, doc, from_bson, to_bson, ser::Error, Document}, options::{FindOptions, ReplaceOptions, UpdateOptions}, sync::{Collection as MongoColl, Database as MongoDatabase}};
+use mongodb::error::{Error as MongoError, ErrorKind, WriteFailure};
 use serde::{Serialize, Deserialize};
+use serde_json::Value;
 use log::{warn, error};
 
 pub trait Collection<T> {
-  fn find_one(self: &Self, id: String) -> Option<T>
+  fn find_one(&self, id: String) -> Option<T>
   where T: for <'a> Deserialize<'a> + std::fmt::Debug;
 
-  fn save(self: &Self, id: String, entity: &T)
+  // get is find_one giving query and unserialization errors
+  // rather than None
+  fn get(&self, id: String) -> Result<Option<T>, String>
+  where T: for <'a> Deserialize<'a>;
+
+  fn save(&self, id: String, entity: &T) -> Result<(), String>
   where T: Serialize;
 
-  fn insert(self: &Self, entity: &T)
+  fn insert(&self, entity: &T) -> Result<(), String>
   where T: Serialize;
+
+  fn find_all(&self) -> Result<Vec<T>, String>
+  where T: for <'a> Deserialize<'a>;
+
+  // find_range streams entities which numeric field is
+  // within [from, to), sorted by field. Query and cursor
+  // errors are streamed for the caller to stop on.
+  fn find_range(&self, field: &str, from: i64, to: i64) -> Box<dyn Iterator<Item = Result<T, String>> + '_>
+  where T: for <'a> Deserialize<'a> + 'static;
+
+  fn delete(&self, id: String) -> Result<(), String>;
+
+  // save_if atomically saves entity only if the numeric field of the
+  // stored document equals expected, or if there is no document when
+  // expected is None. Returns whether entity was saved.
+  fn save_if(&self, id: String, field: &str, expected: Option<i64>, entity: &T) -> Result<bool, String>
+  where T: Serialize;
+
+  // update atomically applies update to the document with id,
+  // upserting it, so concurrent updates of other fields are kept
+  fn update(&self, id: String, update: &Update) -> Result<(), String>;
+}
+
+// Update changes fields of a document by their dotted paths. set
+// replaces values, add_to_set adds values missing from arrays and
+// pull removes values from arrays. A path may appear in one list only.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Update {
+  pub set: Vec<(String, Value)>,
+  pub add_to_set: Vec<(String, Vec<Value>)>,
+  pub pull: Vec<(String, Vec<Value>)>,
+}
+
+impl Update {
+  pub fn is_empty(&self) -> bool {
+    self.set.is_empty() && self.add_to_set.is_empty() && self.pull.is_empty()
+  }
 }
 
 // MongoDB acts as a light factory for
 // MongoCollection<T> trait Collection
+#[derive(Clone)]
 pub struct MongoDB {
   db: MongoDatabase,
 }
@@ -35,6 +82,39 @@ impl MongoDB {
     }
   }
 
+  // ensure_unique_index creates a unique index on the string field of
+  // coll, doing nothing if it already exists. Documents without a
+  // value, such as stacks stored before ids were given, are left out.
+  pub fn ensure_unique_index(&self, coll: &str, field: &str) -> Result<(), String> {
+    self.db
+      .run_command(doc!{
+        "createIndexes": coll,
+        "indexes": [{
+          "key": {field: 1},
+          "name": format!("{}_unique", field),
+          "unique": true,
+          "partialFilterExpression": {field: {"$type": "string", "$gt": ""}},
+        }],
+      }, None)
+      .map(|_| ())
+      .map_err(|err| err.to_string())
+  }
+
+  // ensure_index creates an ascending index on field of coll,
+  // doing nothing if it already exists
+  pub fn ensure_index(&self, coll: &str, field: &str) -> Result<(), String> {
+    self.db
+      .run_command(doc!{
+        "createIndexes": coll,
+        "indexes": [{
+          "key": {field: 1},
+          "name": format!("{}_1", field),
+        }],
+      }, None)
+      .map(|_| ())
+      .map_err(|err| err.to_string())
+  }
+
   // to_mongo_db gives Mongo's Database struct
   pub fn to_mongo_db(&self) -> MongoDatabase {
     self.db.to_owned()
@@ -55,77 +135,421 @@ impl<T> Collection<T> for MongoCollection<T> {
     match self.collection.find_one(doc!{"id": &id}, None) {
       Ok(maybe_document) => match maybe_document {
           Some(doc) => match from_bson::<T>(Bson::Document(doc.to_owned())) {
-              Ok(r) => return Some(r),
+              Ok(r) => Some(r),
               Err(err) => {
-                  println!("Could not unserialize document with id {} in {} collection: {} ", id, self.collection.name(), err);
                   warn!("Could not unserialize document with id {} in {} collection: {} ", id, self.collection.name(), err);
-                  return None
+                  None
               },
           },
           None => {
-              println!("Could not find any document for id {} in {} collection", id, self.collection.name());
               warn!("Could not find any document for id {} in {} collection", id, self.collection.name());
               None
           }, 
       },
       Err(err) => {
-          println!("Could not query any document with id {} in {} collection: {} ", id, self.collection.name(), err);
           warn!("Could not query any document with id {} in {} collection: {} ", id, self.collection.name(), err);
           None
       }
     }
   }
 
+  fn get(&self, id: String) -> Result<Option<T>, String>
+  where T: for<'de> Deserialize<'de> {
+    let name = self.collection.name();
+    match self.collection.find_one(doc!{"id": &id}, None) {
+      Ok(Some(doc)) => from_bson::<T>(Bson::Document(doc))
+        .map(Some)
+        .map_err(|err| format!("could not unserialize document with id {} in {} collection: {}", id, name, err)),
+      Ok(None) => Ok(None),
+      Err(err) => Err(format!("could not query document with id {} in {} collection: {}", id, name, err)),
+    }
+  }
+
   // save of MongoCollection struct performs a 
   // replace_one operation on a MongoDB collection
-  fn save(&self, id: String, entity: &T)
+  fn save(&self, id: String, entity: &T) -> Result<(), String>
   where T: Serialize {
     // secure the json serialization of the entity
     let doc = match unwrap_bson(to_bson(&entity)) {
       Ok(doc) => doc,
       Err(err) => {
         error!("Err save: {}", err);
-        return
+        return Err(err)
       },
     };
     // Actually performs replace_one operation on MongoDB Collection
-    match self.collection.replace_one(
+    if let Err(err) = self.collection.replace_one(
         doc!{"id": id},
         doc, 
         ReplaceOptions::builder().upsert(true).build(),
     ) {
-        Err(err) => error!("Err save: {}", err),
-        _ => (),
-    };
+        error!("Err save: {}", err);
+        return Err(err.to_string())
+    }
+    Ok(())
   }
 
-  fn insert(&self, entity: &T)
+  fn insert(&self, entity: &T) -> Result<(), String>
   where T: Serialize {
     let doc = match unwrap_bson(to_bson(&entity)) {
       Ok(doc) => doc,
       Err(err) => {
         error!("Err insert: {}", err);
-        return
+        return Err(err)
       },
     };
-    // Actually performs replace_one operation on MongoDB Collection
-    match self.collection.insert_one(doc, None) {
-        Err(err) => error!("Err insert: {}", err),
-        _ => (),
+    // Actually performs insert_one operation on MongoDB Collection
+    if let Err(err) = self.collection.insert_one(doc, None) {
+        error!("Err insert: {}", err);
+        return Err(err.to_string())
+    }
+    Ok(())
+  }
+
+  fn find_all(&self) -> Result<Vec<T>, String>
+  where T: for<'de> Deserialize<'de> {
+    self.find_documents(doc!{}, None).collect()
+  }
+
+  fn find_range(&self, field: &str, from: i64, to: i64) -> Box<dyn Iterator<Item = Result<T, String>> + '_>
+  where T: for<'de> Deserialize<'de> + 'static {
+    Box::new(self.find_documents(
+      doc!{field: {"$gte": from, "$lt": to}},
+      Some(FindOptions::builder().sort(doc!{field: 1}).build()),
+    ))
+  }
+
+  fn delete(&self, id: String) -> Result<(), String> {
+    match self.collection.delete_one(doc!{"id": &id}, None) {
+      Ok(res) if res.deleted_count == 0 => Err(format!("no document with id {} in {} collection", id, self.collection.name())),
+      Ok(_) => Ok(()),
+      Err(err) => {
+        error!("Err delete: {}", err);
+        Err(err.to_string())
+      },
+    }
+  }
+
+  fn save_if(&self, id: String, field: &str, expected: Option<i64>, entity: &T) -> Result<bool, String>
+  where T: Serialize {
+    let doc = unwrap_bson(to_bson(&entity))?;
+    let res = match expected {
+      Some(v) => self.collection
+        .replace_one(doc!{"id": &id, field: v}, doc, None)
+        .map(|r| r.matched_count == 1),
+      // the unique index on id makes concurrent inserts fail
+      None => self.collection
+        .update_one(doc!{"id": &id}, doc!{"$setOnInsert": doc}, UpdateOptions::builder().upsert(true).build())
+        .map(|r| r.upserted_id.is_some()),
     };
+    match res {
+      Ok(saved) => Ok(saved),
+      Err(err) if is_duplicate_key(&err) => Ok(false),
+      Err(err) => {
+        error!("Err save_if: {}", err);
+        Err(err.to_string())
+      },
+    }
+  }
+
+  fn update(&self, id: String, update: &Update) -> Result<(), String> {
+    let bson = |value: &Value| to_bson(value).map_err(|err| err.to_string());
+    let mut set = Document::new();
+    for (path, value) in &update.set {
+      set.insert(path.to_owned(), bson(value)?);
+    }
+    let mut add_to_set = Document::new();
+    for (path, values) in &update.add_to_set {
+      add_to_set.insert(path.to_owned(), doc!{"$each": bson(&Value::from(values.to_owned()))?});
+    }
+    let mut pull = Document::new();
+    for (path, values) in &update.pull {
+      pull.insert(path.to_owned(), doc!{"$in": bson(&Value::from(values.to_owned()))?});
+    }
+
+    let mut ops = Document::new();
+    for (op, fields) in [("$set", set), ("$addToSet", add_to_set), ("$pull", pull)] {
+      if !fields.is_empty() {
+        ops.insert(op, fields);
+      }
+    }
+    if ops.is_empty() {
+      return Ok(());
+    }
+    if let Err(err) = self.collection.update_one(doc!{"id": &id}, ops, UpdateOptions::builder().upsert(true).build()) {
+      error!("Err update: {}", err);
+      return Err(err.to_string());
+    }
+    Ok(())
   }
 }
 
+fn is_duplicate_key(err: &MongoError) -> bool {
+  matches!(err.kind.as_ref(), ErrorKind::WriteError(WriteFailure::WriteError(e)) if e.code == 11000)
+}
+
+impl<T> MongoCollection<T> {
+  // find_documents lazily unserializes the documents matching filter,
+  // skipping the ones that can't be read. A failed query or cursor
+  // is given as an error.
+  fn find_documents(&self, filter: Document, options: Option<FindOptions>) -> Box<dyn Iterator<Item = Result<T, String>> + '_>
+  where T: for<'de> Deserialize<'de> {
+    let name = self.collection.name();
+    let cursor = match self.collection.find(filter, options) {
+      Ok(c) => c,
+      Err(err) => return Box::new(std::iter::once(Err(format!("could not query {} collection: {}", name, err)))),
+    };
 
+    Box::new(cursor.filter_map(move |res| {
+      let doc = match res {
+        Ok(doc) => doc,
+        Err(err) => return Some(Err(format!("could not read {} collection: {}", name, err))),
+      };
+      match from_bson::<T>(Bson::Document(doc)) {
+        Ok(r) => Some(Ok(r)),
+        Err(err) => {
+          warn!("Could not unserialize document in {} collection: {}", name, err);
+          None
+        },
+      }
+    }))
+  }
+}
 
 // unwrap_bson secures the serialization of the entity
 // and the unwrapping of the underlying document
 fn unwrap_bson(bson: Result<Bson, Error>) -> Result<Document, String> {
   match bson {
     Ok(b) => match b.as_document() {
-      Some(d) => return Ok(d.to_owned()),
+      Some(d) => Ok(d.to_owned()),
       None => Err("Nothing to serde I guess".into()),
     },
     Err(err) => Err(err.to_string()),
   }
-}
\ No newline at end of file
+}
+type MemoryStore = Arc<Mutex<BTreeMap<String, Value>>>;
+
+// MemoryDB is an in-process database, mostly used by tests.
+// Collections of a same MemoryDB share their storage.
+#[derive(Clone, Default)]
+pub struct MemoryDB {
+  collections: Arc<Mutex<HashMap<String, MemoryStore>>>,
+}
+
+impl MemoryDB {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn new_collection<T>(&self, coll: &str) -> MemoryCollection<T> {
+    let mut collections = self.collections.lock().unwrap();
+    MemoryCollection {
+      docs: collections.entry(coll.to_string()).or_default().clone(),
+      pd: PhantomData{},
+    }
+  }
+}
+
+pub struct MemoryCollection<T> {
+  docs: MemoryStore,
+  pd: PhantomData<T>,
+}
+
+// MemoryCollection<T> implements Collection<T> traits
+// over serialized documents indexed by id
+impl<T> Collection<T> for MemoryCollection<T> {
+  fn find_one(&self, id: String) -> Option<T>
+  where T: for<'de> Deserialize<'de> + std::fmt::Debug {
+    let docs = self.docs.lock().unwrap();
+    docs.get(&id).and_then(|doc| serde_json::from_value(doc.to_owned()).ok())
+  }
+
+  fn get(&self, id: String) -> Result<Option<T>, String>
+  where T: for<'de> Deserialize<'de> {
+    let docs = self.docs.lock().unwrap();
+    docs.get(&id).map(|doc| serde_json::from_value(doc.to_owned()).map_err(|err| err.to_string())).transpose()
+  }
+
+  fn save(&self, id: String, entity: &T) -> Result<(), String>
+  where T: Serialize {
+    let doc = serde_json::to_value(entity).map_err(|err| err.to_string())?;
+    self.docs.lock().unwrap().insert(id, doc);
+    Ok(())
+  }
+
+  fn insert(&self, entity: &T) -> Result<(), String>
+  where T: Serialize {
+    let doc = serde_json::to_value(entity).map_err(|err| err.to_string())?;
+    let mut docs = self.docs.lock().unwrap();
+    // documents without id get a generated one, as MongoDB's _id
+    let mut n = docs.len();
+    while docs.contains_key(&format!("_{}", n)) {
+      n += 1;
+    }
+    docs.insert(format!("_{}", n), doc);
+    Ok(())
+  }
+
+  fn find_all(&self) -> Result<Vec<T>, String>
+  where T: for<'de> Deserialize<'de> {
+    let docs = self.docs.lock().unwrap();
+    Ok(docs.values().filter_map(|doc| serde_json::from_value(doc.to_owned()).ok()).collect())
+  }
+
+  fn find_range(&self, field: &str, from: i64, to: i64) -> Box<dyn Iterator<Item = Result<T, String>> + '_>
+  where T: for<'de> Deserialize<'de> + 'static {
+    let docs = self.docs.lock().unwrap();
+    let mut found: Vec<(i64, T)> = docs
+      .values()
+      .filter_map(|doc| {
+        let at = doc.get(field)?.as_i64()?;
+        if at < from || at >= to {
+          return None;
+        }
+        serde_json::from_value(doc.to_owned()).ok().map(|t| (at, t))
+      })
+      .collect();
+
+    found.sort_by_key(|(at, _)| *at);
+    Box::new(found.into_iter().map(|(_, t)| Ok(t)))
+  }
+
+  fn delete(&self, id: String) -> Result<(), String> {
+    match self.docs.lock().unwrap().remove(&id) {
+      Some(_) => Ok(()),
+      None => Err(format!("no document with id {}", id)),
+    }
+  }
+
+  fn save_if(&self, id: String, field: &str, expected: Option<i64>, entity: &T) -> Result<bool, String>
+  where T: Serialize {
+    let doc = serde_json::to_value(entity).map_err(|err| err.to_string())?;
+    let mut docs = self.docs.lock().unwrap();
+    let current = docs.get(&id).map(|d| d.get(field).and_then(|v| v.as_i64()));
+    let matches = match (current, expected) {
+      (None, None) => true,
+      (Some(c), Some(e)) => c == Some(e),
+      _ => false,
+    };
+    if matches {
+      docs.insert(id, doc);
+    }
+    Ok(matches)
+  }
+
+  fn update(&self, id: String, update: &Update) -> Result<(), String> {
+    let mut docs = self.docs.lock().unwrap();
+    // changes are applied to a copy, stored only once all applied
+    let mut doc = docs.get(&id).cloned().unwrap_or_else(|| serde_json::json!({ "id": id }));
+    for (path, value) in &update.set {
+      *value_at(&mut doc, path)? = value.to_owned();
+    }
+    for (path, values) in &update.add_to_set {
+      let field = value_at(&mut doc, path)?;
+      if field.is_null() {
+        *field = Value::Array(vec![]);
+      }
+      let array = field.as_array_mut().ok_or(format!("{} is not an array", path))?;
+      for value in values {
+        if !array.contains(value) {
+          array.push(value.to_owned());
+        }
+      }
+    }
+    for (path, values) in &update.pull {
+      let pointer: String = path.split('.').map(|key| format!("/{}", key.replace('~', "~0").replace('/', "~1"))).collect();
+      if let Some(array) = doc.pointer_mut(&pointer).and_then(|v| v.as_array_mut()) {
+        array.retain(|v| !values.contains(v));
+      }
+    }
+    docs.insert(id, doc);
+    Ok(())
+  }
+}
+
+// value_at gives the value at the dotted path of doc,
+// creating the objects leading to it as MongoDB does
+fn value_at<'a>(doc: &'a mut Value, path: &str) -> Result<&'a mut Value, String> {
+  let mut value = doc;
+  for key in path.split('.') {
+    if value.is_null() {
+      *value = Value::Object(Default::default());
+    }
+    value = value
+      .as_object_mut()
+      .ok_or(format!("{} crosses a non object field", path))?
+      .entry(key)
+      .or_insert(Value::Null);
+  }
+  Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+  use serde::{Serialize, Deserialize};
+  use serde_json::json;
+  use super::{Collection, MemoryDB, Update};
+
+  #[derive(Serialize, Deserialize, Debug, PartialEq)]
+  struct Entity {
+    id: String,
+    at: i64,
+  }
+
+  fn gen_entity(id: &str, at: i64) -> Entity {
+    Entity { id: id.into(), at }
+  }
+
+  #[test]
+  fn i_should_save_and_find_in_memory() {
+    let db = MemoryDB::new();
+    let coll = db.new_collection::<Entity>("entities");
+    coll.save("a".into(), &gen_entity("a", 1)).unwrap();
+    coll.save("a".into(), &gen_entity("a", 2)).unwrap();
+
+    assert_eq!(coll.find_one("a".into()), Some(gen_entity("a", 2)));
+    assert_eq!(db.new_collection::<Entity>("entities").find_all().unwrap().len(), 1);
+    assert_eq!(db.new_collection::<Entity>("others").find_all().unwrap().len(), 0);
+    assert!(coll.delete("a".into()).is_ok());
+    assert!(coll.delete("a".into()).is_err());
+  }
+
+  #[test]
+  fn i_should_find_range_in_memory() {
+    let coll = MemoryDB::new().new_collection::<Entity>("entities");
+    for at in &[30, 10, 20, 40] {
+      coll.insert(&gen_entity("x", *at)).unwrap();
+    }
+
+    let found: Vec<i64> = coll.find_range("at", 10, 40).map(|e| e.unwrap().at).collect();
+    assert_eq!(found, vec![10, 20, 30]);
+  }
+
+  #[test]
+  fn i_should_save_if_in_memory() {
+    let coll = MemoryDB::new().new_collection::<Entity>("entities");
+    assert_eq!(coll.save_if("a".into(), "at", None, &gen_entity("a", 1)), Ok(true));
+    assert_eq!(coll.save_if("a".into(), "at", None, &gen_entity("a", 2)), Ok(false));
+    assert_eq!(coll.save_if("a".into(), "at", Some(2), &gen_entity("a", 3)), Ok(false));
+    assert_eq!(coll.save_if("a".into(), "at", Some(1), &gen_entity("a", 3)), Ok(true));
+    assert_eq!(coll.find_one("a".into()), Some(gen_entity("a", 3)));
+  }
+
+  #[test]
+  fn i_should_update_fields_in_memory() {
+    let coll = MemoryDB::new().new_collection::<Entity>("entities");
+    let update = Update {
+      set: vec![("at".into(), json!(1)), ("tags.a.x".into(), json!(2))],
+      add_to_set: vec![("seen".into(), vec![json!("a"), json!("b")])],
+      pull: vec![],
+    };
+    coll.update("a".into(), &update).unwrap();
+    coll.update("a".into(), &Update { set: vec![("tags.a.y".into(), json!(3))], add_to_set: vec![("seen".into(), vec![json!("a")])], pull: vec![] }).unwrap();
+    coll.update("a".into(), &Update { pull: vec![("seen".into(), vec![json!("b")]), ("none.x".into(), vec![json!("b")])], ..Update::default() }).unwrap();
+
+    let doc = coll.docs.lock().unwrap()["a"].to_owned();
+    assert_eq!(doc, json!({"id": "a", "at": 1, "tags": {"a": {"x": 2, "y": 3}}, "seen": ["a"]}));
+    assert_eq!(coll.find_one("a".into()), Some(gen_entity("a", 1)));
+    assert!(coll.update("a".into(), &Update { set: vec![("at.x".into(), json!(1))], ..Update::default() }).is_err());
+  }
+}
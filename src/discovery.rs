@@ -0,0 +1,159 @@
+use std::collections::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+
+use crate::gecko;
+use crate::provider::Provider;
+
+// Discovery defines the rules resolving a provider's coins
+// from its whole coins list
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Discovery {
+    #[serde(default)]
+    pub enabled: bool,
+    // allow, when not empty, restricts discovered coins to these ids
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    // top_n keeps the n biggest coins by market cap
+    pub top_n: Option<usize>,
+    // symbol_pattern keeps symbols matching a glob pattern (* and ?)
+    pub symbol_pattern: Option<String>,
+}
+
+// ListedCoin is an entry of a provider's coins list
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct ListedCoin {
+    pub id: String,
+    pub symbol: String,
+    #[serde(default)]
+    pub name: String,
+}
+
+// glob_match tells if s matches pattern, where * matches any
+// sequence of characters and ? any single character
+pub fn glob_match(pattern: &str, s: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut pi, mut si) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while si < s.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == s[si]) {
+            pi += 1;
+            si += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            backtrack = Some((pi, si));
+            pi += 1;
+        } else if let Some((bpi, bsi)) = backtrack {
+            pi = bpi + 1;
+            si = bsi + 1;
+            backtrack = Some((bpi, bsi + 1));
+        } else {
+            return false;
+        }
+    }
+
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+impl Discovery {
+    // check tells what's wrong with the rules, if anything. The whole
+    // coins list holds thousands of ids, so discovered coins must be
+    // bounded by an allow list or top_n.
+    pub fn check(&self) -> Option<String> {
+        match self.top_n {
+            Some(0) => Some("discovery top_n must be positive".into()),
+            None if self.allow.is_empty() => Some("discovery needs top_n or an allow list, it would track every listed coin".into()),
+            _ => None,
+        }
+    }
+
+    // filter applies the rules on a coins list. top holds the ids of
+    // the top_n coins by market cap, if top_n is set.
+    // Returns a coin id => listed coin map.
+    pub fn filter(&self, listed: Vec<ListedCoin>, top: Option<&HashSet<String>>) -> HashMap<String, ListedCoin> {
+        listed
+            .into_iter()
+            .filter(|c| self.allow.is_empty() || self.allow.contains(&c.id))
+            .filter(|c| !self.deny.contains(&c.id))
+            .filter(|c| match &self.symbol_pattern {
+                Some(pattern) => glob_match(pattern, &c.symbol),
+                None => true,
+            })
+            .filter(|c| match top {
+                Some(t) => t.contains(&c.id),
+                None => true,
+            })
+            .map(|c| (c.id.to_owned(), c))
+            .collect()
+    }
+}
+
+// discover resolves the coins of a provider from its coins list
+// and its discovery rules. Returns a coin id => listed coin map.
+pub fn discover(provider: &Provider, discovery: &Discovery) -> Result<HashMap<String, ListedCoin>, String> {
+    let listed = gecko::coins_list(provider)?;
+    let top = match discovery.top_n {
+        Some(n) => Some(gecko::top_market_caps(provider, n)?),
+        None => None,
+    };
+
+    Ok(discovery.filter(listed, top.as_ref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use super::{glob_match, Discovery, ListedCoin};
+
+    fn listed(coins: Vec<(&str, &str)>) -> Vec<ListedCoin> {
+        coins
+            .into_iter()
+            .map(|(id, symbol)| ListedCoin { id: id.into(), symbol: symbol.into(), name: String::new() })
+            .collect()
+    }
+
+    #[test]
+    fn i_should_match_globs() {
+        assert!(glob_match("*", "btc"));
+        assert!(glob_match("b?c", "btc"));
+        assert!(glob_match("*coin", "dogecoin"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("b?c", "bc"));
+        assert!(!glob_match("*coin", "coins"));
+    }
+
+    #[test]
+    fn i_should_require_bounded_rules() {
+        let mut discovery = Discovery { enabled: true, deny: vec!["tether".into()], ..Discovery::default() };
+        assert!(discovery.check().is_some());
+        discovery.top_n = Some(0);
+        assert!(discovery.check().is_some());
+        discovery.top_n = Some(100);
+        assert!(discovery.check().is_none());
+        assert!(Discovery { allow: vec!["bitcoin".into()], ..Discovery::default() }.check().is_none());
+    }
+
+    #[test]
+    fn i_should_filter_with_rules() {
+        let coins = listed(vec![("bitcoin", "btc"), ("tether", "usdt"), ("dogecoin", "doge"), ("wrapped-bitcoin", "wbtc")]);
+        let discovery = Discovery {
+            enabled: true,
+            deny: vec!["tether".into()],
+            symbol_pattern: Some("*btc".into()),
+            ..Discovery::default()
+        };
+
+        let found = discovery.filter(coins.clone(), None);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found.get("bitcoin").unwrap().symbol, "btc");
+
+        let top: HashSet<String> = vec!["bitcoin".to_string(), "tether".to_string()].into_iter().collect();
+        let found = discovery.filter(coins.clone(), Some(&top));
+        assert_eq!(found.len(), 1);
+
+        let allow_only = Discovery { allow: vec!["dogecoin".into()], ..Discovery::default() };
+        assert_eq!(allow_only.filter(coins, None).len(), 1);
+    }
+}
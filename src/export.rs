@@ -0,0 +1,399 @@
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Arc;
+use chrono::{NaiveDate, TimeZone, Utc};
+use parquet::basic::Compression;
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, FloatType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use serde::{Deserialize, Serialize};
+
+use crate::coin::{self, Stack};
+
+// ROW_GROUP_LEN is the number of rows buffered before a
+// parquet row group is written, bounding memory usage
+const ROW_GROUP_LEN: usize = 50_000;
+
+const PARQUET_SCHEMA: &str = "
+message price {
+    REQUIRED INT64 timestamp (TIMESTAMP(MILLIS,true));
+    REQUIRED BYTE_ARRAY coin_id (UTF8);
+    REQUIRED BYTE_ARRAY symbol (UTF8);
+    REQUIRED BYTE_ARRAY currency (UTF8);
+    REQUIRED FLOAT price;
+    REQUIRED BYTE_ARRAY provider (UTF8);
+    REQUIRED BOOLEAN derived;
+}";
+
+// Row is a single price of a coin in a currency at a given time,
+// derived being set on prices converted from another currency
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct Row {
+    pub timestamp: i64,
+    pub coin_id: String,
+    pub symbol: String,
+    pub currency: String,
+    pub price: f32,
+    #[serde(default = "default_provider")]
+    pub provider: String,
+    #[serde(default)]
+    pub derived: bool,
+}
+
+// default_provider is the provider of rows exported without one
+fn default_provider() -> String {
+    coin::DEFAULT_PROVIDER.to_string()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Csv,
+    JsonLines,
+    Parquet,
+    // Dump keeps whole stacks as JSON lines, to be imported back as is
+    Dump,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "jsonl" => Ok(Format::JsonLines),
+            "parquet" => Ok(Format::Parquet),
+            "dump" => Ok(Format::Dump),
+            _ => Err(format!("unknown format {}, expected csv, jsonl, parquet or dump", s)),
+        }
+    }
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv",
+            Format::JsonLines | Format::Dump => "application/x-ndjson",
+            Format::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+}
+
+// day_start gives the timestamp in ms of a date at 00:00 UTC
+pub fn day_start(date: NaiveDate) -> i64 {
+    Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()).timestamp_millis()
+}
+
+// date_range gives the [from, to) timestamps in ms covering dates
+// from and to, both included. Defaults to the whole history.
+pub fn date_range(from: Option<NaiveDate>, to: Option<NaiveDate>) -> (i64, i64) {
+    let from = from.map(day_start).unwrap_or(0);
+    let to = match to.and_then(|d| d.succ_opt()) {
+        Some(d) => day_start(d),
+        None => Utc::now().timestamp_millis() + 1,
+    };
+    (from, to)
+}
+
+// flatten turns a stack in rows sorted by coin and currency,
+// keeping only coins if any is given
+pub fn flatten(stack: &Stack, coins: &[String]) -> Vec<Row> {
+    let mut rows: Vec<Row> = stack
+        .coins
+        .values()
+        .filter(|coin| coins.is_empty() || coins.contains(&coin.id))
+        .flat_map(|coin| coin.prices.iter().map(move |(currency, price)| Row {
+            timestamp: stack.created_at,
+            coin_id: coin.id.to_owned(),
+            symbol: coin.symbol.to_owned(),
+            currency: currency.to_owned(),
+            price: *price,
+            provider: coin::tick_provider(stack).to_string(),
+            derived: coin.derived.contains(currency),
+        }))
+        .collect();
+
+    rows.sort_by(|a, b| (&a.coin_id, &a.currency).cmp(&(&b.coin_id, &b.currency)));
+    rows
+}
+
+// RowWriter writes rows to an output in a given format
+pub trait RowWriter {
+    fn write_row(&mut self, row: Row) -> Result<(), String>;
+    fn finish(self: Box<Self>) -> Result<(), String>;
+}
+
+struct CsvWriter<W: Write> {
+    out: W,
+}
+
+// csv_field quotes a field if it contains a separator, a quote or a newline
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        return format!("\"{}\"", field.replace('"', "\"\""));
+    }
+    field.to_string()
+}
+
+impl<W: Write> RowWriter for CsvWriter<W> {
+    fn write_row(&mut self, row: Row) -> Result<(), String> {
+        writeln!(
+            self.out,
+            "{},{},{},{},{},{},{}",
+            row.timestamp,
+            csv_field(&row.coin_id),
+            csv_field(&row.symbol),
+            csv_field(&row.currency),
+            row.price,
+            csv_field(&row.provider),
+            row.derived,
+        ).map_err(|err| err.to_string())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), String> {
+        self.out.flush().map_err(|err| err.to_string())
+    }
+}
+
+struct JsonLinesWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> RowWriter for JsonLinesWriter<W> {
+    fn write_row(&mut self, row: Row) -> Result<(), String> {
+        let line = serde_json::to_string(&row).map_err(|err| err.to_string())?;
+        writeln!(self.out, "{}", line).map_err(|err| err.to_string())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), String> {
+        self.out.flush().map_err(|err| err.to_string())
+    }
+}
+
+struct ParquetWriter<W: Write + Send> {
+    writer: SerializedFileWriter<W>,
+    rows: Vec<Row>,
+}
+
+impl<W: Write + Send> ParquetWriter<W> {
+    fn new(out: W) -> Result<Self, String> {
+        let schema = parse_message_type(PARQUET_SCHEMA).map_err(|err| err.to_string())?;
+        let props = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+        let writer = SerializedFileWriter::new(out, Arc::new(schema), Arc::new(props)).map_err(|err| err.to_string())?;
+
+        Ok(Self { writer, rows: Vec::with_capacity(ROW_GROUP_LEN) })
+    }
+
+    // flush_row_group writes buffered rows as a new row group
+    fn flush_row_group(&mut self) -> Result<(), parquet::errors::ParquetError> {
+        let Self { writer, rows } = self;
+        if rows.is_empty() {
+            return Ok(());
+        }
+        let mut row_group = writer.next_row_group()?;
+        let mut idx = 0;
+
+        while let Some(mut column) = row_group.next_column()? {
+            let strings = |f: fn(&Row) -> &String| -> Vec<ByteArray> {
+                rows.iter().map(|r| ByteArray::from(f(r).as_str())).collect()
+            };
+            match idx {
+                0 => {
+                    let values: Vec<i64> = rows.iter().map(|r| r.timestamp).collect();
+                    column.typed::<Int64Type>().write_batch(&values, None, None)?;
+                },
+                1 => { column.typed::<ByteArrayType>().write_batch(&strings(|r| &r.coin_id), None, None)?; },
+                2 => { column.typed::<ByteArrayType>().write_batch(&strings(|r| &r.symbol), None, None)?; },
+                3 => { column.typed::<ByteArrayType>().write_batch(&strings(|r| &r.currency), None, None)?; },
+                4 => {
+                    let values: Vec<f32> = rows.iter().map(|r| r.price).collect();
+                    column.typed::<FloatType>().write_batch(&values, None, None)?;
+                },
+                5 => { column.typed::<ByteArrayType>().write_batch(&strings(|r| &r.provider), None, None)?; },
+                _ => {
+                    let values: Vec<bool> = rows.iter().map(|r| r.derived).collect();
+                    column.typed::<BoolType>().write_batch(&values, None, None)?;
+                },
+            };
+            column.close()?;
+            idx += 1;
+        }
+
+        row_group.close()?;
+        rows.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> RowWriter for ParquetWriter<W> {
+    fn write_row(&mut self, row: Row) -> Result<(), String> {
+        self.rows.push(row);
+        if self.rows.len() >= ROW_GROUP_LEN {
+            self.flush_row_group().map_err(|err| err.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), String> {
+        self.flush_row_group().map_err(|err| err.to_string())?;
+        self.writer.close().map(|_| ()).map_err(|err| err.to_string())
+    }
+}
+
+// new_writer gives a RowWriter of format on out. CSV gets a header line.
+fn new_writer<'a, W: Write + Send + 'a>(format: Format, mut out: W) -> Result<Box<dyn RowWriter + 'a>, String> {
+    match format {
+        Format::Dump => Err("dump is not a row format".into()),
+        Format::Csv => {
+            writeln!(out, "timestamp,coin_id,symbol,currency,price,provider,derived").map_err(|err| err.to_string())?;
+            Ok(Box::new(CsvWriter { out }))
+        },
+        Format::JsonLines => Ok(Box::new(JsonLinesWriter { out })),
+        Format::Parquet => Ok(Box::new(ParquetWriter::new(out)?)),
+    }
+}
+
+// dump writes stacks as JSON lines and returns the number of stacks written
+fn dump<W: Write>(stacks: impl Iterator<Item = Result<Stack, String>>, coins: &[String], mut out: W) -> Result<usize, String> {
+    let mut count = 0;
+
+    for stack in stacks {
+        let mut stack = stack?;
+        if !coins.is_empty() {
+            stack.coins.retain(|id, _| coins.contains(id));
+            if stack.coins.is_empty() {
+                continue;
+            }
+        }
+        let line = serde_json::to_string(&stack).map_err(|err| err.to_string())?;
+        writeln!(out, "{}", line).map_err(|err| err.to_string())?;
+        count += 1;
+    }
+
+    out.flush().map_err(|err| err.to_string())?;
+    Ok(count)
+}
+
+// export streams stacks as rows of format to out and returns
+// the number of rows written, or of stacks for a dump.
+// Fails on the first error of stacks, before writing anything
+// if the query itself fails.
+pub fn export<W: Write + Send>(
+    stacks: impl Iterator<Item = Result<Stack, String>>,
+    coins: &[String],
+    format: Format,
+    out: W,
+) -> Result<usize, String> {
+    let mut stacks = stacks.peekable();
+    if let Some(Err(err)) = stacks.peek() {
+        return Err(err.to_owned());
+    }
+    if format == Format::Dump {
+        return dump(stacks, coins, out);
+    }
+    let mut writer = new_writer(format, out)?;
+    let mut count = 0;
+
+    for stack in stacks {
+        for row in flatten(&stack?, coins) {
+            writer.write_row(row)?;
+            count += 1;
+        }
+    }
+
+    writer.finish()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::coin::{Coin, Stack};
+    use super::{export, flatten, Format};
+
+    fn gen_stack(created_at: i64) -> Stack {
+        let mut stack = Stack::new();
+        stack.created_at = created_at;
+        stack.id = format!("coingecko:{}", created_at);
+        for (id, symbol, usd) in &[("bitcoin", "btc", 42.5f32), ("ethereum", "eth", 4.2f32)] {
+            let mut prices = HashMap::new();
+            prices.insert("usd".to_string(), *usd);
+            prices.insert("eur".to_string(), *usd / 2.0);
+            stack.coins.insert(id.to_string(), Coin { id: id.to_string(), symbol: symbol.to_string(), prices, derived: vec!["eur".to_string()], last_updated_at: None });
+        }
+        stack
+    }
+
+    #[test]
+    fn i_should_flatten_stack_in_sorted_rows() {
+        let rows = flatten(&gen_stack(1000), &["bitcoin".to_string()]);
+
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].coin_id, "bitcoin");
+        assert_eq!(rows[0].currency, "eur");
+        assert_eq!(rows[1].currency, "usd");
+        assert_eq!(rows[1].price, 42.5);
+        assert_eq!(rows[1].timestamp, 1000);
+    }
+
+    #[test]
+    fn i_should_export_csv() {
+        let mut out = vec![];
+        let count = export(vec![gen_stack(1000), gen_stack(2000)].into_iter().map(Ok), &[], Format::Csv, &mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+
+        assert_eq!(count, 8);
+        assert_eq!(lines.len(), 9);
+        assert_eq!(lines[0], "timestamp,coin_id,symbol,currency,price,provider,derived");
+        assert_eq!(lines[1], "1000,bitcoin,btc,eur,21.25,coingecko,true");
+        assert_eq!(lines[2], "1000,bitcoin,btc,usd,42.5,coingecko,false");
+    }
+
+    #[test]
+    fn i_should_fail_on_read_errors() {
+        let stacks = vec![Ok(gen_stack(1000)), Err("cursor died".to_string())];
+        assert_eq!(export(stacks.clone().into_iter(), &[], Format::Csv, vec![]), Err("cursor died".to_string()));
+        assert_eq!(export(stacks.into_iter(), &[], Format::Dump, vec![]), Err("cursor died".to_string()));
+
+        let mut out = vec![];
+        let failed = vec![Err("query failed".to_string())];
+        assert_eq!(export(failed.into_iter(), &[], Format::Csv, &mut out), Err("query failed".to_string()));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn i_should_export_json_lines() {
+        let mut out = vec![];
+        export(vec![gen_stack(1000)].into_iter().map(Ok), &["ethereum".to_string()], Format::JsonLines, &mut out).unwrap();
+        let jsonl = String::from_utf8(out).unwrap();
+
+        assert_eq!(jsonl.lines().count(), 2);
+        assert_eq!(
+            jsonl.lines().next().unwrap(),
+            r#"{"timestamp":1000,"coin_id":"ethereum","symbol":"eth","currency":"eur","price":2.1,"provider":"coingecko","derived":true}"#
+        );
+    }
+
+    #[test]
+    fn i_should_export_parquet() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+
+        let path = std::env::temp_dir().join("coinrd-export-test.parquet");
+        let out = std::fs::File::create(&path).unwrap();
+        export(vec![gen_stack(1000), gen_stack(2000)].into_iter().map(Ok), &[], Format::Parquet, out).unwrap();
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 8);
+        assert_eq!(reader.metadata().file_metadata().schema_descr().num_columns(), 7);
+    }
+
+    #[test]
+    fn i_should_parse_formats() {
+        assert_eq!("csv".parse::<Format>(), Ok(Format::Csv));
+        assert_eq!("jsonl".parse::<Format>(), Ok(Format::JsonLines));
+        assert_eq!("parquet".parse::<Format>(), Ok(Format::Parquet));
+        assert_eq!("dump".parse::<Format>(), Ok(Format::Dump));
+        assert!("xls".parse::<Format>().is_err());
+    }
+}
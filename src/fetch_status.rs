@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+
+use crate::database::Collection;
+
+// NOT_RETURNED is the error of a tracked coin missing from a response
+pub const NOT_RETURNED: &str = "not returned by the provider";
+
+// UNTRACKED is the error of an id a response holds but no config tracks
+pub const UNTRACKED: &str = "returned by the provider but not tracked";
+
+// Outcome is what a fetch tells about coins beyond their prices
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Outcome {
+    // errors are the errors of the tracked coins missing from the fetch
+    pub errors: HashMap<String, String>,
+    // untracked are the ids a response held that the provider doesn't track
+    pub untracked: Vec<String>,
+}
+
+impl Outcome {
+    // failed is the outcome of a fetch that failed as a whole
+    pub fn failed(coins: &HashMap<String, String>, err: &str) -> Self {
+        Self {
+            errors: coins.keys().map(|id| (id.to_owned(), err.to_string())).collect(),
+            untracked: vec![],
+        }
+    }
+}
+
+// FetchStatus is the fetch history of a coin of a provider
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct FetchStatus {
+    pub id: String,
+    pub provider: String,
+    pub coin: String,
+    pub last_success: Option<i64>,
+    pub consecutive_misses: u32,
+    pub last_error: Option<String>,
+    // untracked is set on ids the provider returns without tracking them
+    #[serde(default)]
+    pub untracked: bool,
+    pub updated_at: i64,
+}
+
+impl FetchStatus {
+    fn new(provider: &str, coin: &str) -> Self {
+        Self {
+            id: format!("{}:{}", provider, coin),
+            provider: provider.to_string(),
+            coin: coin.to_string(),
+            last_success: None,
+            consecutive_misses: 0,
+            last_error: None,
+            untracked: false,
+            updated_at: 0,
+        }
+    }
+}
+
+// Tracker keeps the fetch status of a provider's coins between ticks
+pub struct Tracker {
+    provider: String,
+    alert_misses: u32,
+    statuses: HashMap<String, FetchStatus>,
+}
+
+impl Tracker {
+    // load resumes from the statuses of provider stored in coll
+    pub fn load(coll: &impl Collection<FetchStatus>, provider: &str, alert_misses: u32) -> Result<Self, String> {
+        let statuses = coll
+            .find_all()?
+            .into_iter()
+            .filter(|s| s.provider == provider)
+            .map(|s| (s.coin.to_owned(), s))
+            .collect();
+        Ok(Self { provider: provider.to_string(), alert_misses, statuses })
+    }
+
+    // record updates the status of every tracked coin, a coin with no error
+    // in outcome being fetched at at, and of the untracked ids of outcome.
+    // Misses are counted by tick: a retry recorded at the same at merges
+    // into the status of the tick, a success of the tick holding.
+    // Returns the coins reaching alert_misses consecutive misses.
+    pub fn record(&mut self, coins: &HashMap<String, String>, outcome: &Outcome, at: i64) -> Vec<&FetchStatus> {
+        let mut alerts = vec![];
+        let provider = &self.provider;
+
+        for id in coins.keys() {
+            let status = self.statuses.entry(id.to_owned()).or_insert_with(|| FetchStatus::new(provider, id));
+            let retried = status.updated_at == at && !status.untracked;
+            status.untracked = false;
+            status.updated_at = at;
+            match outcome.errors.get(id) {
+                Some(_) if retried && status.last_success == Some(at) => {},
+                Some(err) if retried => status.last_error = Some(err.to_owned()),
+                Some(err) => {
+                    status.consecutive_misses += 1;
+                    status.last_error = Some(err.to_owned());
+                    if status.consecutive_misses == self.alert_misses {
+                        alerts.push(id.to_owned());
+                    }
+                },
+                None => {
+                    status.last_success = Some(at);
+                    status.consecutive_misses = 0;
+                },
+            }
+        }
+        for id in &outcome.untracked {
+            let status = self.statuses.entry(id.to_owned()).or_insert_with(|| FetchStatus::new(provider, id));
+            status.untracked = true;
+            status.last_error = Some(UNTRACKED.to_string());
+            status.updated_at = at;
+        }
+
+        alerts.sort();
+        let statuses = &self.statuses;
+        alerts.iter().map(|id| &statuses[id]).collect()
+    }
+
+    // save upserts the statuses updated at at in coll.
+    // Every status is saved even if one fails, the first error is returned.
+    pub fn save(&self, coll: &impl Collection<FetchStatus>, at: i64) -> Result<(), String> {
+        let mut first_err = None;
+        for s in self.statuses.values().filter(|s| s.updated_at == at) {
+            if let Err(err) = coll.save(s.id.to_owned(), s) {
+                first_err.get_or_insert(err);
+            }
+        }
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    pub fn get(&self, coin: &str) -> Option<&FetchStatus> {
+        self.statuses.get(coin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::database::{Collection, MemoryDB};
+        use super::{FetchStatus, Outcome, Tracker, NOT_RETURNED};
+
+    fn coins_of(coins: Vec<&str>) -> HashMap<String, String> {
+        coins.into_iter().map(|id| (id.to_string(), id[..3].to_string())).collect()
+    }
+
+    #[test]
+    fn i_should_count_consecutive_misses() {
+        let coins = coins_of(vec!["bitcoin", "terra-luna"]);
+        let mut tracker = Tracker::load(&MemoryDB::new().new_collection::<FetchStatus>("fetch_status"), "coingecko", 2).unwrap();
+        let mut outcome = Outcome::default();
+        outcome.errors.insert("terra-luna".into(), NOT_RETURNED.into());
+
+        assert!(tracker.record(&coins, &outcome, 1000).is_empty());
+        let alerts = tracker.record(&coins, &outcome, 2000);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].coin, "terra-luna");
+        assert!(tracker.record(&coins, &outcome, 3000).is_empty());
+
+        let luna = tracker.get("terra-luna").unwrap();
+        assert_eq!((luna.consecutive_misses, luna.last_success), (3, None));
+        assert_eq!(luna.last_error.as_deref(), Some(NOT_RETURNED));
+        assert_eq!(tracker.get("bitcoin").unwrap().last_success, Some(3000));
+
+        tracker.record(&coins, &Outcome::default(), 4000);
+        let luna = tracker.get("terra-luna").unwrap();
+        assert_eq!((luna.consecutive_misses, luna.last_success), (0, Some(4000)));
+        assert_eq!(luna.last_error.as_deref(), Some(NOT_RETURNED));
+
+        let failed = Outcome::failed(&coins, "timeout");
+        tracker.record(&coins, &failed, 5000);
+        assert_eq!(tracker.get("bitcoin").unwrap().last_error.as_deref(), Some("timeout"));
+    }
+
+    #[test]
+    fn i_should_count_a_tick_once() {
+        let coins = coins_of(vec!["bitcoin", "terra-luna"]);
+        let mut tracker = Tracker::load(&MemoryDB::new().new_collection::<FetchStatus>("fetch_status"), "coingecko", 2).unwrap();
+        let failed = Outcome::failed(&coins, "timeout");
+
+        assert!(tracker.record(&coins, &failed, 1000).is_empty());
+        assert!(tracker.record(&coins, &Outcome::failed(&coins, "429"), 1000).is_empty());
+        let luna = tracker.get("terra-luna").unwrap();
+        assert_eq!((luna.consecutive_misses, luna.last_error.as_deref()), (1, Some("429")));
+
+        let mut outcome = Outcome::default();
+        outcome.errors.insert("terra-luna".into(), NOT_RETURNED.into());
+        tracker.record(&coins, &outcome, 2000);
+        tracker.record(&coins, &failed, 2000);
+        let bitcoin = tracker.get("bitcoin").unwrap();
+        assert_eq!((bitcoin.consecutive_misses, bitcoin.last_success), (0, Some(2000)));
+        assert_eq!(tracker.get("terra-luna").unwrap().consecutive_misses, 2);
+    }
+
+    #[test]
+    fn i_should_store_statuses() {
+        let db = MemoryDB::new();
+        let coll = db.new_collection::<FetchStatus>("fetch_status");
+        let coins = coins_of(vec!["bitcoin"]);
+        let mut outcome = Outcome::default();
+        outcome.untracked.push("dogecoin".into());
+
+        let mut tracker = Tracker::load(&coll, "coingecko", 5).unwrap();
+        tracker.record(&coins, &outcome, 1000);
+        tracker.save(&coll, 1000).unwrap();
+        tracker.record(&coins, &Outcome::default(), 2000);
+        tracker.save(&coll, 2000).unwrap();
+
+        let doge = coll.find_one("coingecko:dogecoin".into()).unwrap();
+        assert!(doge.untracked);
+        assert_eq!(doge.updated_at, 1000);
+
+        let resumed = Tracker::load(&coll, "coingecko", 5).unwrap();
+        assert_eq!(resumed.get("bitcoin").unwrap().last_success, Some(2000));
+        assert!(Tracker::load(&coll, "other", 5).unwrap().get("bitcoin").is_none());
+    }
+}
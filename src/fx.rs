@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+
+use crate::coin::Stack;
+use crate::database::Collection;
+
+// RATES_MAX_AGE_MS is how old stored rates may be to convert a price
+const RATES_MAX_AGE_MS: i64 = 86_400_000;
+
+// Rates are the values of currencies in a common unit,
+// e.g. BTC for CoinGecko's exchange rates
+pub type Rates = HashMap<String, f64>;
+
+#[derive(Deserialize)]
+struct Rate {
+    value: f64,
+}
+
+// ExchangeRatesResponse is the body of CoinGecko's /exchange_rates
+#[derive(Deserialize)]
+pub struct ExchangeRatesResponse {
+    rates: HashMap<String, Rate>,
+}
+
+// format_exchange_rates keeps the usable rates of a response
+pub fn format_exchange_rates(response: ExchangeRatesResponse) -> Rates {
+    response
+        .rates
+        .into_iter()
+        .filter(|(_, r)| r.value.is_finite() && r.value > 0.0)
+        .map(|(currency, r)| (currency, r.value))
+        .collect()
+}
+
+// convert gives price in from currency in to currency
+pub fn convert(price: f32, from: &str, to: &str, rates: &Rates) -> Option<f32> {
+    let from_rate = rates.get(from)?;
+    let to_rate = rates.get(to)?;
+    Some((price as f64 * to_rate / from_rate) as f32)
+}
+
+// derive fills the prices in currencies missing from the coins
+// of stack from their price in base, flagging them as derived.
+// Quoted prices are left as is.
+pub fn derive(stack: &mut Stack, base: &str, currencies: &[String], rates: &Rates) {
+    for coin in stack.coins.values_mut() {
+        let price = match coin.prices.get(base) {
+            Some(p) => *p,
+            None => continue,
+        };
+        for currency in currencies {
+            if coin.prices.contains_key(currency) {
+                continue;
+            }
+            if let Some(converted) = convert(price, base, currency, rates) {
+                coin.prices.insert(currency.to_owned(), converted);
+                coin.derived.push(currency.to_owned());
+            }
+        }
+    }
+}
+
+// FxProvider is the definition of a service giving exchange rates,
+// deserialized from the fx_providers of the providers file
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct FxProvider {
+    name: String,
+    base_route: String,
+    routes: HashMap<String, String>,
+    // base is the currency rates are stored against
+    base: String,
+    currencies: Vec<String>,
+    // interval_secs spaces fetches, 0 fetching rates every tick
+    #[serde(default)]
+    interval_secs: u64,
+}
+
+impl FxProvider {
+    pub fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn get_base_route(&self) -> &String {
+        &self.base_route
+    }
+
+    pub fn get_routes(&self) -> &HashMap<String, String> {
+        &self.routes
+    }
+
+    pub fn get_uri(&self, route: &str) -> Option<String> {
+        self.routes.get(route).map(|r| self.base_route.to_owned() + r)
+    }
+
+    pub fn get_base(&self) -> &String {
+        &self.base
+    }
+
+    pub fn get_currencies(&self) -> &Vec<String> {
+        &self.currencies
+    }
+
+    pub fn get_interval_secs(&self) -> u64 {
+        self.interval_secs
+    }
+}
+
+// FxRates are the rates of a provider's currencies against its base
+// at created_at, the base being worth 1
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct FxRates {
+    pub id: String,
+    pub provider: String,
+    pub base: String,
+    pub rates: Rates,
+    pub created_at: i64,
+}
+
+impl FxRates {
+    // new keeps the provider's currencies of rates, rebased on its base.
+    // None if rates don't hold the base.
+    pub fn new(provider: &FxProvider, rates: &Rates, at: i64) -> Option<Self> {
+        let base = provider.get_base();
+        let base_rate = rates.get(base)?;
+        let mut rebased: Rates = provider
+            .get_currencies()
+            .iter()
+            .filter_map(|c| rates.get(c).map(|r| (c.to_owned(), r / base_rate)))
+            .collect();
+        rebased.insert(base.to_owned(), 1.0);
+
+        Some(Self {
+            id: format!("{}:{}", provider.get_name(), at),
+            provider: provider.get_name().to_owned(),
+            base: base.to_owned(),
+            rates: rebased,
+            created_at: at,
+        })
+    }
+
+    // convert gives price in from currency in to currency
+    pub fn convert(&self, price: f32, from: &str, to: &str) -> Option<f32> {
+        convert(price, from, to, &self.rates)
+    }
+}
+
+// save_rates upserts rates in coll, keyed by provider and time,
+// so every fetch adds to the history
+pub fn save_rates(coll: &impl Collection<FxRates>, rates: &FxRates) -> Result<(), String> {
+    coll.save(rates.id.to_owned(), rates)
+}
+
+// derive_at derives the currencies of stack missing from its prices in
+// base with the last rates of provider stored by the stack's time.
+// Returns whether such rates were found.
+pub fn derive_at(coll: &impl Collection<FxRates>, provider: &str, stack: &mut Stack, base: &str, currencies: &[String]) -> Result<bool, String> {
+    match rates_at(coll, provider, stack.created_at)? {
+        Some(rates) => {
+            derive(stack, base, currencies, &rates.rates);
+            Ok(true)
+        },
+        None => Ok(false),
+    }
+}
+
+// rates_at gives the last rates of provider stored at or before at,
+// if no older than a day
+pub fn rates_at(coll: &impl Collection<FxRates>, provider: &str, at: i64) -> Result<Option<FxRates>, String> {
+    let mut last = None;
+    for rates in coll.find_range("created_at", at - RATES_MAX_AGE_MS, at + 1) {
+        let rates = rates?;
+        if rates.provider == provider {
+            last = Some(rates);
+        }
+    }
+    Ok(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs;
+    use crate::coin::{Coin, Stack};
+    use crate::database::MemoryDB;
+    use crate::provider::parse_fx_toml;
+    use super::{convert, derive, derive_at, format_exchange_rates, rates_at, save_rates, ExchangeRatesResponse, FxRates, Rates};
+
+    fn fixture() -> Rates {
+        let content = fs::read_to_string("./test/exchange-rates.json").unwrap();
+        format_exchange_rates(serde_json::from_str::<ExchangeRatesResponse>(&content).unwrap())
+    }
+
+    #[test]
+    fn i_should_convert_through_rates() {
+        let rates = fixture();
+        assert_eq!(convert(40000.0, "usd", "btc", &rates), Some(1.0));
+        assert_eq!(convert(100.0, "usd", "eur", &rates), Some(80.0));
+        assert_eq!(convert(100.0, "usd", "xyz", &rates), None);
+    }
+
+    #[test]
+    fn i_should_derive_missing_currencies() {
+        let rates = fixture();
+        let mut stack = Stack::new();
+        stack.coins.insert("ethereum".into(), Coin {
+            id: "ethereum".into(),
+            symbol: "eth".into(),
+            prices: vec![("usd".to_string(), 2500.0), ("eur".to_string(), 2001.0)].into_iter().collect(),
+            derived: vec![],
+            last_updated_at: None,
+        });
+        stack.coins.insert("nope".into(), Coin { id: "nope".into(), symbol: "n".into(), prices: HashMap::new(), derived: vec![], last_updated_at: None });
+
+        let currencies: Vec<String> = vec!["usd", "eur", "jpy", "btc", "xyz"].into_iter().map(|c| c.to_string()).collect();
+        derive(&mut stack, "usd", &currencies, &rates);
+
+        let eth = &stack.coins["ethereum"];
+        assert_eq!(eth.prices["eur"], 2001.0);
+        assert_eq!(eth.prices["jpy"], 275000.0);
+        assert_eq!(eth.prices["btc"], 0.0625);
+        assert_eq!(eth.derived, vec!["jpy".to_string(), "btc".to_string()]);
+        assert!(stack.coins["nope"].prices.is_empty());
+    }
+
+    #[test]
+    fn i_should_store_rates_history() {
+        let content = fs::read_to_string("./test/providers-test-3.toml").unwrap();
+        let fx_providers = parse_fx_toml(&content).unwrap();
+        let ecb = &fx_providers["ecb"];
+        assert_eq!(ecb.get_interval_secs(), 3600);
+
+        let coll = MemoryDB::new().new_collection::<FxRates>("fx_rates");
+        let first = FxRates::new(ecb, &fixture(), 1000).unwrap();
+        assert_eq!(first.rates["eur"], 0.8);
+        assert_eq!(first.rates["usd"], 1.0);
+        assert_eq!(first.rates.get("eth"), None);
+        assert_eq!(first.convert(100.0, "eur", "gbp"), Some(87.5));
+        save_rates(&coll, &first).unwrap();
+
+        let mut rates = fixture();
+        rates.insert("eur".into(), 36000.0);
+        save_rates(&coll, &FxRates::new(ecb, &rates, 2000).unwrap()).unwrap();
+
+        assert_eq!(rates_at(&coll, "ecb", 1500), Ok(Some(first)));
+        assert_eq!(rates_at(&coll, "ecb", 2000).unwrap().unwrap().rates["eur"], 0.9);
+        assert_eq!(rates_at(&coll, "ecb", 500), Ok(None));
+        assert_eq!(rates_at(&coll, "other", 2000), Ok(None));
+
+        let mut stack = Stack::new();
+        stack.created_at = 1500;
+        stack.coins.insert("bitcoin".into(), Coin {
+            id: "bitcoin".into(),
+            symbol: "btc".into(),
+            prices: vec![("usd".to_string(), 100.0)].into_iter().collect(),
+            derived: vec![],
+            last_updated_at: None,
+        });
+        let currencies = vec!["usd".to_string(), "gbp".to_string()];
+        assert_eq!(derive_at(&coll, "other", &mut stack, "usd", &currencies), Ok(false));
+        assert_eq!(derive_at(&coll, "ecb", &mut stack, "usd", &currencies), Ok(true));
+        assert_eq!(stack.coins["bitcoin"].prices["gbp"], 70.0);
+        assert_eq!(stack.coins["bitcoin"].derived, vec!["gbp".to_string()]);
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use serde::Serialize;
+
+use crate::coin::Stack;
+use crate::fetch_status::FetchStatus;
+
+// Gap is a run of missing ticks of a coin, from and to
+// being the first and last missing ticks
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Gap {
+    pub coin: String,
+    pub from: i64,
+    pub to: i64,
+    pub missing: i64,
+}
+
+// missing_between counts the ticks of interval_ms strictly between
+// the ticks a and b
+fn missing_between(a: i64, b: i64, interval_ms: i64) -> i64 {
+    if b <= a {
+        return 0;
+    }
+    (b - a - 1) / interval_ms
+}
+
+fn gap(coin: &str, last: i64, next: i64, interval_ms: i64) -> Gap {
+    let missing = missing_between(last, next, interval_ms);
+    Gap {
+        coin: coin.to_string(),
+        from: last + interval_ms,
+        to: last + missing * interval_ms,
+        missing,
+    }
+}
+
+// detect gives the gaps of at least min_ticks ticks of each coin of
+// stacks, sorted by time, over the ticks of [from, to). A coin is seen
+// at a tick with a price or listed unchanged. Only coins are checked
+// if any is given, those never seen being missing all along.
+// Fails on the first error of stacks.
+pub fn detect(
+    stacks: impl Iterator<Item = Result<Stack, String>>,
+    coins: &[String],
+    interval_ms: i64,
+    from: i64,
+    to: i64,
+    min_ticks: i64,
+) -> Result<Vec<Gap>, String> {
+    let interval_ms = interval_ms.max(1);
+    // ticks before the first one of the range count as seen
+    let before = from + (interval_ms - from.rem_euclid(interval_ms)) % interval_ms - interval_ms;
+    let mut last_seen: HashMap<String, i64> = coins.iter().map(|c| (c.to_owned(), before)).collect();
+    let mut gaps = vec![];
+
+    for stack in stacks {
+        let stack = stack?;
+        for id in stack.coins.keys().chain(&stack.unchanged) {
+            if !coins.is_empty() && !coins.contains(id) {
+                continue;
+            }
+            let last = last_seen.insert(id.to_owned(), stack.created_at).unwrap_or(before);
+            if missing_between(last, stack.created_at, interval_ms) >= min_ticks {
+                gaps.push(gap(id, last, stack.created_at, interval_ms));
+            }
+        }
+    }
+    for (id, last) in last_seen {
+        if missing_between(last, to, interval_ms) >= min_ticks {
+            gaps.push(gap(&id, last, to, interval_ms));
+        }
+    }
+
+    gaps.sort_by(|a, b| a.coin.cmp(&b.coin).then(a.from.cmp(&b.from)));
+    Ok(gaps)
+}
+
+// missing_since_fetch counts the ticks a coin missed since it was last
+// fetched, whether its price changed or not, the tick in progress at now
+// aside. A coin never fetched missed its consecutive misses.
+pub fn missing_since_fetch(status: &FetchStatus, interval_ms: i64, now: i64) -> i64 {
+    match status.last_success {
+        Some(last) => missing_between(last, now, interval_ms.max(1)),
+        None => status.consecutive_misses as i64,
+    }
+}
+
+// render_metrics writes the gap gauges in the Prometheus text format
+pub fn render_metrics(missing: &[(String, i64)], min_ticks: i64) -> String {
+    let mut out = String::new();
+    let gapped = missing.iter().filter(|(_, m)| *m >= min_ticks).count();
+
+    out.push_str("# HELP coinrd_price_gap_ticks Ticks missed since a coin was last fetched\n");
+    out.push_str("# TYPE coinrd_price_gap_ticks gauge\n");
+    for (coin, m) in missing {
+        let _ = writeln!(out, "coinrd_price_gap_ticks{{coin=\"{}\"}} {}", coin.replace('\\', "\\\\").replace('"', "\\\""), m);
+    }
+    out.push_str("# HELP coinrd_price_gaps Coins missing at least gaps.min_ticks ticks\n");
+    out.push_str("# TYPE coinrd_price_gaps gauge\n");
+    let _ = writeln!(out, "coinrd_price_gaps {}", gapped);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coin::{Coin, Stack};
+    use crate::fetch_status::FetchStatus;
+    use super::{detect, missing_since_fetch, render_metrics, Gap};
+
+    fn gen_stack(at: i64, coins: Vec<&str>) -> Stack {
+        let mut stack = Stack::new();
+        stack.created_at = at;
+        for id in coins {
+            stack.coins.insert(id.to_string(), Coin {
+                id: id.to_string(),
+                symbol: id[..3].to_string(),
+                prices: vec![("usd".to_string(), at as f32)].into_iter().collect(),
+                derived: vec![],
+                last_updated_at: None,
+            });
+        }
+        stack
+    }
+
+    #[test]
+    fn i_should_detect_gaps() {
+        let stacks = vec![
+            gen_stack(60, vec!["bitcoin", "ethereum"]),
+            gen_stack(120, vec!["bitcoin"]),
+            gen_stack(420, vec!["bitcoin", "ethereum"]),
+            gen_stack(480, vec!["ethereum"]),
+        ];
+
+        let gaps = detect(stacks.clone().into_iter().map(Ok), &[], 60, 30, 600, 2).unwrap();
+        assert_eq!(gaps, vec![
+            Gap { coin: "bitcoin".into(), from: 180, to: 360, missing: 4 },
+            Gap { coin: "bitcoin".into(), from: 480, to: 540, missing: 2 },
+            Gap { coin: "ethereum".into(), from: 120, to: 360, missing: 5 },
+        ]);
+
+        // dogecoin is never seen, bitcoin misses the end of the range
+        let coins = vec!["bitcoin".to_string(), "dogecoin".to_string()];
+        let gaps = detect(stacks.into_iter().map(Ok), &coins, 60, 60, 720, 3).unwrap();
+        assert_eq!(gaps, vec![
+            Gap { coin: "bitcoin".into(), from: 180, to: 360, missing: 4 },
+            Gap { coin: "bitcoin".into(), from: 480, to: 660, missing: 4 },
+            Gap { coin: "dogecoin".into(), from: 60, to: 660, missing: 11 },
+        ]);
+    }
+
+    #[test]
+    fn i_should_see_unchanged_coins() {
+        let mut flat = gen_stack(120, vec![]);
+        flat.unchanged.push("bitcoin".into());
+        let stacks = vec![gen_stack(60, vec!["bitcoin"]), flat, gen_stack(180, vec![]), gen_stack(240, vec!["bitcoin"])];
+
+        let gaps = detect(stacks.into_iter().map(Ok), &[], 60, 60, 300, 1).unwrap();
+        assert_eq!(gaps, vec![Gap { coin: "bitcoin".into(), from: 180, to: 180, missing: 1 }]);
+    }
+
+    #[test]
+    fn i_should_gauge_missing_ticks() {
+        let mut status = FetchStatus {
+            id: "coingecko:bitcoin".into(),
+            provider: "coingecko".into(),
+            coin: "bitcoin".into(),
+            last_success: None,
+            consecutive_misses: 2,
+            last_error: None,
+            untracked: false,
+            updated_at: 300,
+        };
+        assert_eq!(missing_since_fetch(&status, 60, 600), 2);
+        status.last_success = Some(300);
+        assert_eq!(missing_since_fetch(&status, 60, 330), 0);
+        assert_eq!(missing_since_fetch(&status, 60, 600), 4);
+
+        let missing = vec![("bitcoin".to_string(), 4), ("ethereum".to_string(), 0)];
+        let metrics = render_metrics(&missing, 3);
+        assert!(metrics.contains("# TYPE coinrd_price_gap_ticks gauge\n"));
+        assert!(metrics.contains("coinrd_price_gap_ticks{coin=\"bitcoin\"} 4\n"));
+        assert!(metrics.ends_with("coinrd_price_gaps 1\n"));
+    }
+}
@@ -1,66 +1,275 @@
 use crate::provider::{Provide, Provider};
 use crate::coin::{Coin, Stack};
+use crate::discovery::ListedCoin;
+use crate::fetch_status::{Outcome, NOT_RETURNED};
+use crate::fx::{self, ExchangeRatesResponse, FxProvider, Rates};
 use reqwest::blocking;
-use std::collections::HashMap;
-use chrono::Utc;
+use std::collections::{HashMap, HashSet};
+use chrono::{NaiveDate, Utc};
+use log::warn;
+use serde::Deserialize;
+
+type PricesResponse = HashMap<String, HashMap<String, f64>>;
+
+// LAST_UPDATED_AT is the key of the time a coin's prices were
+// last updated in a simple_price response, in seconds
+const LAST_UPDATED_AT: &str = "last_updated_at";
 
 // format_coin_data transforms a gecko api response
-// into a HashMap of Coin
+// into a HashMap of Coin, along with the ids coins_config doesn't track
 fn format_coin_data(
-    mut coins_data: HashMap<String, HashMap<String, f32>>,
+    mut coins_data: PricesResponse,
     coins_config: &HashMap<String, String>
-) -> Option<HashMap<String, Coin>> {
+) -> (HashMap<String, Coin>, Vec<String>) {
     let mut coins: HashMap<String, Coin> = HashMap::new();
+    let mut untracked = vec![];
 
-    for (id, prices) in coins_data.drain() {
+    for (id, mut prices) in coins_data.drain() {
         let symbol = match coins_config.get(&id) {
             Some(l ) => l.to_owned(),
-            None => continue,
+            None => {
+                untracked.push(id);
+                continue
+            },
         };
+        let last_updated_at = prices.remove(LAST_UPDATED_AT).map(|t| t as i64);
 
         coins.insert(id.to_owned(), Coin {
             id,
             symbol,
-            prices,
+            prices: prices.into_iter().map(|(c, p)| (c, p as f32)).collect(),
+            derived: vec![],
+            last_updated_at,
         });
     };
 
-    if coins.len() == 0 {
-        return None
-    }
-    Some(coins)
+    (coins, untracked)
 }
 
-// simple_price gives price in specified currencies for spcific cryptocurrencies
-pub fn simple_price(provider: &Provider) -> Result<Stack, String> {
+// fetch_simple_price requests prices of a comma separated list of coins ids
+fn fetch_simple_price(provider: &Provider, ids: &str) -> Result<PricesResponse, String> {
     let uri = format!(
-        "{}?ids={}&vs_currencies={}",
+        "{}?ids={}&vs_currencies={}&include_last_updated_at=true",
         provider.get_uri("simple_price").unwrap(),
-        provider.get_coins_string(),
-        provider.get_currencies_string(),
+        ids,
+        provider.get_quoted_currencies_string(),
     );
 
+    let response_string = get_text(uri)?;
+    serde_json::from_str(response_string.as_str()).map_err(|err| err.to_string())
+}
+
+// get_text performs a GET request and renders the response body
+fn get_text(uri: String) -> Result<String, String> {
     // match response
-    let response_string: String = match blocking::get(uri) {
+    match blocking::get(uri).and_then(|response| response.error_for_status()) {
         // match render of content
         Ok(response) => 
             match response.text() {
-                Ok(res) => res,
-                Err(err) => return Err(err.to_string()),
+                Ok(res) => Ok(res),
+                Err(err) => Err(err.to_string()),
             },
-        Err(err) => return Err(err.to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+// simple_price gives price in specified currencies for spcific cryptocurrencies.
+// Coins are requested by chunks of chunk_size ids, one after another.
+// A failing chunk is logged and skipped, an error is returned if all chunks failed.
+// The outcome holds the error of every coin missing from the stack.
+pub fn simple_price(provider: &Provider, chunk_size: usize) -> Result<(Stack, Outcome), String> {
+    let chunks = provider.get_coins_chunks(chunk_size);
+    let mut data = PricesResponse::new();
+    let mut outcome = Outcome::default();
+    let mut last_err = None;
+
+    for ids in &chunks {
+        match fetch_simple_price(provider, ids) {
+            Ok(chunk_data) => data.extend(chunk_data),
+            Err(err) => {
+                warn!("simple_price chunk failed: {}", err);
+                for id in ids.split(',') {
+                    outcome.errors.insert(id.to_string(), err.to_owned());
+                }
+                last_err = Some(err);
+            },
+        }
+    }
+
+    if data.is_empty() {
+        if let Some(err) = last_err {
+            return Err(err);
+        }
+    }
+    let (coins, untracked) = format_coin_data(data, provider.get_coins());
+    if coins.is_empty() {
+        return Err(String::from("Could not retrieve any coin data"));
+    }
+    for id in provider.get_coins().keys() {
+        if !coins.contains_key(id) {
+            outcome.errors.entry(id.to_owned()).or_insert_with(|| NOT_RETURNED.to_string());
+        }
+    }
+    outcome.untracked = untracked;
+
+    let stack = Stack {
+        id: String::new(),
+        coins,
+        created_at: Utc::now().timestamp_millis(),
+        unchanged: vec![],
+    };
+    Ok((stack, outcome))
+}
+
+// fx_rates gives the values of currencies in BTC from an fx provider
+pub fn fx_rates(provider: &FxProvider) -> Result<Rates, String> {
+    match provider.get_uri("exchange_rates") {
+        Some(uri) => fetch_exchange_rates(uri),
+        None => Err(format!("No exchange_rates route for fx provider {}", provider.get_name())),
+    }
+}
+
+fn fetch_exchange_rates(uri: String) -> Result<Rates, String> {
+    let response_string = get_text(uri)?;
+    serde_json::from_str::<ExchangeRatesResponse>(response_string.as_str())
+        .map(fx::format_exchange_rates)
+        .map_err(|err| err.to_string())
+}
+
+#[derive(Deserialize)]
+struct MarketData {
+    current_price: HashMap<String, f32>,
+}
+
+#[derive(Deserialize)]
+struct HistoryResponse {
+    id: String,
+    market_data: Option<MarketData>,
+}
+
+// format_history_data keeps the provider's currencies
+// of a gecko coins history response
+fn format_history_data(history: HistoryResponse, provider: &Provider) -> Result<Coin, String> {
+    let symbol = match provider.get_coins().get(&history.id) {
+        Some(s) => s.to_owned(),
+        None => return Err(format!("Unknown coin {}", history.id)),
     };
+    let mut current_price = match history.market_data {
+        Some(md) => md.current_price,
+        None => return Err(format!("No market data for coin {}", history.id)),
+    };
+
+    let prices = provider
+        .get_currencies()
+        .iter()
+        .filter_map(|c| current_price.remove(c).map(|p| (c.to_owned(), p)))
+        .collect();
+
+    Ok(Coin {
+        id: history.id,
+        symbol,
+        prices,
+        derived: vec![],
+        last_updated_at: None,
+    })
+}
 
-    let coins_data = match serde_json::from_str(response_string.as_str()) {
-        Ok(data) => format_coin_data(data, provider.get_coins()),
-        Err(err) =>  return Err(err.to_string()),
+// coins_history gives prices of a coin at a given date (00:00 UTC)
+pub fn coins_history(provider: &Provider, id: &str, date: NaiveDate) -> Result<Coin, String> {
+    let route = match provider.get_uri("coins_history") {
+        Some(r) => r.replace("{id}", id),
+        None => return Err(format!("No coins_history route for provider {}", provider.get_name())),
     };
+    let uri = format!("{}?date={}&localization=false", route, date.format("%d-%m-%Y"));
 
-    match coins_data {
-        Some(coins) => Ok(Stack {
-            coins,
-            created_at: Utc::now().timestamp_millis(),
-        }),
-        None => Err(String::from("Could not retrieve any coin data")),
+    let response_string = get_text(uri)?;
+    match serde_json::from_str::<HistoryResponse>(response_string.as_str()) {
+        Ok(history) => format_history_data(history, provider),
+        Err(err) => Err(err.to_string()),
     }
-}
\ No newline at end of file
+}
+
+// MARKETS_PAGE_LEN is the maximum page size of coins markets route
+const MARKETS_PAGE_LEN: usize = 250;
+
+#[derive(Deserialize)]
+struct MarketEntry {
+    id: String,
+}
+
+// coins_list gives every coin listed by the provider
+pub fn coins_list(provider: &Provider) -> Result<Vec<ListedCoin>, String> {
+    let uri = match provider.get_uri("coins_list") {
+        Some(u) => u,
+        None => return Err(format!("No coins_list route for provider {}", provider.get_name())),
+    };
+
+    let response_string = get_text(uri)?;
+    serde_json::from_str(response_string.as_str()).map_err(|err| err.to_string())
+}
+
+// top_market_caps gives the ids of the n biggest coins by market cap
+pub fn top_market_caps(provider: &Provider, n: usize) -> Result<HashSet<String>, String> {
+    let route = match provider.get_uri("coins_markets") {
+        Some(u) => u,
+        None => return Err(format!("No coins_markets route for provider {}", provider.get_name())),
+    };
+    // pages must keep the same size for their offsets to be consistent
+    let per_page = MARKETS_PAGE_LEN.min(n);
+    let mut ids = vec![];
+    let mut page = 1;
+
+    while ids.len() < n {
+        let uri = format!("{}?vs_currency=usd&order=market_cap_desc&per_page={}&page={}", route, per_page, page);
+        let entries: Vec<MarketEntry> = serde_json::from_str(get_text(uri)?.as_str()).map_err(|err| err.to_string())?;
+        if entries.is_empty() {
+            break;
+        }
+        ids.extend(entries.into_iter().map(|e| e.id));
+        page += 1;
+    }
+
+    Ok(ids.into_iter().take(n).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::provider::{update_provider, Provide};
+    use super::{format_coin_data, format_history_data, HistoryResponse, PricesResponse};
+
+    #[test]
+    fn i_should_format_coin_data() {
+        let provider = update_provider("./test/providers-test-1.toml", "test1").unwrap();
+        let response: PricesResponse = serde_json::from_str(
+            r#"{"bitcoin":{"usd":42.5,"last_updated_at":1617235201},"nope":{"usd":1.0}}"#
+        ).unwrap();
+
+        let (coins, untracked) = format_coin_data(response, provider.get_coins());
+        assert_eq!(coins.len(), 1);
+        assert_eq!(untracked, vec!["nope".to_string()]);
+        assert_eq!(coins["bitcoin"].prices.get("usd"), Some(&42.5));
+        assert_eq!(coins["bitcoin"].prices.len(), 1);
+        assert_eq!(coins["bitcoin"].last_updated_at, Some(1617235201));
+    }
+
+    #[test]
+    fn i_should_format_history_data() {
+        let provider = update_provider("./test/providers-test-1.toml", "test1").unwrap();
+        let history: HistoryResponse = serde_json::from_str(
+            r#"{"id":"bitcoin","symbol":"btc","market_data":{"current_price":{"usd":42.0,"jpy":4200.0}}}"#
+        ).unwrap();
+
+        let coin = format_history_data(history, &provider).unwrap();
+        assert_eq!(coin.symbol, "btc");
+        assert_eq!(coin.prices.get("usd"), Some(&42.0));
+        assert_eq!(coin.prices.get("jpy"), None);
+    }
+
+    #[test]
+    fn i_should_reject_history_without_market_data() {
+        let provider = update_provider("./test/providers-test-1.toml", "test1").unwrap();
+        let history: HistoryResponse = serde_json::from_str(r#"{"id":"bitcoin","symbol":"btc"}"#).unwrap();
+
+        assert!(format_history_data(history, &provider).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use chrono::Utc;
+use serde::Serialize;
+
+// Health keeps track of the collector's liveness. It is updated
+// by the main loop and read by the HTTP server.
+pub struct Health {
+    started_at: i64,
+    last_fetch_at: AtomicI64,
+    last_write_at: AtomicI64,
+    // interval_ms is the expected duration between two ticks
+    interval_ms: i64,
+    // ready_intervals is the number of intervals a fetch or a write
+    // can be late before the collector is considered not ready
+    ready_intervals: i64,
+    // config_errors are the errors of the last rejected providers
+    // reload, the collector running on the previous providers
+    config_errors: Mutex<Vec<String>>,
+    // standby is raised while another replica holds the leader lease
+    standby: AtomicBool,
+}
+
+// Status is the serializable snapshot of a Health
+#[derive(Serialize, Debug)]
+pub struct Status {
+    pub ready: bool,
+    pub started_at: i64,
+    pub last_fetch_at: i64,
+    pub last_write_at: i64,
+    pub standby: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub config_errors: Vec<String>,
+}
+
+impl Health {
+    pub fn new(interval_ms: i64, ready_intervals: i64) -> Self {
+        Self {
+            started_at: Utc::now().timestamp_millis(),
+            last_fetch_at: AtomicI64::new(0),
+            last_write_at: AtomicI64::new(0),
+            interval_ms,
+            ready_intervals,
+            config_errors: Mutex::new(vec![]),
+            standby: AtomicBool::new(false),
+        }
+    }
+
+    // mark_fetch records a successful fetch from a provider
+    pub fn mark_fetch(&self) {
+        self.last_fetch_at.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    // mark_write records a tick whose DB writes all succeeded
+    pub fn mark_write(&self) {
+        self.last_write_at.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    // set_config_errors records the errors of a providers reload,
+    // an empty list meaning the last reload succeeded
+    pub fn set_config_errors(&self, errors: Vec<String>) {
+        *self.config_errors.lock().unwrap() = errors;
+    }
+
+    pub fn set_standby(&self, standby: bool) {
+        self.standby.store(standby, Ordering::Relaxed);
+    }
+
+    // is_ready tells if both the last fetch and the last write
+    // happened within ready_intervals intervals from now.
+    // A standby replica is ready, as it doesn't fetch nor write.
+    pub fn is_ready(&self, now: i64) -> bool {
+        if self.standby.load(Ordering::Relaxed) {
+            return true;
+        }
+        let max_age = self.interval_ms * self.ready_intervals;
+        let fresh = |at: i64| at > 0 && now - at <= max_age;
+
+        fresh(self.last_fetch_at.load(Ordering::Relaxed))
+            && fresh(self.last_write_at.load(Ordering::Relaxed))
+    }
+
+    pub fn status(&self, now: i64) -> Status {
+        Status {
+            ready: self.is_ready(now),
+            started_at: self.started_at,
+            last_fetch_at: self.last_fetch_at.load(Ordering::Relaxed),
+            last_write_at: self.last_write_at.load(Ordering::Relaxed),
+            standby: self.standby.load(Ordering::Relaxed),
+            config_errors: self.config_errors.lock().unwrap().to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use super::Health;
+
+    #[test]
+    fn i_should_not_be_ready_before_first_tick() {
+        let health = Health::new(1000, 3);
+        assert!(!health.is_ready(health.started_at));
+    }
+
+    #[test]
+    fn i_should_be_ready_within_intervals() {
+        let health = Health::new(1000, 3);
+        health.mark_fetch();
+        health.mark_write();
+        let at = health.last_fetch_at.load(Ordering::Relaxed);
+        assert!(health.is_ready(at + 3000));
+        assert!(!health.is_ready(at + 3001));
+    }
+
+    #[test]
+    fn i_should_be_ready_on_standby() {
+        let health = Health::new(1000, 3);
+        health.set_standby(true);
+        assert!(health.is_ready(health.started_at));
+        assert!(health.status(health.started_at).standby);
+    }
+
+    #[test]
+    fn i_should_not_be_ready_without_write() {
+        let health = Health::new(1000, 3);
+        health.mark_fetch();
+        let at = health.last_fetch_at.load(Ordering::Relaxed);
+        assert!(!health.is_ready(at));
+    }
+}
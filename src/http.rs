@@ -0,0 +1,525 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use chrono::{NaiveDate, Utc};
+use log::{info, warn};
+
+use crate::coin::Stack;
+use crate::database::{Collection, MongoDB};
+use crate::export::{self, Format};
+use crate::gaps;
+use crate::health::Health;
+use crate::indicators::{self, Spec};
+use crate::fetch_status::FetchStatus;
+use crate::portfolio::{self, Snapshot};
+
+// READ_TIMEOUT bounds how long a client may take to send its request line
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// MAX_CONNECTIONS bounds the connections handled at once,
+// the next ones being answered 503 right away
+const MAX_CONNECTIONS: usize = 32;
+
+// Context holds what request handlers need
+pub struct Context {
+    pub health: Arc<Health>,
+    pub db: MongoDB,
+    pub price_history: String,
+    pub portfolio_history: String,
+    pub fetch_status: String,
+    pub interval_ms: i64,
+    pub gap_min_ticks: i64,
+}
+
+type Stream = Box<dyn FnOnce(&mut (dyn Write + Send)) -> Result<(), String> + Send>;
+
+pub enum Body {
+    Text(String),
+    // Stream is written to the connection in chunks, the status line
+    // and headers going with the first one. A stream failing before
+    // writing anything is answered 500, one failing after is cut short
+    // of its last chunk, so clients can't take it for a whole body.
+    Stream(Stream),
+}
+
+// Response is a minimal HTTP response
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Body,
+}
+
+impl Response {
+    fn json(status: u16, body: String) -> Self {
+        Self { status, content_type: "application/json", body: Body::Text(body) }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self::json(status, serde_json::json!({ "error": message }).to_string())
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            500 => "Internal Server Error",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+}
+
+// Request is a parsed HTTP request line
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+}
+
+// route matches a request with its handler
+pub fn route(req: &Request, ctx: &Context) -> Response {
+    if req.method != "GET" {
+        return Response::error(405, "method not allowed");
+    }
+
+    match req.path.as_str() {
+        "/healthz" => Response::json(200, r#"{"status":"ok"}"#.into()),
+        "/readyz" => {
+            let status = ctx.health.status(Utc::now().timestamp_millis());
+            let code = if status.ready { 200 } else { 503 };
+            Response::json(code, serde_json::to_string(&status).unwrap_or_default())
+        },
+        "/export" => export_handler(req, ctx).unwrap_or_else(|err| Response::error(400, &err)),
+        "/indicators" => indicators_handler(req, ctx).unwrap_or_else(|err| Response::error(400, &err)),
+        "/portfolio" => portfolio_handler(req, ctx).unwrap_or_else(|err| Response::error(400, &err)),
+        "/metrics" => metrics_handler(ctx).unwrap_or_else(|err| Response::error(500, &err)),
+        _ => Response::error(404, "not found"),
+    }
+}
+
+// query_range gives the [from, to) range of the from and to dates
+// of a query, defaulting to the whole history
+fn query_range(req: &Request) -> Result<(i64, i64), String> {
+    let date = |key: &str| -> Result<Option<NaiveDate>, String> {
+        req.query
+            .get(key)
+            .map(|v| NaiveDate::parse_from_str(v, "%Y-%m-%d").map_err(|err| format!("{}: {}", key, err)))
+            .transpose()
+    };
+    Ok(export::date_range(date("from")?, date("to")?))
+}
+
+// export_handler streams price history rows.
+// Query: format=csv|jsonl|parquet, from=YYYY-MM-DD, to=YYYY-MM-DD, coins=id,..
+fn export_handler(req: &Request, ctx: &Context) -> Result<Response, String> {
+    let format = match req.query.get("format") {
+        Some(f) => f.parse::<Format>()?,
+        None => Format::Csv,
+    };
+    let (from, to) = query_range(req)?;
+    let coins: Vec<String> = match req.query.get("coins") {
+        Some(c) => c.split(',').filter(|s| !s.is_empty()).map(|s| s.to_string()).collect(),
+        None => vec![],
+    };
+
+    let coll = ctx.db.new_collection::<Stack>(&ctx.price_history);
+    Ok(Response {
+        status: 200,
+        content_type: format.content_type(),
+        body: Body::Stream(Box::new(move |out| {
+            export::export(coll.find_range("created_at", from, to), &coins, format, out).map(|_| ())
+        })),
+    })
+}
+
+// indicators_handler computes an indicator over price history.
+// Query: indicator=sma|ema|rsi|macd|bollinger|atr, coin=id, currency=usd,
+// period=N, candle_secs=N (default 3600), from=YYYY-MM-DD, to=YYYY-MM-DD
+fn indicators_handler(req: &Request, ctx: &Context) -> Result<Response, String> {
+    let param = |key: &str| req.query.get(key).ok_or(format!("missing {}", key));
+    let number = |key: &str| -> Result<Option<usize>, String> {
+        req.query
+            .get(key)
+            .map(|v| v.parse::<usize>().map_err(|err| format!("{}: {}", key, err)))
+            .transpose()
+    };
+    let spec = Spec::new(param("indicator")?.parse()?, param("coin")?, param("currency")?, number("period")?)?;
+    let candle_secs = number("candle_secs")?.unwrap_or(3600);
+    if candle_secs == 0 {
+        return Err("candle_secs must be greater than 0".into());
+    }
+    let (from, to) = query_range(req)?;
+
+    let coll = ctx.db.new_collection::<Stack>(&ctx.price_history);
+    let points = indicators::compute(&coll, &spec, candle_secs as i64 * 1000, from, to)?;
+    Ok(Response::json(200, serde_json::to_string(&points).map_err(|err| err.to_string())?))
+}
+
+// portfolio_handler reports the latest value of a portfolio.
+// Query: id=ID, currency=usd (default), since=YYYY-MM-DD for the P&L
+fn portfolio_handler(req: &Request, ctx: &Context) -> Result<Response, String> {
+    let id = req.query.get("id").ok_or("missing id")?;
+    let currency = req.query.get("currency").map(|c| c.as_str()).unwrap_or("usd");
+    let since = req.query
+        .get("since")
+        .map(|v| NaiveDate::parse_from_str(v, "%Y-%m-%d").map_err(|err| format!("since: {}", err)))
+        .transpose()?;
+
+    let history = ctx.db.new_collection::<Snapshot>(&ctx.portfolio_history);
+    let report = portfolio::report(&history, id, currency, since.map(export::day_start), Utc::now().timestamp_millis())?;
+    Ok(Response::json(200, serde_json::to_string(&report).map_err(|err| err.to_string())?))
+}
+
+// metrics_handler gauges the ticks each tracked coin missed since
+// it was last fetched, in the Prometheus text format
+fn metrics_handler(ctx: &Context) -> Result<Response, String> {
+    let now = Utc::now().timestamp_millis();
+    let mut missing: Vec<(String, i64)> = ctx.db
+        .new_collection::<FetchStatus>(&ctx.fetch_status)
+        .find_all()?
+        .iter()
+        .filter(|status| !status.untracked)
+        .map(|status| (status.coin.to_owned(), gaps::missing_since_fetch(status, ctx.interval_ms, now)))
+        .collect();
+    missing.sort();
+    Ok(Response {
+        status: 200,
+        content_type: "text/plain; version=0.0.4",
+        body: Body::Text(gaps::render_metrics(&missing, ctx.gap_min_ticks)),
+    })
+}
+
+// percent_decode decodes %XX sequences and + of a query component
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(b) => {
+                        decoded.push(b);
+                        i += 2;
+                    },
+                    None => decoded.push(b'%'),
+                }
+            },
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// parse_request_line extracts method, path and query
+// from an HTTP request line
+fn parse_request_line(line: &str) -> Option<Request> {
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?;
+    let (path, query) = match target.split_once('?') {
+        Some((p, q)) => (p, q),
+        None => (target, ""),
+    };
+
+    let query = query
+        .split('&')
+        .filter(|kv| !kv.is_empty())
+        .map(|kv| match kv.split_once('=') {
+            Some((k, v)) => (percent_decode(k), percent_decode(v)),
+            None => (percent_decode(kv), String::new()),
+        })
+        .collect();
+
+    Some(Request { method, path: path.to_string(), query })
+}
+
+fn handle(stream: TcpStream, ctx: &Context) {
+    // an idle client must not hold its connection forever
+    if let Err(err) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+        warn!("Could not set read timeout: {}", err);
+        return;
+    }
+    let mut line = String::new();
+    if let Err(err) = BufReader::new(&stream).read_line(&mut line) {
+        warn!("Could not read request: {}", err);
+        return;
+    }
+
+    let response = match parse_request_line(&line) {
+        Some(req) => route(&req, ctx),
+        None => Response::error(400, "bad request"),
+    };
+    respond(stream, response);
+}
+
+// Chunked writes a body with the chunked transfer encoding,
+// head being written before the first chunk
+struct Chunked<'a> {
+    stream: &'a mut TcpStream,
+    head: Option<String>,
+}
+
+impl Chunked<'_> {
+    // finish writes the last chunk, marking the end of the body
+    fn finish(mut self) -> io::Result<()> {
+        if let Some(head) = self.head.take() {
+            self.stream.write_all(head.as_bytes())?;
+        }
+        self.stream.write_all(b"0\r\n\r\n")?;
+        self.stream.flush()
+    }
+}
+
+impl Write for Chunked<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if let Some(head) = self.head.take() {
+            self.stream.write_all(head.as_bytes())?;
+        }
+        write!(self.stream, "{:x}\r\n", buf.len())?;
+        self.stream.write_all(buf)?;
+        self.stream.write_all(b"\r\n")?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+// respond writes response to stream
+fn respond(mut stream: TcpStream, response: Response) {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nConnection: close\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+    );
+    let res = match response.body {
+        Body::Text(body) => stream
+            .write_all(format!("{}Content-Length: {}\r\n\r\n{}", head, body.len(), body).as_bytes())
+            .map_err(|err| err.to_string()),
+        Body::Stream(write_body) => {
+            let head = format!("{}Transfer-Encoding: chunked\r\n\r\n", head);
+            let mut out = BufWriter::new(Chunked { stream: &mut stream, head: Some(head) });
+            let res = write_body(&mut out).and_then(|_| out.flush().map_err(|err| err.to_string()));
+            // what is still buffered is dropped on error
+            let (chunked, _) = out.into_parts();
+            match res {
+                Ok(_) => chunked.finish().map_err(|err| err.to_string()),
+                Err(err) if chunked.head.is_some() => {
+                    warn!("Could not stream response: {}", err);
+                    return respond(stream, Response::error(500, &err));
+                },
+                Err(err) => Err(err),
+            }
+        },
+    };
+    if let Err(err) = res {
+        warn!("Could not write response: {}", err);
+    }
+}
+
+// Slot counts a connection being handled until dropped
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    // take gives a slot if fewer than max connections are handled
+    fn take(active: &Arc<AtomicUsize>, max: usize) -> Option<Slot> {
+        active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| Some(n + 1).filter(|_| n < max))
+            .ok()
+            .map(|_| Slot(active.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// serve binds addr and answers requests from a dedicated thread,
+// each connection being handled on its own thread so a long export
+// does not hold health checks. Up to MAX_CONNECTIONS are handled at once.
+pub fn serve(addr: &str, ctx: Context) -> std::io::Result<thread::JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)?;
+    let ctx = Arc::new(ctx);
+    let active = Arc::new(AtomicUsize::new(0));
+    info!("HTTP server listening on {}", addr);
+
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            let s = match stream {
+                Ok(s) => s,
+                Err(err) => {
+                    warn!("Could not accept connection: {}", err);
+                    continue;
+                },
+            };
+            match Slot::take(&active, MAX_CONNECTIONS) {
+                Some(slot) => {
+                    let ctx = ctx.clone();
+                    thread::spawn(move || {
+                        handle(s, &ctx);
+                        drop(slot);
+                    });
+                },
+                None => {
+                    warn!("{} connections already handled, refusing one", MAX_CONNECTIONS);
+                    // the answer is small, a slow client can't hold the accept loop long
+                    let _ = s.set_write_timeout(Some(READ_TIMEOUT));
+                    respond(s, Response::error(503, "too many connections"));
+                },
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use crate::collector::db_connection;
+    use crate::health::Health;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::{parse_request_line, percent_decode, respond, route, Body, Context, Request, Response, Slot};
+
+    fn gen_context() -> Context {
+        Context {
+            health: Arc::new(Health::new(1000, 3)),
+            // the client connects lazily, no server is needed
+            db: db_connection("mongodb://localhost:27017", "test"),
+            price_history: "price_history".into(),
+            portfolio_history: "portfolio_history".into(),
+            fetch_status: "fetch_status".into(),
+            interval_ms: 1000,
+            gap_min_ticks: 3,
+        }
+    }
+
+    fn get(path: &str) -> Request {
+        parse_request_line(&format!("GET {} HTTP/1.1\r\n", path)).unwrap()
+    }
+
+    #[test]
+    fn i_should_parse_request_line() {
+        let req = get("/export?coins=bitcoin%2Cethereum&format=csv&flag");
+        assert_eq!(req.method, "GET");
+        assert_eq!(req.path, "/export");
+        assert_eq!(req.query.get("coins").unwrap(), "bitcoin,ethereum");
+        assert_eq!(req.query.get("format").unwrap(), "csv");
+        assert_eq!(req.query.get("flag").unwrap(), "");
+        assert_eq!(parse_request_line(""), None);
+    }
+
+    #[test]
+    fn i_should_percent_decode() {
+        assert_eq!(percent_decode("a%20b+c"), "a b c");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+
+    #[test]
+    fn i_should_route_health_endpoints() {
+        let ctx = gen_context();
+        assert_eq!(route(&get("/healthz"), &ctx).status, 200);
+        assert_eq!(route(&get("/readyz"), &ctx).status, 503);
+        ctx.health.mark_fetch();
+        ctx.health.mark_write();
+        assert_eq!(route(&get("/readyz"), &ctx).status, 200);
+        assert_eq!(route(&get("/nope"), &ctx).status, 404);
+
+        let post = parse_request_line("POST /healthz HTTP/1.1").unwrap();
+        assert_eq!(route(&post, &ctx).status, 405);
+    }
+
+    #[test]
+    fn i_should_reject_bad_export_queries() {
+        let ctx = gen_context();
+        assert_eq!(route(&get("/export?format=xls"), &ctx).status, 400);
+        assert_eq!(route(&get("/export?from=01-01-2021"), &ctx).status, 400);
+
+        let res = route(&get("/export?format=parquet&from=2021-01-01"), &ctx);
+        assert_eq!(res.status, 200);
+        assert_eq!(res.content_type, "application/vnd.apache.parquet");
+    }
+
+    #[test]
+    fn i_should_reject_bad_indicator_queries() {
+        let ctx = gen_context();
+        assert_eq!(route(&get("/indicators?coin=bitcoin&currency=usd"), &ctx).status, 400);
+        assert_eq!(route(&get("/indicators?indicator=vwap&coin=bitcoin&currency=usd"), &ctx).status, 400);
+        assert_eq!(route(&get("/indicators?indicator=rsi&coin=bitcoin&currency=usd&period=0"), &ctx).status, 400);
+        assert_eq!(route(&get("/indicators?indicator=rsi&coin=bitcoin&currency=usd&candle_secs=x"), &ctx).status, 400);
+        assert_eq!(route(&get("/portfolio"), &ctx).status, 400);
+        assert_eq!(route(&get("/portfolio?id=main&since=yesterday"), &ctx).status, 400);
+    }
+
+    // stream_response responds with a stream writing body then returning res,
+    // and gives what a client reads
+    fn stream_response(body: String, res: Result<(), String>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        respond(server, Response {
+            status: 200,
+            content_type: "text/csv",
+            body: Body::Stream(Box::new(move |out| {
+                out.write_all(body.as_bytes()).map_err(|err| err.to_string())?;
+                res
+            })),
+        });
+        let mut read = String::new();
+        client.read_to_string(&mut read).unwrap();
+        read
+    }
+
+    #[test]
+    fn i_should_stream_chunks() {
+        let whole = stream_response("a,b\n".into(), Ok(()));
+        assert!(whole.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(whole.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(whole.ends_with("\r\n\r\n4\r\na,b\n\r\n0\r\n\r\n"));
+
+        let failed = stream_response(String::new(), Err("query failed".into()));
+        assert!(failed.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(failed.contains("query failed"));
+
+        // buffered rows of a failed stream are dropped, those sent lack the last chunk
+        let cut = stream_response("a,b\n".into(), Err("cursor died".into()));
+        assert!(cut.starts_with("HTTP/1.1 500"));
+        let cut = stream_response("x".repeat(10_000), Err("cursor died".into()));
+        assert!(cut.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(!cut.ends_with("0\r\n\r\n"));
+    }
+
+    #[test]
+    fn i_should_bound_connections() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let first = Slot::take(&active, 2).unwrap();
+        let second = Slot::take(&active, 2).unwrap();
+        assert!(Slot::take(&active, 2).is_none());
+
+        drop(first);
+        assert!(Slot::take(&active, 2).is_some());
+        drop(second);
+        assert_eq!(active.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use log::warn;
+use serde_json::{json, Value};
+
+use crate::coin::{self, Coin, Stack};
+use crate::database::{Collection, Update};
+use crate::export::{Format, Row};
+
+// Summary counts what an import went through
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub lines: usize,
+    pub stacks: usize,
+}
+
+// stack_id gives the id of a stack that doesn't have one yet,
+// keyed as the live ticks of the default provider
+pub fn stack_id(stack: &Stack) -> String {
+    if stack.id.is_empty() {
+        return coin::tick_id(coin::DEFAULT_PROVIDER, stack.created_at);
+    }
+    stack.id.to_owned()
+}
+
+// merge_stack upserts stack into coll, setting its coins' prices one
+// by one over the ones of an already stored stack with the same id, so
+// writers of a same tick, as replicas or an import, keep each other's
+// coins. Importing the same data twice therefore leaves the same documents.
+// Coins or currencies that can't be field names are skipped.
+pub fn merge_stack(stack: Stack, coll: &impl Collection<Stack>) -> Result<(), String> {
+    let id = stack_id(&stack);
+    let mut update = Update::default();
+    // currencies now quoted leave derived, which can't
+    // be added to and pulled from in a same update
+    let mut quoted = Update::default();
+
+    update.set.push(("id".into(), json!(id)));
+    update.set.push(("created_at".into(), json!(stack.created_at)));
+    if !stack.unchanged.is_empty() {
+        update.add_to_set.push(("unchanged".into(), stack.unchanged.iter().map(|c| json!(c)).collect()));
+    }
+    for (coin_id, coin) in &stack.coins {
+        if !is_field_name(coin_id) || !coin.prices.keys().all(|c| is_field_name(c)) {
+            warn!("Skipped {} of {}: its id or a currency can't be a field name", coin_id, id);
+            continue;
+        }
+        let path = format!("coins.{}", coin_id);
+        update.set.push((format!("{}.id", path), json!(coin.id)));
+        update.set.push((format!("{}.symbol", path), json!(coin.symbol)));
+        for (currency, price) in &coin.prices {
+            update.set.push((format!("{}.prices.{}", path, currency), json!(price)));
+        }
+        if let Some(at) = coin.last_updated_at {
+            update.set.push((format!("{}.last_updated_at", path), json!(at)));
+        }
+        if !coin.derived.is_empty() {
+            update.add_to_set.push((format!("{}.derived", path), coin.derived.iter().map(|c| json!(c)).collect()));
+        }
+        let quotes: Vec<Value> = coin.prices.keys().filter(|c| !coin.derived.contains(c)).map(|c| json!(c)).collect();
+        if !quotes.is_empty() {
+            quoted.pull.push((format!("{}.derived", path), quotes));
+        }
+    }
+
+    coll.update(id.to_owned(), &update)?;
+    if !quoted.is_empty() {
+        coll.update(id, &quoted)?;
+    }
+    Ok(())
+}
+
+// is_field_name tells if key can be a step of a dotted field path
+fn is_field_name(key: &str) -> bool {
+    !key.is_empty() && !key.contains('.') && !key.starts_with('$')
+}
+
+// split_csv_line splits a CSV line, handling quoted fields
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            },
+            ('"', _) => quoted = !quoted,
+            (',', false) => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+// CsvColumns maps the columns of a Row to their position in a CSV header
+struct CsvColumns {
+    positions: HashMap<String, usize>,
+}
+
+impl CsvColumns {
+    fn from_header(header: &str) -> Result<Self, String> {
+        let positions: HashMap<String, usize> = split_csv_line(header)
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| (name.trim().to_string(), i))
+            .collect();
+
+        for name in &["timestamp", "coin_id", "symbol", "currency", "price"] {
+            if !positions.contains_key(*name) {
+                return Err(format!("line 1: missing {} column", name));
+            }
+        }
+        Ok(Self { positions })
+    }
+
+    // parse reads a row of line. The provider and derived columns are
+    // optional, as exports didn't always have them.
+    fn parse(&self, line: &str) -> Result<Row, String> {
+        let fields = split_csv_line(line);
+        let get = |name: &str| -> Result<&str, String> {
+            fields
+                .get(self.positions[name])
+                .map(|f| f.trim())
+                .ok_or_else(|| format!("missing {}", name))
+        };
+        let optional = |name: &str| self.positions.get(name).and_then(|i| fields.get(*i)).map(|f| f.trim());
+
+        Ok(Row {
+            timestamp: get("timestamp")?.parse::<i64>().map_err(|err| format!("timestamp: {}", err))?,
+            coin_id: get("coin_id")?.to_string(),
+            symbol: get("symbol")?.to_string(),
+            currency: get("currency")?.to_string(),
+            price: get("price")?.parse::<f32>().map_err(|err| format!("price: {}", err))?,
+            provider: optional("provider").unwrap_or(coin::DEFAULT_PROVIDER).to_string(),
+            derived: match optional("derived") {
+                Some(d) => d.parse::<bool>().map_err(|err| format!("derived: {}", err))?,
+                None => false,
+            },
+        })
+    }
+}
+
+// import reads input in format and upserts its stacks in coll.
+// Rows sharing a provider and timestamp are gathered in a single
+// stack keyed as live ticks, rows are expected to be sorted by
+// timestamp as exports are.
+pub fn import<R: BufRead>(input: R, format: Format, coll: &impl Collection<Stack>) -> Result<Summary, String> {
+    let mut summary = Summary::default();
+    let mut current: Option<Stack> = None;
+    let mut csv_columns: Option<CsvColumns> = None;
+
+    for (idx, line) in input.lines().enumerate() {
+        let line = line.map_err(|err| err.to_string())?;
+        let at_line = |err: String| format!("line {}: {}", idx + 1, err);
+        if line.trim().is_empty() {
+            continue;
+        }
+        summary.lines += 1;
+
+        let row = match format {
+            Format::Dump => {
+                let stack = serde_json::from_str::<Stack>(&line).map_err(|err| at_line(err.to_string()))?;
+                merge_stack(stack, coll).map_err(at_line)?;
+                summary.stacks += 1;
+                continue;
+            },
+            Format::JsonLines => serde_json::from_str::<Row>(&line).map_err(|err| at_line(err.to_string()))?,
+            Format::Csv => match &csv_columns {
+                Some(columns) => columns.parse(&line).map_err(at_line)?,
+                None => {
+                    csv_columns = Some(CsvColumns::from_header(&line)?);
+                    continue;
+                },
+            },
+            Format::Parquet => return Err("parquet files can't be imported".into()),
+        };
+
+        let Row { timestamp, coin_id, symbol, currency, price, provider, derived } = row;
+        let id = coin::tick_id(&provider, timestamp);
+        if let Some(stack) = current.take() {
+            if stack.id == id {
+                current = Some(stack);
+            } else {
+                merge_stack(stack, coll).map_err(at_line)?;
+                summary.stacks += 1;
+            }
+        }
+        let stack = current.get_or_insert_with(|| Stack {
+            id,
+            coins: HashMap::new(),
+            created_at: timestamp,
+            unchanged: vec![],
+        });
+        let coin = stack
+            .coins
+            .entry(coin_id.to_owned())
+            .or_insert_with(|| Coin { id: coin_id, symbol, prices: HashMap::new(), derived: vec![], last_updated_at: None });
+        if derived {
+            coin.derived.push(currency.to_owned());
+        }
+        coin.prices.insert(currency, price);
+    }
+
+    if let Some(stack) = current {
+        merge_stack(stack, coll)?;
+        summary.stacks += 1;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use crate::coin::{Coin, Stack};
+    use crate::database::{Collection, MemoryDB};
+    use crate::export::{self, Format};
+    use super::{import, split_csv_line};
+
+    const CSV: &str = "timestamp,coin_id,symbol,currency,price
+1000,bitcoin,btc,usd,42.5
+1000,bitcoin,btc,eur,21.25
+1000,ethereum,eth,usd,4.2
+2000,bitcoin,btc,usd,43
+";
+
+    #[test]
+    fn i_should_split_quoted_csv_fields() {
+        assert_eq!(split_csv_line(r#"1,"a,b","say ""hi""""#), vec!["1", "a,b", r#"say "hi""#]);
+    }
+
+    #[test]
+    fn i_should_import_csv_rows_in_stacks() {
+        let coll = MemoryDB::new().new_collection::<Stack>("price_history");
+        let summary = import(Cursor::new(CSV), Format::Csv, &coll).unwrap();
+
+        assert_eq!(summary.lines, 5);
+        assert_eq!(summary.stacks, 2);
+        let stack = coll.find_one("coingecko:1000".into()).unwrap();
+        assert_eq!(stack.coins.len(), 2);
+        assert_eq!(stack.coins["bitcoin"].prices["eur"], 21.25);
+        assert_eq!(coll.find_one("coingecko:2000".into()).unwrap().coins["bitcoin"].prices["usd"], 43.0);
+    }
+
+    #[test]
+    fn i_should_import_twice_without_duplicates() {
+        let coll = MemoryDB::new().new_collection::<Stack>("price_history");
+        import(Cursor::new(CSV), Format::Csv, &coll).unwrap();
+        import(Cursor::new(CSV), Format::Csv, &coll).unwrap();
+
+        assert_eq!(coll.find_all().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn i_should_merge_into_live_ticks() {
+        let coll = MemoryDB::new().new_collection::<Stack>("price_history");
+        let mut live = Stack::new();
+        live.created_at = 1000;
+        live.align_to_tick("coingecko", 1000);
+        live.coins.insert("bitcoin".into(), Coin {
+            id: "bitcoin".into(),
+            symbol: "btc".into(),
+            prices: vec![("usd".to_string(), 42.0), ("eur".to_string(), 20.0)].into_iter().collect(),
+            derived: vec!["eur".into()],
+            last_updated_at: None,
+        });
+        coll.save(live.id.to_owned(), &live).unwrap();
+
+        let csv = "timestamp,coin_id,symbol,currency,price,provider,derived\n1000,bitcoin,btc,eur,21.25,coingecko,false\n1000,bitcoin,btc,gbp,18,coingecko,true\n";
+        import(Cursor::new(csv), Format::Csv, &coll).unwrap();
+
+        assert_eq!(coll.find_all().unwrap().len(), 1);
+        let bitcoin = &coll.find_one("coingecko:1000".into()).unwrap().coins["bitcoin"];
+        assert_eq!((bitcoin.prices["usd"], bitcoin.prices["eur"]), (42.0, 21.25));
+        assert_eq!(bitcoin.derived, vec!["gbp".to_string()]);
+    }
+
+    #[test]
+    fn i_should_round_trip_exports() {
+        let source = MemoryDB::new().new_collection::<Stack>("price_history");
+        import(Cursor::new(CSV), Format::Csv, &source).unwrap();
+        let mut stack = source.find_one("coingecko:1000".into()).unwrap();
+        stack.coins.get_mut("bitcoin").unwrap().derived.push("eur".into());
+        source.save(stack.id.to_owned(), &stack).unwrap();
+
+        for format in &[Format::JsonLines, Format::Dump, Format::Csv] {
+            let mut out = vec![];
+            export::export(source.find_range("created_at", 0, i64::MAX), &[], *format, &mut out).unwrap();
+
+            let target = MemoryDB::new().new_collection::<Stack>("price_history");
+            import(Cursor::new(out), *format, &target).unwrap();
+            let stack = target.find_one("coingecko:1000".into()).unwrap();
+            assert_eq!(stack.coins.len(), 2);
+            assert_eq!(stack.coins["bitcoin"].derived, vec!["eur".to_string()]);
+            assert_eq!(target.find_all().unwrap().len(), 2);
+        }
+    }
+
+    #[test]
+    fn i_should_report_bad_lines() {
+        let coll = MemoryDB::new().new_collection::<Stack>("price_history");
+        let err = import(Cursor::new("timestamp,coin_id,symbol,currency,price\n1000,bitcoin,btc,usd,abc\n"), Format::Csv, &coll);
+        assert!(err.unwrap_err().starts_with("line 2: price"));
+
+        let err = import(Cursor::new("timestamp,coin_id\n"), Format::Csv, &coll);
+        assert_eq!(err.unwrap_err(), "line 1: missing symbol column");
+    }
+}
@@ -0,0 +1,535 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use serde::{Serialize, Deserialize};
+
+use crate::coin::Stack;
+use crate::database::Collection;
+
+// MACD periods are the usual fast, slow and signal ones
+const MACD_FAST: usize = 12;
+const MACD_SLOW: usize = 26;
+const MACD_SIGNAL: usize = 9;
+// BOLLINGER_K is the number of standard deviations of the bands
+const BOLLINGER_K: f64 = 2.0;
+// MAX_CANDLES bounds the candles gathered at once, flat ones included
+pub const MAX_CANDLES: i64 = 100_000;
+
+// Candle is the open, high, low and close prices of the ticks
+// falling in [start, start + candle length)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candle {
+    pub start: i64,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+}
+
+// candles gathers the prices in currency of coin from stacks
+// into candles of candle_ms, failing on the first error of stacks.
+// As unchanged prices aren't stored, a candle without prices is
+// filled with the previous close, so periods count time.
+// Fails if there would be more than MAX_CANDLES candles.
+pub fn candles(stacks: impl Iterator<Item = Result<Stack, String>>, coin: &str, currency: &str, candle_ms: i64) -> Result<Vec<Candle>, String> {
+    let mut prices: Vec<(i64, f64)> = vec![];
+    for stack in stacks {
+        let stack = stack?;
+        let price = match stack.coins.get(coin).and_then(|c| c.prices.get(currency)) {
+            Some(p) if p.is_finite() => *p as f64,
+            _ => continue,
+        };
+        prices.push((stack.created_at, price));
+    }
+    prices.sort_by_key(|(at, _)| *at);
+
+    let candle_ms = candle_ms.max(1);
+    let mut candles: Vec<Candle> = vec![];
+    for (at, price) in prices {
+        let start = at - at.rem_euclid(candle_ms);
+        let count = candles.len() as i64;
+        match candles.last_mut() {
+            Some(c) if c.start == start => {
+                c.high = c.high.max(price);
+                c.low = c.low.min(price);
+                c.close = price;
+            },
+            Some(c) => {
+                if count + (start - c.start) / candle_ms > MAX_CANDLES {
+                    return Err(format!("more than {} candles of {}ms, narrow the range or widen candles", MAX_CANDLES, candle_ms));
+                }
+                let close = c.close;
+                let mut filled = c.start + candle_ms;
+                while filled < start {
+                    candles.push(Candle { start: filled, open: close, high: close, low: close, close });
+                    filled += candle_ms;
+                }
+                candles.push(Candle { start, open: price, high: price, low: price, close: price });
+            },
+            None => candles.push(Candle { start, open: price, high: price, low: price, close: price }),
+        }
+    }
+    Ok(candles)
+}
+
+// sma gives the simple moving average over period values,
+// None until period values are known
+pub fn sma(values: &[f64], period: usize) -> Vec<Option<f64>> {
+    let period = period.max(1);
+    let mut sum = 0.0;
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            sum += v;
+            if i >= period {
+                sum -= values[i - period];
+            }
+            Some(sum / period as f64).filter(|_| i + 1 >= period)
+        })
+        .collect()
+}
+
+// ema gives the exponential moving average over period values,
+// seeded with the sma of the first period values
+pub fn ema(values: &[f64], period: usize) -> Vec<Option<f64>> {
+    let period = period.max(1);
+    let alpha = 2.0 / (period as f64 + 1.0);
+    let mut prev: Option<f64> = None;
+    sma(values, period)
+        .into_iter()
+        .zip(values)
+        .map(|(seed, v)| {
+            prev = match prev {
+                Some(p) => Some(p + alpha * (v - p)),
+                None => seed,
+            };
+            prev
+        })
+        .collect()
+}
+
+// wilder smooths values the way RSI and ATR do, seeded with
+// the mean of the first period values
+fn wilder(values: &[f64], period: usize) -> Vec<Option<f64>> {
+    let period = period.max(1) as f64;
+    let mut prev: Option<f64> = None;
+    sma(values, period as usize)
+        .into_iter()
+        .zip(values)
+        .map(|(seed, v)| {
+            prev = match prev {
+                Some(p) => Some((p * (period - 1.0) + v) / period),
+                None => seed,
+            };
+            prev
+        })
+        .collect()
+}
+
+// rsi gives the relative strength index over period changes
+pub fn rsi(closes: &[f64], period: usize) -> Vec<Option<f64>> {
+    let changes: Vec<f64> = closes.windows(2).map(|w| w[1] - w[0]).collect();
+    let gains = wilder(&changes.iter().map(|c| c.max(0.0)).collect::<Vec<f64>>(), period);
+    let losses = wilder(&changes.iter().map(|c| (-c).max(0.0)).collect::<Vec<f64>>(), period);
+
+    let mut rsi = vec![None];
+    rsi.extend(gains.into_iter().zip(losses).map(|(g, l)| match (g, l) {
+        (Some(_), Some(l)) if l <= 0.0 => Some(100.0),
+        (Some(g), Some(l)) => Some(100.0 - 100.0 / (1.0 + g / l)),
+        _ => None,
+    }));
+    rsi.truncate(closes.len());
+    rsi
+}
+
+// macd gives the macd line, its signal and their difference
+pub fn macd(closes: &[f64], fast: usize, slow: usize, signal: usize) -> Vec<Option<(f64, f64, f64)>> {
+    let lines: Vec<Option<f64>> = ema(closes, fast)
+        .into_iter()
+        .zip(ema(closes, slow))
+        .map(|(f, s)| Some(f? - s?))
+        .collect();
+    // the signal is the ema of the defined part of the macd line
+    let skip = lines.iter().take_while(|l| l.is_none()).count();
+    let defined: Vec<f64> = lines.iter().skip(skip).flatten().copied().collect();
+    let signals = ema(&defined, signal);
+
+    lines
+        .iter()
+        .enumerate()
+        .map(|(i, l)| {
+            let line = (*l)?;
+            let signal = (*signals.get(i.checked_sub(skip)?)?)?;
+            Some((line, signal, line - signal))
+        })
+        .collect()
+}
+
+// bollinger gives the middle, upper and lower bands over period
+// closes, k standard deviations away from their sma
+pub fn bollinger(closes: &[f64], period: usize, k: f64) -> Vec<Option<(f64, f64, f64)>> {
+    let period = period.max(1);
+    sma(closes, period)
+        .into_iter()
+        .enumerate()
+        .map(|(i, mean)| {
+            let mean = mean?;
+            let window = &closes[i + 1 - period..=i];
+            let std = (window.iter().map(|c| (c - mean).powi(2)).sum::<f64>() / period as f64).sqrt();
+            Some((mean, mean + k * std, mean - k * std))
+        })
+        .collect()
+}
+
+// atr gives the average true range over period candles
+pub fn atr(candles: &[Candle], period: usize) -> Vec<Option<f64>> {
+    let ranges: Vec<f64> = candles
+        .iter()
+        .enumerate()
+        .map(|(i, c)| match i.checked_sub(1).map(|p| candles[p].close) {
+            Some(prev) => (c.high - c.low).max((c.high - prev).abs()).max((c.low - prev).abs()),
+            None => c.high - c.low,
+        })
+        .collect();
+    wilder(&ranges, period)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Indicator {
+    Sma,
+    Ema,
+    Rsi,
+    Macd,
+    Bollinger,
+    Atr,
+}
+
+impl Indicator {
+    fn default_period(&self) -> usize {
+        match self {
+            Indicator::Sma | Indicator::Ema | Indicator::Bollinger => 20,
+            Indicator::Rsi | Indicator::Atr => 14,
+            Indicator::Macd => MACD_SLOW,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Indicator::Sma => "sma",
+            Indicator::Ema => "ema",
+            Indicator::Rsi => "rsi",
+            Indicator::Macd => "macd",
+            Indicator::Bollinger => "bollinger",
+            Indicator::Atr => "atr",
+        }
+    }
+}
+
+impl FromStr for Indicator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sma" => Ok(Indicator::Sma),
+            "ema" => Ok(Indicator::Ema),
+            "rsi" => Ok(Indicator::Rsi),
+            "macd" => Ok(Indicator::Macd),
+            "bollinger" => Ok(Indicator::Bollinger),
+            "atr" => Ok(Indicator::Atr),
+            _ => Err(format!("unknown indicator {}, must be sma, ema, rsi, macd, bollinger or atr", s)),
+        }
+    }
+}
+
+// Spec is an indicator of the prices of a coin in a currency,
+// written indicator:coin:currency[:period]. MACD takes no period.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spec {
+    pub indicator: Indicator,
+    pub coin: String,
+    pub currency: String,
+    pub period: usize,
+}
+
+impl Spec {
+    pub fn new(indicator: Indicator, coin: &str, currency: &str, period: Option<usize>) -> Result<Self, String> {
+        let period = period.unwrap_or_else(|| indicator.default_period());
+        if period == 0 {
+            return Err("period must be greater than 0".into());
+        }
+        if coin.is_empty() || currency.is_empty() {
+            return Err("coin and currency must not be empty".into());
+        }
+        Ok(Self { indicator, coin: coin.to_string(), currency: currency.to_string(), period })
+    }
+
+    // warmup is the number of candles read before the first point,
+    // so exponential averages settle
+    fn warmup(&self) -> usize {
+        match self.indicator {
+            Indicator::Sma | Indicator::Bollinger => self.period,
+            Indicator::Ema | Indicator::Rsi | Indicator::Atr => self.period * 3,
+            Indicator::Macd => (MACD_SLOW + MACD_SIGNAL) * 3,
+        }
+    }
+
+    // values computes the indicator over candles, None
+    // where there aren't enough candles yet
+    fn values(&self, candles: &[Candle]) -> Vec<Option<HashMap<String, f64>>> {
+        let closes: Vec<f64> = candles.iter().map(|c| c.close).collect();
+        let single = |values: Vec<Option<f64>>| values
+            .into_iter()
+            .map(|v| v.map(|v| vec![("value".to_string(), v)].into_iter().collect()))
+            .collect();
+        let triple = |values: Vec<Option<(f64, f64, f64)>>, names: [&str; 3]| values
+            .into_iter()
+            .map(|v| v.map(|(a, b, c)| vec![
+                (names[0].to_string(), a),
+                (names[1].to_string(), b),
+                (names[2].to_string(), c),
+            ].into_iter().collect()))
+            .collect();
+
+        match self.indicator {
+            Indicator::Sma => single(sma(&closes, self.period)),
+            Indicator::Ema => single(ema(&closes, self.period)),
+            Indicator::Rsi => single(rsi(&closes, self.period)),
+            Indicator::Atr => single(atr(candles, self.period)),
+            Indicator::Macd => triple(macd(&closes, MACD_FAST, MACD_SLOW, MACD_SIGNAL), ["macd", "signal", "histogram"]),
+            Indicator::Bollinger => triple(bollinger(&closes, self.period, BOLLINGER_K), ["middle", "upper", "lower"]),
+        }
+    }
+}
+
+impl fmt::Display for Spec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.indicator {
+            Indicator::Macd => write!(f, "macd:{}:{}", self.coin, self.currency),
+            _ => write!(f, "{}:{}:{}:{}", self.indicator.name(), self.coin, self.currency, self.period),
+        }
+    }
+}
+
+impl FromStr for Spec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let period = match parts.get(3) {
+            Some(p) => Some(p.parse::<usize>().map_err(|err| format!("{}: period: {}", s, err))?),
+            None => None,
+        };
+        match parts.len() {
+            3 | 4 => Spec::new(parts[0].parse()?, parts[1], parts[2], period).map_err(|err| format!("{}: {}", s, err)),
+            _ => Err(format!("{}: expected indicator:coin:currency[:period]", s)),
+        }
+    }
+}
+
+// Jobs are the comma separated specs computed on schedule
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Jobs(pub Vec<Spec>);
+
+impl FromStr for Jobs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|j| j.trim())
+            .filter(|j| !j.is_empty())
+            .map(|j| j.parse())
+            .collect::<Result<Vec<Spec>, String>>()
+            .map(Jobs)
+    }
+}
+
+// Point is the value(s) of an indicator for the candle starting at
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Point {
+    pub id: String,
+    pub indicator: String,
+    pub coin: String,
+    pub currency: String,
+    pub candle_ms: i64,
+    pub at: i64,
+    pub values: HashMap<String, f64>,
+}
+
+// compute gives the points of spec over the candles of candle_ms
+// starting in [from, to), reading history from coll
+pub fn compute(coll: &impl Collection<Stack>, spec: &Spec, candle_ms: i64, from: i64, to: i64) -> Result<Vec<Point>, String> {
+    let read_from = from.saturating_sub(spec.warmup() as i64 * candle_ms);
+    let candles = candles(coll.find_range("created_at", read_from, to), &spec.coin, &spec.currency, candle_ms)?;
+    let name = spec.to_string();
+
+    Ok(spec.values(&candles)
+        .into_iter()
+        .zip(&candles)
+        .filter(|(_, c)| c.start >= from)
+        .filter_map(|(values, c)| values.map(|values| Point {
+            id: format!("{}:{}:{}", name, candle_ms, c.start),
+            indicator: name.to_owned(),
+            coin: spec.coin.to_owned(),
+            currency: spec.currency.to_owned(),
+            candle_ms,
+            at: c.start,
+            values,
+        }))
+        .collect())
+}
+
+// run_jobs upserts the points of every job over the last lookback
+// candles into points. Every job runs even if one fails,
+// the first error is returned along with the number of points saved.
+pub fn run_jobs(
+    history: &impl Collection<Stack>,
+    points: &impl Collection<Point>,
+    jobs: &Jobs,
+    candle_ms: i64,
+    lookback: usize,
+    now: i64,
+) -> Result<usize, String> {
+    let from = now - now.rem_euclid(candle_ms.max(1)) - lookback as i64 * candle_ms;
+    let mut saved = 0;
+    let mut first_err = None;
+
+    for spec in &jobs.0 {
+        let found = match compute(history, spec, candle_ms, from, now + 1) {
+            Ok(f) => f,
+            Err(err) => {
+                first_err.get_or_insert(format!("{}: {}", spec, err));
+                continue;
+            },
+        };
+        for point in found {
+            match points.save(point.id.to_owned(), &point) {
+                Ok(_) => saved += 1,
+                Err(err) => {
+                    first_err.get_or_insert(format!("{}: {}", spec, err));
+                },
+            }
+        }
+    }
+
+    match first_err {
+        Some(err) => Err(err),
+        None => Ok(saved),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::coin::{Coin, Stack};
+    use crate::database::{Collection, MemoryDB};
+    use super::{atr, bollinger, candles, compute, ema, macd, rsi, run_jobs, sma, Candle, Jobs, Point, Spec};
+
+    fn gen_stack(at: i64, usd: f32) -> Stack {
+        let mut coins = HashMap::new();
+        coins.insert("bitcoin".to_string(), Coin {
+            id: "bitcoin".into(),
+            symbol: "btc".into(),
+            prices: vec![("usd".to_string(), usd)].into_iter().collect(),
+            derived: vec![],
+            last_updated_at: None,
+        });
+        Stack { id: format!("coingecko:{}", at), coins, created_at: at, unchanged: vec![] }
+    }
+
+    fn close_to(a: Option<f64>, b: f64) -> bool {
+        a.map(|a| (a - b).abs() < 1e-6).unwrap_or(false)
+    }
+
+    #[test]
+    fn i_should_compute_moving_averages() {
+        let values = [1.0, 2.0, 3.0, 4.0, 5.0];
+        assert_eq!(sma(&values, 3), vec![None, None, Some(2.0), Some(3.0), Some(4.0)]);
+
+        let ema = ema(&values, 3);
+        assert_eq!(ema[1], None);
+        assert!(close_to(ema[2], 2.0));
+        assert!(close_to(ema[3], 3.0));
+
+        let bands = bollinger(&[2.0, 4.0, 2.0, 4.0], 2, 2.0);
+        assert_eq!(bands[0], None);
+        assert_eq!(bands[1], Some((3.0, 5.0, 1.0)));
+    }
+
+    #[test]
+    fn i_should_compute_oscillators() {
+        let rising: Vec<f64> = (0..40).map(|i| i as f64).collect();
+        let rsi = rsi(&rising, 14);
+        assert_eq!(rsi.len(), 40);
+        assert_eq!(rsi[13], None);
+        assert!(close_to(rsi[14], 100.0));
+
+        let zigzag: Vec<f64> = (0..40).map(|i| if i % 2 == 0 { 10.0 } else { 11.0 }).collect();
+        let last = super::rsi(&zigzag, 14)[39].unwrap();
+        assert!(last > 40.0 && last < 60.0);
+
+        let macd = macd(&rising, 12, 26, 9);
+        assert_eq!(macd[32], None);
+        let (line, signal, histogram) = macd[33].unwrap();
+        assert!((line - 7.0).abs() < 1e-6 && (signal - 7.0).abs() < 1e-6 && histogram.abs() < 1e-6);
+    }
+
+    #[test]
+    fn i_should_compute_atr_over_candles() {
+        let stacks = vec![gen_stack(0, 10.0), gen_stack(10, 12.0), gen_stack(60, 11.0), gen_stack(70, 15.0)];
+        let candles = candles(stacks.into_iter().map(Ok), "bitcoin", "usd", 60).unwrap();
+        assert_eq!(candles, vec![
+            Candle { start: 0, open: 10.0, high: 12.0, low: 10.0, close: 12.0 },
+            Candle { start: 60, open: 11.0, high: 15.0, low: 11.0, close: 15.0 },
+        ]);
+
+        assert_eq!(atr(&candles, 2), vec![None, Some(3.0)]);
+    }
+
+    #[test]
+    fn i_should_fill_flat_candles() {
+        let stacks = vec![gen_stack(0, 10.0), gen_stack(200, 12.0)];
+        let candles = candles(stacks.into_iter().map(Ok), "bitcoin", "usd", 60).unwrap();
+        assert_eq!(candles.iter().map(|c| (c.start, c.close)).collect::<Vec<(i64, f64)>>(), vec![(0, 10.0), (60, 10.0), (120, 10.0), (180, 12.0)]);
+        assert_eq!(candles[1], Candle { start: 60, open: 10.0, high: 10.0, low: 10.0, close: 10.0 });
+
+        let sparse = vec![gen_stack(0, 10.0), gen_stack(super::MAX_CANDLES * 60, 12.0)];
+        assert!(super::candles(sparse.into_iter().map(Ok), "bitcoin", "usd", 60).is_err());
+    }
+
+    #[test]
+    fn i_should_parse_specs() {
+        let spec: Spec = "rsi:bitcoin:usd".parse().unwrap();
+        assert_eq!(spec.period, 14);
+        assert_eq!(spec.to_string(), "rsi:bitcoin:usd:14");
+        assert_eq!("macd:bitcoin:usd".parse::<Spec>().unwrap().to_string(), "macd:bitcoin:usd");
+
+        assert!("rsi:bitcoin".parse::<Spec>().is_err());
+        assert!("rsi:bitcoin:usd:0".parse::<Spec>().is_err());
+        assert!("vwap:bitcoin:usd".parse::<Spec>().is_err());
+        assert_eq!("".parse::<Jobs>().unwrap(), Jobs(vec![]));
+        assert_eq!("sma:bitcoin:usd:5, ema:ethereum:eur".parse::<Jobs>().unwrap().0.len(), 2);
+    }
+
+    #[test]
+    fn i_should_persist_indicator_series() {
+        let db = MemoryDB::new();
+        let history = db.new_collection::<Stack>("price_history");
+        for i in 0..10 {
+            history.save(format!("{}", i), &gen_stack(i * 60, i as f32)).unwrap();
+        }
+
+        let spec: Spec = "sma:bitcoin:usd:3".parse().unwrap();
+        // warmup candles make the first point of the range defined
+        let found = compute(&history, &spec, 60, 300, 600).unwrap();
+        assert_eq!(found.iter().map(|p| p.at).collect::<Vec<i64>>(), vec![300, 360, 420, 480, 540]);
+        assert_eq!(found[0].values["value"], 4.0);
+
+        let points = db.new_collection::<Point>("indicator_history");
+        let jobs = Jobs(vec![spec]);
+        assert_eq!(run_jobs(&history, &points, &jobs, 60, 4, 599), Ok(5));
+        assert_eq!(run_jobs(&history, &points, &jobs, 60, 4, 599), Ok(5));
+        assert_eq!(points.find_all().unwrap().len(), 5);
+        assert!(points.find_one("sma:bitcoin:usd:3:60:540".into()).is_some());
+    }
+}
@@ -1,17 +1,145 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use serde::{Serialize, Deserialize};
 
 use crate::coin::Coin;
 use crate::database::Collection;
+use crate::window::Window;
+
+// PriceEntry is the prices of a coin observed at a tick from a provider
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct PriceEntry {
+    pub at: i64,
+    pub provider: String,
+    pub prices: HashMap<String, f32>,
+    // derived lists the currencies of prices converted from another one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub derived: Vec<String>,
+}
+
+// EMA_PERIOD is the number of ticks the ema of Stats weighs over
+const EMA_PERIOD: f64 = 12.0;
+
+// Stats are rolling statistics of the prices of a currency over a window,
+// sum and sum_sq letting them follow entries coming in and out of it
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct Stats {
+    pub count: usize,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f64,
+    pub std: f64,
+    // change_pct is the change in percent since the oldest price of the window
+    pub change_pct: Option<f32>,
+    pub ema: f64,
+    sum: f64,
+    sum_sq: f64,
+}
+
+impl Stats {
+    fn push(&mut self, price: f32) {
+        if self.count == 0 {
+            self.min = price;
+            self.max = price;
+            self.ema = price as f64;
+        } else {
+            self.min = self.min.min(price);
+            self.max = self.max.max(price);
+            self.ema += 2.0 / (EMA_PERIOD + 1.0) * (price as f64 - self.ema);
+        }
+        self.count += 1;
+        self.sum += price as f64;
+        self.sum_sq += (price as f64).powi(2);
+        self.settle();
+    }
+
+    // evict takes price out, telling if min or max are to be rescanned
+    fn evict(&mut self, price: f32) -> bool {
+        self.count -= 1;
+        self.sum -= price as f64;
+        self.sum_sq -= (price as f64).powi(2);
+        self.settle();
+        price <= self.min || price >= self.max
+    }
+
+    fn settle(&mut self) {
+        if self.count == 0 {
+            return;
+        }
+        let n = self.count as f64;
+        self.mean = self.sum / n;
+        self.std = (self.sum_sq / n - self.mean.powi(2)).max(0.0).sqrt();
+    }
+}
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(from = "StoredLatestCoinData")]
 pub struct LatestCoinData {
     pub id: String,
     pub symbol: String,
-    pub prices: Vec<HashMap<String, f32>>,
+    pub prices: Vec<PriceEntry>,
+    // stats are the statistics of the window by currency
+    pub stats: HashMap<String, Stats>,
     pub updated_at: i64,
     #[serde(skip)]
-    prices_max_len: usize,
+    window: Window,
+}
+
+// StoredEntry reads the entries of documents stored before
+// entries were timestamped, which only hold prices
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredEntry {
+    Entry(PriceEntry),
+    Legacy(HashMap<String, f32>),
+}
+
+// StoredLatestCoinData is any stored version of LatestCoinData
+#[derive(Deserialize)]
+struct StoredLatestCoinData {
+    id: String,
+    symbol: String,
+    prices: Vec<StoredEntry>,
+    // ticks held the tick of the last entries, before entries held it
+    #[serde(default)]
+    ticks: Vec<i64>,
+    #[serde(default)]
+    stats: HashMap<String, Stats>,
+    updated_at: i64,
+}
+
+impl From<StoredLatestCoinData> for LatestCoinData {
+    fn from(stored: StoredLatestCoinData) -> Self {
+        // ticks were the ticks of the last entries, unknown ones are 0
+        let offset = stored.prices.len().saturating_sub(stored.ticks.len());
+        let ticks = stored.ticks;
+        let prices = stored.prices
+            .into_iter()
+            .enumerate()
+            .map(|(i, entry)| match entry {
+                StoredEntry::Entry(e) => e,
+                StoredEntry::Legacy(prices) => PriceEntry {
+                    at: i.checked_sub(offset).and_then(|t| ticks.get(t)).copied().unwrap_or(0),
+                    provider: String::new(),
+                    prices,
+                    derived: vec![],
+                },
+            })
+            .collect();
+
+        let mut lcd = LatestCoinData {
+            id: stored.id,
+            symbol: stored.symbol,
+            prices,
+            stats: stored.stats,
+            updated_at: stored.updated_at,
+            window: Window::default(),
+        };
+        // documents stored before stats were kept compute them once
+        if lcd.stats.is_empty() {
+            lcd.rebuild_stats();
+        }
+        lcd
+    }
 }
 
 pub fn get_coin_latest_data(id: String, db: &impl Collection<LatestCoinData>) -> Option<LatestCoinData> {
@@ -19,28 +147,116 @@ pub fn get_coin_latest_data(id: String, db: &impl Collection<LatestCoinData>) ->
 }
 
 impl LatestCoinData {
-    pub fn new(id: String, symbol: String, prices_max_len: usize) -> LatestCoinData {
+    pub fn new(id: String, symbol: String, window: Window) -> LatestCoinData {
         LatestCoinData {
             id,
             updated_at: 0,
             symbol,
             prices: vec![],
-            prices_max_len,
+            stats: HashMap::new(),
+            window,
         }
     }
 
-    pub fn update_with_coin(&mut self, coin: Coin) {
-        if self.prices_max_len == 0 {
-            return;
+    // update_with_coin pushes the prices of coin observed at tick from
+    // provider, skipping a tick already in the window, then evicts the
+    // entries older than the window's max age or over its max length.
+    // Stats follow the pushed and evicted prices.
+    // Returns whether prices were pushed.
+    pub fn update_with_coin(&mut self, coin: Coin, at: i64, provider: &str) -> bool {
+        if self.window.max_len == Some(0) || self.prices.iter().any(|e| e.at == at) {
+            return false;
+        }
+        for (currency, price) in coin.prices.iter().filter(|(_, p)| p.is_finite()) {
+            self.stats.entry(currency.to_owned()).or_default().push(*price);
+        }
+        self.prices.push(PriceEntry { at, provider: provider.to_string(), prices: coin.prices, derived: coin.derived });
+
+        let mut evicted = vec![];
+        if let Some(max_age) = self.window.max_age_ms {
+            let (kept, old) = self.prices.drain(..).partition(|e| e.at > at - max_age);
+            self.prices = kept;
+            evicted = old;
+        }
+        if let Some(max_len) = self.window.max_len {
+            let over = self.prices.len().saturating_sub(max_len);
+            evicted.extend(self.prices.drain(..over));
+        }
+        self.evict_stats(&evicted);
+        true
+    }
+
+    // evict_stats takes the evicted entries out of stats, rescanning
+    // min and max only when an evicted price was one of them
+    fn evict_stats(&mut self, evicted: &[PriceEntry]) {
+        let mut rescan = HashSet::new();
+        for entry in evicted {
+            for (currency, price) in entry.prices.iter().filter(|(_, p)| p.is_finite()) {
+                if let Some(stats) = self.stats.get_mut(currency) {
+                    if stats.evict(*price) {
+                        rescan.insert(currency.to_owned());
+                    }
+                }
+            }
+        }
+        self.stats.retain(|_, s| s.count > 0);
+
+        for currency in rescan {
+            let mut prices = self.prices.iter().filter_map(|e| e.prices.get(&currency)).filter(|p| p.is_finite());
+            if let (Some(first), Some(stats)) = (prices.next(), self.stats.get_mut(&currency)) {
+                let (min, max) = prices.fold((*first, *first), |(min, max), p| (min.min(*p), max.max(*p)));
+                stats.min = min;
+                stats.max = max;
+            }
+        }
+        let currencies: Vec<String> = self.stats.keys().cloned().collect();
+        for currency in currencies {
+            let change = self.change_since_oldest(&currency);
+            if let Some(stats) = self.stats.get_mut(&currency) {
+                stats.change_pct = change;
+            }
         }
-        if self.prices.len() >= self.prices_max_len {
-            self.prices.remove(0);
+    }
+
+    // rebuild_stats computes stats from the entries of the window
+    fn rebuild_stats(&mut self) {
+        self.stats.clear();
+        for entry in &self.prices {
+            for (currency, price) in entry.prices.iter().filter(|(_, p)| p.is_finite()) {
+                self.stats.entry(currency.to_owned()).or_default().push(*price);
+            }
         }
-        self.prices.push(coin.prices);
+        self.evict_stats(&[]);
     }
 
-    pub fn set_prices_max_len(&mut self, p: usize) {
-        self.prices_max_len = p;
+    pub fn set_window(&mut self, window: Window) {
+        self.window = window;
+    }
+
+    // price_at gives the price in currency of the last entry observed at or before at
+    pub fn price_at(&self, currency: &str, at: i64) -> Option<f32> {
+        self.prices
+            .iter()
+            .rev()
+            .filter(|e| e.at <= at)
+            .find_map(|e| e.prices.get(currency).copied())
+    }
+
+    // price_minutes_ago gives the price in currency minutes before now
+    pub fn price_minutes_ago(&self, currency: &str, minutes: i64, now: i64) -> Option<f32> {
+        self.price_at(currency, now - minutes * 60_000)
+    }
+
+    // change_since_oldest gives the change in percent of the price in
+    // currency between the oldest and the latest entries holding it
+    pub fn change_since_oldest(&self, currency: &str) -> Option<f32> {
+        let mut prices = self.prices.iter().filter_map(|e| e.prices.get(currency));
+        let oldest = *prices.next()?;
+        let latest = *prices.next_back()?;
+        if oldest == 0.0 {
+            return None;
+        }
+        Some((latest - oldest) / oldest * 100.0)
     }
 }
 
@@ -50,55 +266,155 @@ mod tests {
     use std::collections::HashMap;
 
     use crate::coin::Coin;
+    use crate::window::Window;
     use super::LatestCoinData;
 
+    fn gen_coin(usd: Option<f32>) -> Coin {
+        let mut prices = HashMap::new();
+        if let Some(p) = usd {
+            prices.insert("usd".to_string(), p);
+        }
+        Coin {
+            id: "test".into(),
+            symbol: "t".into(),
+            prices,
+            derived: vec![],
+            last_updated_at: None,
+        }
+    }
+
     #[test]
     pub fn i_can_update_with_coin_if_empty_vec_prices() {
-        let mut lcd = LatestCoinData::new("test1".into(), "t1".into(), 1);
+        let mut lcd = LatestCoinData::new("test1".into(), "t1".into(), Window::count(1));
         let c = Coin {
             id: "test1".into(),
             symbol: "t1".into(),
             prices: HashMap::new(),
+            derived: vec![],
+            last_updated_at: None,
         };
-        lcd.update_with_coin(c.clone());
-        assert_eq!(c.prices, lcd.prices[0]);
+        lcd.update_with_coin(c.clone(), 1, "coingecko");
+        assert_eq!(c.prices, lcd.prices[0].prices);
+        assert_eq!(lcd.prices[0].provider, "coingecko");
         assert_eq!(lcd.prices.len(), 1);
     }
 
     #[test]
     pub fn i_can_replace_vec_prices_if_len_is_0() {
-        let mut lcd = LatestCoinData::new("test2".into(), "t2".into(), 0);
+        let mut lcd = LatestCoinData::new("test2".into(), "t2".into(), Window::count(0));
         let c = Coin {
             id: "test2".into(),
             symbol: "t2".into(),
             prices: HashMap::new(),
+            derived: vec![],
+            last_updated_at: None,
         };
-        lcd.update_with_coin(c.clone());
+        lcd.update_with_coin(c.clone(), 1, "coingecko");
         assert_eq!(lcd.prices.len(), 0);
     }
 
     #[test]
     pub fn i_can_replace_vec_prices_if_len_is_greater_or_equal() {
-        let mut lcd = LatestCoinData::new("test3".into(), "t3".into(), 2);
+        let mut lcd = LatestCoinData::new("test3".into(), "t3".into(), Window::count(2));
         let c = Coin {
             id: "test3_1".into(),
             symbol: "t3_1".into(),
             prices: HashMap::new(),
+            derived: vec![],
+            last_updated_at: None,
         };
-        lcd.update_with_coin(c.clone());
+        lcd.update_with_coin(c.clone(), 1, "coingecko");
         let c = Coin {
             id: "test3_2".into(),
             symbol: "t3_2".into(),
             prices: HashMap::new(),
+            derived: vec![],
+            last_updated_at: None,
         };
-        lcd.update_with_coin(c.clone());
+        lcd.update_with_coin(c.clone(), 2, "coingecko");
         let c = Coin {
             id: "test3_3".into(),
             symbol: "t3_3".into(),
             prices: HashMap::new(),
+            derived: vec![],
+            last_updated_at: None,
         };
-        lcd.update_with_coin(c.clone());
-        assert_eq!(c.prices, lcd.prices[1]);
+        lcd.update_with_coin(c.clone(), 3, "coingecko");
+        assert_eq!(c.prices, lcd.prices[1].prices);
         assert_eq!(lcd.prices.len(), 2);
+        assert_eq!(lcd.prices.iter().map(|e| e.at).collect::<Vec<i64>>(), vec![2, 3]);
+    }
+
+    #[test]
+    pub fn i_should_skip_ticks_already_in_window() {
+        let mut lcd = LatestCoinData::new("test4".into(), "t4".into(), Window::count(3));
+        assert!(lcd.update_with_coin(gen_coin(None), 1, "coingecko"));
+        assert!(!lcd.update_with_coin(gen_coin(None), 1, "coingecko"));
+        assert_eq!(lcd.prices.len(), 1);
+    }
+
+    #[test]
+    pub fn i_should_read_legacy_documents() {
+        let lcd: LatestCoinData = serde_json::from_str(
+            r#"{"id":"test5","symbol":"t5","prices":[{"usd":1.0},{"usd":2.0},{"usd":3.0}],"ticks":[20,30],"updated_at":0}"#
+        ).unwrap();
+        assert_eq!(lcd.prices.iter().map(|e| e.at).collect::<Vec<i64>>(), vec![0, 20, 30]);
+        assert_eq!(lcd.prices[2].prices["usd"], 3.0);
+
+        let json = serde_json::to_string(&lcd).unwrap();
+        let lcd: LatestCoinData = serde_json::from_str(&json).unwrap();
+        assert_eq!(lcd.prices[1].at, 20);
+    }
+
+    #[test]
+    pub fn i_should_query_prices_over_time() {
+        let mut lcd = LatestCoinData::new("test6".into(), "t6".into(), Window::count(4));
+        lcd.update_with_coin(gen_coin(Some(100.0)), 0, "coingecko");
+        lcd.update_with_coin(gen_coin(None), 60_000, "coingecko");
+        lcd.update_with_coin(gen_coin(Some(110.0)), 120_000, "coingecko");
+
+        assert_eq!(lcd.price_minutes_ago("usd", 1, 120_000), Some(100.0));
+        assert_eq!(lcd.price_minutes_ago("usd", 0, 120_000), Some(110.0));
+        assert_eq!(lcd.price_minutes_ago("usd", 3, 120_000), None);
+        assert!((lcd.change_since_oldest("usd").unwrap() - 10.0).abs() < 1e-4);
+        assert_eq!(lcd.change_since_oldest("eur"), None);
+    }
+
+    #[test]
+    pub fn i_should_evict_entries_older_than_the_window() {
+        let window = Window { max_len: Some(3), max_age_ms: Some(120_000) };
+        let mut lcd = LatestCoinData::new("test7".into(), "t7".into(), window);
+        for at in &[0, 60_000, 120_000, 180_000] {
+            lcd.update_with_coin(gen_coin(Some(1.0)), *at, "coingecko");
+        }
+        assert_eq!(lcd.prices.iter().map(|e| e.at).collect::<Vec<i64>>(), vec![120_000, 180_000]);
+
+        // a late tick doesn't outlive the window either
+        lcd.set_window(Window { max_len: None, max_age_ms: Some(60_000) });
+        lcd.update_with_coin(gen_coin(Some(1.0)), 300_000, "coingecko");
+        assert_eq!(lcd.prices.iter().map(|e| e.at).collect::<Vec<i64>>(), vec![300_000]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn i_should_keep_rolling_stats() {
+        let mut lcd = LatestCoinData::new("test8".into(), "t8".into(), Window::count(3));
+        for (at, usd) in [(0, 10.0), (1, 20.0), (2, 30.0), (3, 40.0)].iter() {
+            lcd.update_with_coin(gen_coin(Some(*usd)), *at, "coingecko");
+        }
+        let stats = &lcd.stats["usd"];
+        assert_eq!((stats.count, stats.min, stats.max), (3, 20.0, 40.0));
+        assert!((stats.mean - 30.0).abs() < 1e-9);
+        assert!((stats.std - (200.0f64 / 3.0).sqrt()).abs() < 1e-9);
+        assert!((stats.change_pct.unwrap() - 100.0).abs() < 1e-4);
+        assert!(stats.ema > 10.0 && stats.ema < 40.0);
+
+        // stats survive a round trip and are rebuilt for documents without them
+        let json = serde_json::to_string(&lcd).unwrap();
+        let stored: LatestCoinData = serde_json::from_str(&json).unwrap();
+        assert_eq!(&stored.stats["usd"], stats);
+        let legacy: LatestCoinData = serde_json::from_str(
+            r#"{"id":"test8","symbol":"t8","prices":[{"usd":2.0},{"usd":4.0}],"updated_at":0}"#
+        ).unwrap();
+        assert_eq!((legacy.stats["usd"].min, legacy.stats["usd"].max), (2.0, 4.0));
+    }
+}
@@ -0,0 +1,170 @@
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+
+use crate::database::Collection;
+
+// LEASE_ID is the id of the leader lease document
+pub const LEASE_ID: &str = "leader";
+
+// Lease is held by the leader until expires_at. token is bumped
+// on every change of holder, telling a former leader it lost the
+// lease. Writes don't carry it: it isn't a fencing token.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Lease {
+    pub id: String,
+    pub holder: String,
+    pub token: i64,
+    pub expires_at: i64,
+}
+
+// Election campaigns for the lease of a collection
+// on behalf of holder
+pub struct Election<C> {
+    coll: C,
+    holder: String,
+    ttl_ms: i64,
+    // token is the token of the held lease, if any
+    token: Option<i64>,
+}
+
+impl<C: Collection<Lease>> Election<C> {
+    pub fn new(coll: C, holder: &str, ttl_ms: i64) -> Self {
+        Self { coll, holder: holder.to_string(), ttl_ms, token: None }
+    }
+
+    pub fn token(&self) -> Option<i64> {
+        self.token
+    }
+
+    // campaign acquires, renews or takes over an expired lease at now.
+    // Returns the lease token while leader, None while another replica
+    // holds the lease, and an error if the lease can't be read or written.
+    pub fn campaign(&mut self, now: i64) -> Result<Option<i64>, String> {
+        let current = match self.coll.get(LEASE_ID.into()) {
+            Ok(c) => c,
+            Err(err) => {
+                self.step_down();
+                return Err(format!("could not read the lease: {}", err));
+            },
+        };
+        let (expected, token) = match &current {
+            None => (None, 1),
+            Some(lease) if Some(lease.token) == self.token && lease.holder == self.holder => (Some(lease.token), lease.token),
+            Some(lease) if lease.expires_at <= now => (Some(lease.token), lease.token + 1),
+            Some(_) => return Ok(self.step_down()),
+        };
+
+        let lease = Lease {
+            id: LEASE_ID.into(),
+            holder: self.holder.to_owned(),
+            token,
+            expires_at: now + self.ttl_ms,
+        };
+        match self.coll.save_if(LEASE_ID.into(), "token", expected, &lease) {
+            Ok(true) => {
+                if self.token != Some(token) {
+                    info!("{} is now leader with token {}", self.holder, token);
+                }
+                self.token = Some(token);
+                Ok(self.token)
+            },
+            Ok(false) => Ok(self.step_down()),
+            Err(err) => {
+                self.step_down();
+                Err(format!("could not campaign for the lease: {}", err))
+            },
+        }
+    }
+
+    // holds tells if the lease is still ours at now, to be checked
+    // right before writing. The check narrows the window in which a
+    // former leader writes, it doesn't close it: writes following the
+    // check aren't conditioned on the lease, and expiry compares
+    // expires_at with the local clock.
+    pub fn holds(&self, now: i64) -> bool {
+        match (self.token, self.coll.find_one(LEASE_ID.into())) {
+            (Some(token), Some(lease)) => lease.token == token && lease.holder == self.holder && lease.expires_at > now,
+            _ => false,
+        }
+    }
+
+    // resign expires a held lease, so a follower takes over without
+    // waiting for the ttl
+    pub fn resign(&mut self, now: i64) {
+        let token = match self.token.take() {
+            Some(t) => t,
+            None => return,
+        };
+        let lease = Lease { id: LEASE_ID.into(), holder: self.holder.to_owned(), token, expires_at: now };
+        if let Err(err) = self.coll.save_if(LEASE_ID.into(), "token", Some(token), &lease) {
+            warn!("Could not resign the lease: {}", err);
+        }
+    }
+
+    fn step_down(&mut self) -> Option<i64> {
+        if self.token.take().is_some() {
+            warn!("{} lost the lease", self.holder);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use crate::database::{Collection, MemoryDB};
+    use super::{Election, Lease, LEASE_ID};
+
+    #[test]
+    fn i_should_elect_a_single_leader() {
+        let db = MemoryDB::new();
+        let mut a = Election::new(db.new_collection::<Lease>("leases"), "a", 1000);
+        let mut b = Election::new(db.new_collection::<Lease>("leases"), "b", 1000);
+
+        assert_eq!(a.campaign(0), Ok(Some(1)));
+        assert_eq!(b.campaign(10), Ok(None));
+        assert_eq!(a.campaign(500), Ok(Some(1)));
+        assert!(a.holds(1400));
+        assert_eq!(b.campaign(1400), Ok(None));
+        assert!(!b.holds(1400));
+    }
+
+    #[test]
+    fn i_should_take_over_expired_leases() {
+        let db = MemoryDB::new();
+        let mut a = Election::new(db.new_collection::<Lease>("leases"), "a", 1000);
+        let mut b = Election::new(db.new_collection::<Lease>("leases"), "b", 1000);
+
+        assert_eq!(a.campaign(0), Ok(Some(1)));
+        assert_eq!(b.campaign(1000), Ok(Some(2)));
+        // the former leader sees it lost the lease
+        assert!(!a.holds(1001));
+        assert_eq!(a.campaign(1001), Ok(None));
+        assert_eq!(a.token(), None);
+    }
+
+    #[test]
+    fn i_should_hand_over_on_resign() {
+        let db = MemoryDB::new();
+        let mut a = Election::new(db.new_collection::<Lease>("leases"), "a", 1000);
+        let mut b = Election::new(db.new_collection::<Lease>("leases"), "b", 1000);
+
+        a.campaign(0).unwrap();
+        a.resign(100);
+        assert_eq!(b.campaign(100), Ok(Some(2)));
+    }
+
+    #[test]
+    fn i_should_not_follow_on_lease_errors() {
+        let db = MemoryDB::new();
+        let mut a = Election::new(db.new_collection::<Lease>("leases"), "a", 1000);
+        assert_eq!(a.campaign(0), Ok(Some(1)));
+
+        // an unreadable lease is neither held nor followed
+        db.new_collection::<Value>("leases")
+            .save(LEASE_ID.into(), &json!({"id": LEASE_ID, "holder": 1}))
+            .unwrap();
+        assert!(a.campaign(500).is_err());
+        assert_eq!(a.token(), None);
+    }
+}
@@ -1,164 +1,76 @@
 pub mod config;
 pub mod gecko;
 pub mod provider;
+pub mod provider_source;
+pub mod validation;
 pub mod coin;
 pub mod latest_coins_data;
+pub mod window;
+pub mod indicators;
+pub mod fx;
+pub mod portfolio;
+pub mod quality;
+pub mod gaps;
+pub mod fetch_status;
 pub mod database;
 pub mod coin_info;
-
+pub mod asset;
+pub mod discovery;
+pub mod health;
+pub mod leader;
+pub mod http;
+pub mod shutdown;
+pub mod watcher;
+pub mod cli;
+pub mod collector;
+pub mod commands;
+pub mod export;
+pub mod import;
+
+use std::{env, process};
+use cli::Command;
 use config::Config;
-use coin::Stack;
-use database::{Collection};
-use latest_coins_data::get_coin_latest_data;
-use provider::{Provide, Provider};
-use core::time;
-use std::thread;
-use mongodb::sync::{Client};
-use log::{info, warn, error};
-use chrono::Utc;
-
-use crate::coin_info::CoinInfo;
-use crate::latest_coins_data::LatestCoinData;
-use crate::database::MongoDB;
-const F: u32 = 4;
-const S: u64 = 64;
 
-// db_connection returns a MongoDB struct wrapping
-// around Mongo DB connector.
-fn db_connection (mongodb_uri: String) -> MongoDB {
-    let client = match Client::with_uri_str(&mongodb_uri) {
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let cli = match cli::parse(&args) {
         Ok(c) => c,
         Err(err) => {
-            error!("Could not establish connection to DB: {}", err);
-            panic!("{}", err);
+            eprintln!("{}\n\n{}", err, cli::USAGE);
+            process::exit(2);
         },
     };
-    
-    MongoDB::new(client.database("coins"))
-}
-
-// save_coins_stack stores a batch of trimmed coins in a single new
-// MongoDB Document
-fn save_coins_stack(coins: &Stack, db: &MongoDB) {
-    db.new_collection::<Stack>("price_history").insert(coins);
-}
-
-// save_latest_entries stores the x lasts (x = prices_max_len) into a single document
-// organized by currency id 
-fn save_latest_entries(coins: &Stack, db: &MongoDB, prices_max_len: usize) {
-    let coll = db.new_collection::<LatestCoinData>("latest_entries");
-
-    for c in coins.coins.iter() {
-        let coin = c.1.to_owned();
-        let mut latest_coins = match get_coin_latest_data(coin.id.to_owned(), &coll) {
-            Some(mut lcd) => {
-                lcd.set_prices_max_len(prices_max_len);
-                lcd
-            },
-            None => LatestCoinData::new(coin.id.to_owned(), coin.symbol.to_owned(), prices_max_len),
-        };
 
-        latest_coins.updated_at = Utc::now().timestamp_millis();
-        latest_coins.update_with_coin(coin);
-        coll.save(latest_coins.id.to_owned(), &latest_coins);
+    // validate-config on a given file does not need the config layers
+    if let Command::ValidateConfig { ref_file: Some(ref_file) } = &cli.command {
+        exit_on_error(commands::validate_config(ref_file));
+        return;
     }
-}
-
-fn should_update_providers(c_f: u32) -> bool {
-    c_f == F
-}
 
-fn update_coingecko_list_provider_routine(ref_file: &str, collection: impl Collection<CoinInfo>) -> Result<Provider, String> {
-    let coingecko = match provider::update_provider(ref_file, "coingecko") {
-        Some(c) => c,
-        _ => return Err("Could not find matching provider".to_owned()),
+    let config = Config::parse(&cli.config_args);
+    let res = match &cli.command {
+        Command::Run => {
+            collector::run(&config);
+            Ok(())
+        },
+        Command::FetchOnce => commands::fetch_once(&config),
+        Command::ValidateConfig { .. } => commands::validate_config(&config.providers.ref_file),
+        Command::Backfill { coins, from, to } => commands::backfill(&config, coins, *from, *to),
+        Command::Export { coins, from, to, format, output } => {
+            commands::export(&config, coins, *from, *to, *format, output.as_deref())
+        },
+        Command::Import { format, input } => commands::import(&config, *format, input.as_deref()),
+        Command::Gaps { coins, from, to, backfill } => commands::gaps(&config, coins, *from, *to, *backfill),
+        Command::Coins(action) => commands::coins(&config, action),
+        Command::PushProviders { ref_file } => commands::push_providers(&config, ref_file.as_deref()),
+        Command::Portfolio(action) => commands::portfolio(&config, action),
     };
-
-    for coin in coingecko.get_coins() {
-        collection.save(coin.0.to_owned(), &CoinInfo::new(coin.0, coin.1));
-    }
-
-    Ok(coingecko)
+    exit_on_error(res);
 }
 
-fn main() {
-    // so should_update_providers triggers straight away
-    let mut cur_f = 4;
-    let config = Config::parse();
-    
-    let db = db_connection(config.mongodb_uri);
-
-    let mut coingecko = match provider::update_provider(&config.ref_file, "coingecko") {
-        Some(c) => c,
-        _ => panic!("coingecko config in {} file must be provided", &config.ref_file),
-    };
-
-    let mut coins_cache = Stack::new();
-
-    loop {
-        if should_update_providers(cur_f) {
-            coingecko = match update_coingecko_list_provider_routine(
-                &config.ref_file,
-                db.new_collection::<CoinInfo>("coin_info"),
-            ) {
-                Ok(fresh_gecko) => fresh_gecko,
-                Err(err) => {
-                    warn!("{}", err);
-                    coingecko
-                },
-            };
-            cur_f = 0;
-        }
-
-        match gecko::simple_price(&coingecko) {
-            Ok(coins) => {
-                let trimmed_coins = coin::trim_nonupdated_coins(&coins_cache, &coins);
-                info!("{:?}", &trimmed_coins);
-
-                if trimmed_coins.coins.len() > 0 {
-                    save_coins_stack(&trimmed_coins, &db);
-                    save_latest_entries(&trimmed_coins, &db, config.prices_max_len)
-                }
-                coins_cache = coins;
-            },
-            Err(err) => {
-                warn!("{}", err);
-                continue
-            }
-    
-        };
-
-        info!("Going for a siesta for {}s", S);
-        thread::sleep(time::Duration::from_secs(S));
-        cur_f = cur_f + 1;
+fn exit_on_error(res: Result<(), String>) {
+    if let Err(err) = res {
+        eprintln!("{}", err);
+        process::exit(1);
     }
 }
-
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn should_test_should_update_providers() {
-        let mut it = 0;
-        let mut trial = false;
-
-        while it < super::F {
-            trial = super::should_update_providers(it);
-            it = it + 1;
-        }
-        assert_eq!(trial, false);
-
-        it = 0;
-        while it <= super::F {
-            trial = super::should_update_providers(it);
-            it = it + 1;
-        }
-        assert_eq!(trial, true);
-
-        it = 0;
-        while it <= 7 {
-            trial = super::should_update_providers(it);
-            it = it + 1;
-        }
-        assert_eq!(trial, false);
-    }
-}
\ No newline at end of file
@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+
+use crate::coin::Stack;
+use crate::database::Collection;
+
+// COLLECTION is the collection of portfolios
+pub const COLLECTION: &str = "portfolios";
+
+// LATEST_MAX_AGE_MS is how old the latest snapshot of a portfolio may be
+const LATEST_MAX_AGE_MS: i64 = 86_400_000;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Holding {
+    pub coin: String,
+    pub quantity: f64,
+}
+
+// Portfolio is a set of holdings, valued every tick
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Portfolio {
+    pub id: String,
+    pub holdings: Vec<Holding>,
+}
+
+impl Portfolio {
+    // parse reads holdings written coin=quantity,..
+    pub fn parse(id: &str, holdings: &str) -> Result<Self, String> {
+        let holdings = holdings
+            .split(',')
+            .filter(|h| !h.is_empty())
+            .map(|h| {
+                let (coin, quantity) = h.split_once('=').ok_or(format!("{}: expected coin=quantity", h))?;
+                let quantity = quantity.parse::<f64>().map_err(|err| format!("{}: {}", h, err))?;
+                if !quantity.is_finite() || quantity < 0.0 {
+                    return Err(format!("{}: quantity must be a positive number", h));
+                }
+                Ok(Holding { coin: coin.to_string(), quantity })
+            })
+            .collect::<Result<Vec<Holding>, String>>()?;
+        Ok(Self { id: id.to_string(), holdings })
+    }
+}
+
+// Snapshot is the value of a portfolio at a tick by currency, along
+// with the value of each holding. missing lists by currency the coins
+// the tick had no price of in that currency, left out of its value.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub id: String,
+    pub portfolio: String,
+    pub at: i64,
+    pub values: HashMap<String, f64>,
+    pub holdings: HashMap<String, HashMap<String, f64>>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub missing: HashMap<String, Vec<String>>,
+}
+
+impl Snapshot {
+    // is_partial tells if a holding has no price in currency
+    pub fn is_partial(&self, currency: &str) -> bool {
+        self.missing.get(currency).is_some_and(|coins| !coins.is_empty())
+    }
+}
+
+// value values portfolio in currencies with the prices of stack
+pub fn value(portfolio: &Portfolio, stack: &Stack, currencies: &[String]) -> Snapshot {
+    let mut values: HashMap<String, f64> = HashMap::new();
+    let mut holdings: HashMap<String, HashMap<String, f64>> = HashMap::new();
+    let mut missing: HashMap<String, Vec<String>> = HashMap::new();
+
+    for holding in &portfolio.holdings {
+        let coin = stack.coins.get(&holding.coin);
+        for currency in currencies {
+            match coin.and_then(|c| c.prices.get(currency)).filter(|p| p.is_finite()) {
+                Some(price) => {
+                    let held = holding.quantity * *price as f64;
+                    *values.entry(currency.to_owned()).or_default() += held;
+                    *holdings.entry(holding.coin.to_owned()).or_default().entry(currency.to_owned()).or_default() += held;
+                },
+                None => missing.entry(currency.to_owned()).or_default().push(holding.coin.to_owned()),
+            }
+        }
+    }
+
+    Snapshot {
+        id: format!("{}:{}", portfolio.id, stack.created_at),
+        portfolio: portfolio.id.to_owned(),
+        at: stack.created_at,
+        values,
+        holdings,
+        missing,
+    }
+}
+
+// value_all upserts the snapshot of every portfolio at the tick of stack.
+// Every portfolio is valued even if one fails, the first error is returned.
+pub fn value_all(
+    portfolios: &impl Collection<Portfolio>,
+    history: &impl Collection<Snapshot>,
+    stack: &Stack,
+    currencies: &[String],
+) -> Result<usize, String> {
+    let mut saved = 0;
+    let mut first_err = None;
+    for portfolio in portfolios.find_all()? {
+        let snapshot = value(&portfolio, stack, currencies);
+        match history.save(snapshot.id.to_owned(), &snapshot) {
+            Ok(_) => saved += 1,
+            Err(err) => {
+                first_err.get_or_insert(err);
+            },
+        }
+    }
+    match first_err {
+        Some(err) => Err(err),
+        None => Ok(saved),
+    }
+}
+
+// Pnl is the change of value of a portfolio since a snapshot
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Pnl {
+    pub since: i64,
+    pub start_value: f64,
+    pub change: f64,
+    pub change_pct: Option<f64>,
+}
+
+// Report is the latest value of a portfolio in a currency,
+// its P&L since a date and the allocation of its holdings in percent.
+// missing lists the holdings the latest value has no price of.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Report {
+    pub portfolio: String,
+    pub currency: String,
+    pub at: i64,
+    pub value: f64,
+    pub pnl: Option<Pnl>,
+    pub allocation: HashMap<String, f64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub missing: Vec<String>,
+}
+
+// allocation gives the share in percent of each holding in currency
+pub fn allocation(snapshot: &Snapshot, currency: &str) -> HashMap<String, f64> {
+    let total = snapshot.values.get(currency).copied().unwrap_or(0.0);
+    if total <= 0.0 {
+        return HashMap::new();
+    }
+    snapshot
+        .holdings
+        .iter()
+        .filter_map(|(coin, values)| values.get(currency).map(|v| (coin.to_owned(), v / total * 100.0)))
+        .collect()
+}
+
+// report builds the report of portfolio in currency from its latest
+// snapshot at now and, if since is given, its first snapshot from since.
+// P&L compares full snapshots only: none is given if the latest one
+// misses a price in currency, and partial start snapshots are skipped.
+pub fn report(history: &impl Collection<Snapshot>, portfolio: &str, currency: &str, since: Option<i64>, now: i64) -> Result<Report, String> {
+    let mut latest = None;
+    for snapshot in history.find_range("at", now - LATEST_MAX_AGE_MS, now + 1) {
+        let snapshot = snapshot?;
+        if snapshot.portfolio == portfolio {
+            latest = Some(snapshot);
+        }
+    }
+    let latest = latest.ok_or(format!("no recent snapshot of portfolio {}", portfolio))?;
+    let value = *latest
+        .values
+        .get(currency)
+        .ok_or(format!("portfolio {} isn't valued in {}", portfolio, currency))?;
+
+    let mut pnl = None;
+    if let Some(since) = since.filter(|_| !latest.is_partial(currency)) {
+        for start in history.find_range("at", since, now + 1) {
+            let start = start?;
+            if start.portfolio != portfolio || !start.values.contains_key(currency) || start.is_partial(currency) {
+                continue;
+            }
+            let start_value = start.values[currency];
+            pnl = Some(Pnl {
+                since: start.at,
+                start_value,
+                change: value - start_value,
+                change_pct: Some((value - start_value) / start_value * 100.0).filter(|_| start_value != 0.0),
+            });
+            break;
+        }
+    }
+
+    Ok(Report {
+        portfolio: portfolio.to_string(),
+        currency: currency.to_string(),
+        at: latest.at,
+        value,
+        pnl,
+        allocation: allocation(&latest, currency),
+        missing: latest.missing.get(currency).cloned().unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coin::{Coin, Stack};
+    use crate::database::{Collection, MemoryDB};
+    use super::{report, value, value_all, Portfolio, Snapshot};
+
+    fn gen_stack(at: i64, btc: f32, eth: f32) -> Stack {
+        let mut stack = Stack::new();
+        stack.created_at = at;
+        for (id, usd) in &[("bitcoin", btc), ("ethereum", eth)] {
+            stack.coins.insert(id.to_string(), Coin {
+                id: id.to_string(),
+                symbol: id[..3].to_string(),
+                prices: vec![("usd".to_string(), *usd)].into_iter().collect(),
+                derived: vec![],
+                last_updated_at: None,
+            });
+        }
+        stack
+    }
+
+    #[test]
+    fn i_should_parse_holdings() {
+        let portfolio = Portfolio::parse("main", "bitcoin=0.5,ethereum=2").unwrap();
+        assert_eq!(portfolio.holdings.len(), 2);
+        assert_eq!(portfolio.holdings[1].quantity, 2.0);
+        assert!(Portfolio::parse("main", "bitcoin").is_err());
+        assert!(Portfolio::parse("main", "bitcoin=-1").is_err());
+    }
+
+    #[test]
+    fn i_should_value_portfolios() {
+        let portfolio = Portfolio::parse("main", "bitcoin=0.5,ethereum=2,dogecoin=100").unwrap();
+        let currencies = vec!["usd".to_string(), "eur".to_string()];
+        let snapshot = value(&portfolio, &gen_stack(1000, 40000.0, 2000.0), &currencies);
+
+        assert_eq!(snapshot.id, "main:1000");
+        assert_eq!(snapshot.values["usd"], 24000.0);
+        assert_eq!(snapshot.values.get("eur"), None);
+        assert_eq!(snapshot.holdings["ethereum"]["usd"], 4000.0);
+        assert_eq!(snapshot.missing["usd"], vec!["dogecoin".to_string()]);
+        assert_eq!(snapshot.missing["eur"], vec!["bitcoin".to_string(), "ethereum".to_string(), "dogecoin".to_string()]);
+    }
+
+    #[test]
+    fn i_should_report_pnl_and_allocation() {
+        let db = MemoryDB::new();
+        let portfolios = db.new_collection::<Portfolio>("portfolios");
+        let history = db.new_collection::<Snapshot>("portfolio_history");
+        portfolios.save("main".into(), &Portfolio::parse("main", "bitcoin=1,ethereum=10").unwrap()).unwrap();
+
+        let currencies = vec!["usd".to_string()];
+        assert_eq!(value_all(&portfolios, &history, &gen_stack(1000, 30000.0, 1000.0), &currencies), Ok(1));
+        assert_eq!(value_all(&portfolios, &history, &gen_stack(2000, 30000.0, 3000.0), &currencies), Ok(1));
+
+        let report = report(&history, "main", "usd", Some(500), 2500).unwrap();
+        assert_eq!(report.value, 60000.0);
+        let pnl = report.pnl.unwrap();
+        assert_eq!((pnl.since, pnl.start_value, pnl.change), (1000, 40000.0, 20000.0));
+        assert_eq!(pnl.change_pct, Some(50.0));
+        assert_eq!(report.allocation["bitcoin"], 50.0);
+
+        assert!(super::report(&history, "main", "eur", None, 2500).is_err());
+        assert!(super::report(&history, "other", "usd", None, 2500).is_err());
+    }
+
+    #[test]
+    fn i_should_skip_partial_snapshots_in_pnl() {
+        let db = MemoryDB::new();
+        let portfolios = db.new_collection::<Portfolio>("portfolios");
+        let history = db.new_collection::<Snapshot>("portfolio_history");
+        portfolios.save("main".into(), &Portfolio::parse("main", "bitcoin=1,ethereum=10").unwrap()).unwrap();
+
+        let currencies = vec!["usd".to_string()];
+        let mut partial = gen_stack(1000, 30000.0, 1000.0);
+        partial.coins.remove("ethereum");
+        assert_eq!(value_all(&portfolios, &history, &partial, &currencies), Ok(1));
+        assert_eq!(value_all(&portfolios, &history, &gen_stack(2000, 30000.0, 2000.0), &currencies), Ok(1));
+        assert_eq!(value_all(&portfolios, &history, &gen_stack(3000, 30000.0, 3000.0), &currencies), Ok(1));
+
+        let report = report(&history, "main", "usd", Some(500), 3500).unwrap();
+        assert_eq!(report.pnl.unwrap().since, 2000);
+        assert!(report.missing.is_empty());
+
+        let mut partial = gen_stack(4000, 30000.0, 3000.0);
+        partial.coins.get_mut("ethereum").unwrap().prices.clear();
+        assert_eq!(value_all(&portfolios, &history, &partial, &currencies), Ok(1));
+        let report = super::report(&history, "main", "usd", Some(500), 4500).unwrap();
+        assert_eq!(report.value, 30000.0);
+        assert_eq!(report.pnl, None);
+        assert_eq!(report.missing, vec!["ethereum".to_string()]);
+    }
+}
@@ -1,7 +1,12 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::{fs, io::Error};
-use log::{info, error};
+use std::{fmt, fs, io::Error};
+
+use crate::discovery::Discovery;
+use crate::fx::FxProvider;
+use crate::provider_source::{self, TomlFile};
+use crate::validation::Diagnostic;
+use crate::window::WindowPolicy;
 
 // Provide defines a Provider behavior
 pub trait Provide {
@@ -14,13 +19,21 @@ pub trait Provide {
         .collect::<Vec<String>>()
         .join(",")
     }
+    // get_coins_chunks splits coins ids in comma separated
+    // lists of at most size ids
+    fn get_coins_chunks(&self, size: usize) -> Vec<String> {
+        self.get_coins()
+        .keys()
+        .map(|s: &String| s.as_str())
+        .collect::<Vec<&str>>()
+        .chunks(size.max(1))
+        .map(|chunk| chunk.join(","))
+        .collect()
+    }
     fn get_base_route(&self) -> &String;
     fn get_routes(&self) -> &HashMap<String, String>;
     fn get_uri(&self, route: &str) -> Option<String> {
-        match self.get_routes().get(route) {
-            Some(r) => Some(self.get_base_route().to_owned() + r),
-            None => None,
-        }
+        self.get_routes().get(route).map(|r| self.get_base_route().to_owned() + r)
     }
     fn get_currencies(&self) -> &Vec<String>;
     fn get_currencies_string(&self) -> String {
@@ -30,13 +43,60 @@ pub trait Provide {
 
 // Provider is the definition of a service that should be
 // deserialized from config
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Provider {
     name: String,
+    // coins can be left empty if discovery is enabled
+    #[serde(default)]
     coins: HashMap<String, String>,
     base_route: String,
     routes: HashMap<String, String>,
     currencies: Vec<String>,
+    discovery: Option<Discovery>,
+    // base_currency, if set, is the only currency prices are requested in,
+    // the other currencies being derived with the rates fx_provider stored
+    #[serde(default)]
+    base_currency: Option<String>,
+    #[serde(default)]
+    fx_provider: Option<String>,
+    // windows bound the latest_entries windows per coin or group of coins
+    #[serde(default)]
+    windows: Vec<WindowPolicy>,
+}
+
+impl Provider {
+    // get_discovery gives the discovery rules, if enabled
+    pub fn get_discovery(&self) -> Option<&Discovery> {
+        self.discovery.as_ref().filter(|d| d.enabled)
+    }
+
+    pub fn get_base_currency(&self) -> Option<&String> {
+        self.base_currency.as_ref()
+    }
+
+    pub fn get_fx_provider(&self) -> Option<&String> {
+        self.fx_provider.as_ref()
+    }
+
+    // get_quoted_currencies_string gives the currencies prices are requested in
+    pub fn get_quoted_currencies_string(&self) -> String {
+        match &self.base_currency {
+            Some(base) => base.to_owned(),
+            None => self.get_currencies_string(),
+        }
+    }
+
+    pub fn get_windows(&self) -> &[WindowPolicy] {
+        &self.windows
+    }
+
+    // merge_coins adds coins to the configured ones,
+    // configured symbols taking precedence
+    pub fn merge_coins(&mut self, coins: HashMap<String, String>) {
+        for (id, symbol) in coins {
+            self.coins.entry(id).or_insert(symbol);
+        }
+    }
 }
 
 impl Provide for Provider {
@@ -61,38 +121,99 @@ impl Provide for Provider {
     }
 }
 
+// Diff lists what changed between two versions of a provider
+#[derive(Debug, Default, PartialEq)]
+pub struct Diff {
+    pub added_coins: Vec<String>,
+    pub removed_coins: Vec<String>,
+    pub added_currencies: Vec<String>,
+    pub removed_currencies: Vec<String>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.added_coins.is_empty()
+            && self.removed_coins.is_empty()
+            && self.added_currencies.is_empty()
+            && self.removed_currencies.is_empty()
+    }
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parts = [
+            ("+coins", &self.added_coins),
+            ("-coins", &self.removed_coins),
+            ("+currencies", &self.added_currencies),
+            ("-currencies", &self.removed_currencies),
+        ];
+        let found: Vec<String> = parts
+            .iter()
+            .filter(|(_, l)| !l.is_empty())
+            .map(|(name, l)| format!("{} {}", name, l.join(",")))
+            .collect();
+        match found.is_empty() {
+            true => write!(f, "no changes"),
+            false => write!(f, "{}", found.join(" ")),
+        }
+    }
+}
+
+// diff compares the coins and currencies of old and new
+pub fn diff(old: &impl Provide, new: &impl Provide) -> Diff {
+    // missing gives the sorted items of a not in b
+    let missing = |a: &[&String], b: &[&String]| -> Vec<String> {
+        let mut found: Vec<String> = a.iter().filter(|x| !b.contains(x)).map(|x| x.to_string()).collect();
+        found.sort();
+        found
+    };
+    let old_coins: Vec<&String> = old.get_coins().keys().collect();
+    let new_coins: Vec<&String> = new.get_coins().keys().collect();
+    let old_currencies: Vec<&String> = old.get_currencies().iter().collect();
+    let new_currencies: Vec<&String> = new.get_currencies().iter().collect();
+
+    Diff {
+        added_coins: missing(&new_coins, &old_coins),
+        removed_coins: missing(&old_coins, &new_coins),
+        added_currencies: missing(&new_currencies, &old_currencies),
+        removed_currencies: missing(&old_currencies, &new_currencies),
+    }
+}
+
 #[derive(Deserialize)]
 struct Providers {
     providers: HashMap<String, Provider>
 }
 
+// parse_toml reads the providers of a config toml content
+pub fn parse_toml(content: &str) -> Result<HashMap<String, Provider>, toml::de::Error> {
+    let plist: Providers = toml::from_str(content)?;
+    Ok(plist.providers)
+}
+
+#[derive(Deserialize)]
+struct FxProviders {
+    #[serde(default)]
+    fx_providers: HashMap<String, FxProvider>,
+}
+
+// parse_fx_toml reads the fx providers of a config toml content
+pub fn parse_fx_toml(content: &str) -> Result<HashMap<String, FxProvider>, toml::de::Error> {
+    let plist: FxProviders = toml::from_str(content)?;
+    Ok(plist.fx_providers)
+}
+
 // list_from_toml generates a providers list from
 // a config toml file.
 pub fn list_from_toml(filepath: String) -> Result<HashMap<String, Provider>, Error> {
-    let content = match fs::read_to_string(filepath) {
-        Ok(c) => c,
-        Err(err) => return Err(err),
-    };
-
-    let plist: Providers = match toml::from_str(&content) {
-        Ok(r) => r,
-        Err(err) => return Err(err.into()),
-    };
-    Ok(plist.providers)
+    let content = fs::read_to_string(filepath)?;
+    Ok(parse_toml(&content)?)
 }
 
-pub fn update_provider(ref_file: &str, provider_name: &str) -> Option<Provider> {
-    info!("Update provider {} requested", provider_name);
-    match list_from_toml(ref_file.to_string()) {
-        Ok(p) => match p.get(provider_name) {
-            Some(mp) => return Some(mp.to_owned()),
-            _ => None,
-        },
-        Err(err) => {
-            error!("update_provider: {:?}", err);
-            None
-        },
-    }
+// update_provider loads provider_name from ref_file once the whole
+// file is validated. Warnings are logged, errors are returned.
+pub fn update_provider(ref_file: &str, provider_name: &str) -> Result<Provider, Vec<Diagnostic>> {
+    provider_source::load_provider(&TomlFile::new(ref_file), provider_name)
 }
 
 #[cfg(test)]
@@ -118,6 +239,61 @@ mod tests {
         assert_eq!(trial.get("test1").unwrap().get_uri("ping").unwrap(), "https://api.coingecko.com/api/v3/ping");
     }
 
+    #[test]
+    fn i_should_split_coins_in_chunks() {
+        use super::Provide;
+
+        let trial = super::update_provider("./test/providers-test-1.toml", "test1").unwrap();
+        let chunks = trial.get_coins_chunks(4);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].split(',').count(), 4);
+        assert_eq!(chunks[1].split(',').count(), 2);
+        assert_eq!(trial.get_coins_chunks(0).len(), 6);
+    }
+
+    #[test]
+    fn i_should_parse_discovery_rules() {
+        let mut trial = super::update_provider("./test/providers-test-3.toml", "test3").unwrap();
+        let discovery = trial.get_discovery().unwrap();
+        assert_eq!(discovery.top_n, Some(50));
+        assert_eq!(discovery.symbol_pattern.as_deref(), Some("*"));
+        assert!(trial.coins.is_empty());
+
+        let mut coins = std::collections::HashMap::new();
+        coins.insert("bitcoin".to_string(), "btc".to_string());
+        trial.merge_coins(coins);
+        assert_eq!(trial.coins.get("bitcoin").unwrap(), "btc");
+
+        let trial = super::update_provider("./test/providers-test-1.toml", "test1").unwrap();
+        assert!(trial.get_discovery().is_none());
+        assert!(trial.get_windows().is_empty());
+    }
+
+    #[test]
+    fn i_should_parse_window_policies() {
+        let trial = super::update_provider("./test/providers-test-3.toml", "test3").unwrap();
+        let windows = trial.get_windows();
+        assert_eq!(windows.len(), 2);
+        assert_eq!(windows[0].coins, vec!["bitcoin".to_string(), "wrapped-*".to_string()]);
+        assert_eq!(windows[0].max_age_secs, Some(86400));
+        assert_eq!(windows[1].max_len, Some(2));
+        assert!(windows[1].coins.is_empty());
+    }
+
+    #[test]
+    fn i_should_diff_providers() {
+        let old = super::update_provider("./test/providers-test-1.toml", "test1").unwrap();
+        let new = super::update_provider("./test/providers-test-3.toml", "test3").unwrap();
+        let diff = super::diff(&old, &new);
+
+        assert!(diff.added_coins.is_empty());
+        assert_eq!(diff.removed_coins.len(), 6);
+        assert_eq!(diff.removed_currencies, vec!["btc".to_string(), "eth".to_string()]);
+        assert!(super::diff(&old, &old).is_empty());
+        assert_eq!(super::diff(&old, &old).to_string(), "no changes");
+        assert_eq!(super::diff(&new, &old).to_string().split(' ').count(), 4);
+    }
+
     #[test]
     fn i_should_update_provider_multiple_times() {
         let mut trial = super::update_provider("./test/providers-test-1.toml", "test1").unwrap();
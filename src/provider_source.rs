@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::fs;
+use std::time::UNIX_EPOCH;
+use chrono::Utc;
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+
+use crate::config::{Config, ProvidersSource};
+use crate::database::{Collection, MongoDB};
+use crate::fx::FxProvider;
+use crate::provider::Provider;
+use crate::validation::{self, Diagnostic, Report, Severity};
+
+// DOCUMENT_ID is the id of the providers document of a collection
+pub const DOCUMENT_ID: &str = "providers";
+
+// ProviderSource is where providers definitions are read from
+pub trait ProviderSource {
+    // describe names the source in logs and diagnostics
+    fn describe(&self) -> String;
+    // version identifies the current definitions, a different
+    // version meaning providers should be reloaded
+    fn version(&self) -> Option<String>;
+    fn load(&self) -> Result<Report, Vec<Diagnostic>>;
+}
+
+// TomlFile reads providers from a TOML file,
+// its modification time being its version
+pub struct TomlFile {
+    path: String,
+}
+
+impl TomlFile {
+    pub fn new(path: &str) -> Self {
+        Self { path: path.to_string() }
+    }
+}
+
+impl ProviderSource for TomlFile {
+    fn describe(&self) -> String {
+        self.path.to_owned()
+    }
+
+    fn version(&self) -> Option<String> {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok()?;
+        modified.duration_since(UNIX_EPOCH).ok().map(|d| d.as_nanos().to_string())
+    }
+
+    fn load(&self) -> Result<Report, Vec<Diagnostic>> {
+        validation::validate_file(&self.path)
+    }
+}
+
+// ProvidersDocument holds the providers definitions in a collection,
+// version being bumped on every change
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ProvidersDocument {
+    pub id: String,
+    pub version: i64,
+    pub updated_at: i64,
+    pub providers: HashMap<String, Provider>,
+    #[serde(default)]
+    pub fx_providers: HashMap<String, FxProvider>,
+}
+
+// CollectionSource reads providers from the document of a collection
+// shared by every replica
+pub struct CollectionSource<C> {
+    coll: C,
+    name: String,
+}
+
+impl<C: Collection<ProvidersDocument>> CollectionSource<C> {
+    pub fn new(coll: C, name: &str) -> Self {
+        Self { coll, name: name.to_string() }
+    }
+}
+
+impl<C: Collection<ProvidersDocument>> ProviderSource for CollectionSource<C> {
+    fn describe(&self) -> String {
+        format!("{} collection", self.name)
+    }
+
+    fn version(&self) -> Option<String> {
+        self.coll.find_one(DOCUMENT_ID.into()).map(|d| d.version.to_string())
+    }
+
+    fn load(&self) -> Result<Report, Vec<Diagnostic>> {
+        match self.coll.find_one(DOCUMENT_ID.into()) {
+            Some(doc) => validation::check(doc.providers, doc.fx_providers),
+            None => Err(vec![Diagnostic {
+                severity: Severity::Error,
+                line: None,
+                provider: None,
+                message: format!("no {} document", DOCUMENT_ID),
+            }]),
+        }
+    }
+}
+
+// from_config gives the providers source config points to
+pub fn from_config(config: &Config, db: &MongoDB) -> Box<dyn ProviderSource> {
+    match config.providers.source {
+        ProvidersSource::File => Box::new(TomlFile::new(&config.providers.ref_file)),
+        ProvidersSource::Database => Box::new(CollectionSource::new(
+            db.new_collection::<ProvidersDocument>(&config.providers.collection),
+            &config.providers.collection,
+        )),
+    }
+}
+
+// load_provider loads provider_name from source once all of its
+// providers are validated. Warnings are logged, errors are returned.
+pub fn load_provider(source: &dyn ProviderSource, provider_name: &str) -> Result<Provider, Vec<Diagnostic>> {
+    info!("Update provider {} requested", provider_name);
+    let report = source.load()?;
+    for warning in &report.warnings {
+        warn!("{}: {}", source.describe(), warning);
+    }
+
+    match report.providers.get(provider_name) {
+        Some(p) => Ok(p.to_owned()),
+        None => Err(vec![Diagnostic {
+            severity: Severity::Error,
+            line: None,
+            provider: Some(provider_name.to_string()),
+            message: "no such provider".into(),
+        }]),
+    }
+}
+
+// load_fx_providers loads the fx providers of source once all of
+// its providers are validated
+pub fn load_fx_providers(source: &dyn ProviderSource) -> Result<HashMap<String, FxProvider>, Vec<Diagnostic>> {
+    source.load().map(|report| report.fx_providers)
+}
+
+// publish validates providers and stores them in coll,
+// bumping the version so replicas reload them. Returns the new version.
+pub fn publish(
+    coll: &impl Collection<ProvidersDocument>,
+    providers: HashMap<String, Provider>,
+    fx_providers: HashMap<String, FxProvider>,
+) -> Result<i64, Vec<Diagnostic>> {
+    let report = validation::check(providers, fx_providers)?;
+    let version = coll.find_one(DOCUMENT_ID.into()).map(|d| d.version).unwrap_or(0) + 1;
+    let doc = ProvidersDocument {
+        id: DOCUMENT_ID.into(),
+        version,
+        updated_at: Utc::now().timestamp_millis(),
+        providers: report.providers,
+        fx_providers: report.fx_providers,
+    };
+
+    coll.save(DOCUMENT_ID.into(), &doc).map_err(|err| vec![Diagnostic {
+        severity: Severity::Error,
+        line: None,
+        provider: None,
+        message: err,
+    }])?;
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::database::MemoryDB;
+    use crate::provider::Provide;
+    use super::{load_fx_providers, load_provider, publish, CollectionSource, ProviderSource, ProvidersDocument, TomlFile};
+
+    #[test]
+    fn i_should_load_providers_from_a_collection() {
+        let file = TomlFile::new("./test/providers-test-1.toml");
+        let source = CollectionSource::new(MemoryDB::new().new_collection::<ProvidersDocument>("providers"), "providers");
+        assert!(source.version().is_none());
+        assert!(source.load().is_err());
+        assert!(file.version().is_some());
+
+        let providers = file.load().ok().unwrap().providers;
+        assert_eq!(publish(&source.coll, providers.clone(), HashMap::new()), Ok(1));
+        assert_eq!(publish(&source.coll, providers, HashMap::new()), Ok(2));
+        assert_eq!(source.version().as_deref(), Some("2"));
+
+        let trial = load_provider(&source, "test1").unwrap();
+        assert_eq!(trial.get_uri("ping").unwrap(), "https://api.coingecko.com/api/v3/ping");
+        assert!(load_provider(&source, "nope").is_err());
+        assert!(load_fx_providers(&source).unwrap().is_empty());
+
+        let report = TomlFile::new("./test/providers-test-3.toml").load().ok().unwrap();
+        assert_eq!(publish(&source.coll, report.providers, report.fx_providers), Ok(3));
+        assert!(load_fx_providers(&source).unwrap().contains_key("ecb"));
+    }
+
+    #[test]
+    fn i_should_not_publish_invalid_providers() {
+        let coll = MemoryDB::new().new_collection::<ProvidersDocument>("providers");
+        let mut providers = TomlFile::new("./test/providers-test-2.toml").load().ok().unwrap().providers;
+        let test2 = providers.get_mut("test2").unwrap();
+        test2.merge_coins(vec![("bit coin".to_string(), "btc2".to_string())].into_iter().collect());
+
+        assert!(publish(&coll, providers, HashMap::new()).is_err());
+        assert!(CollectionSource::new(coll, "providers").version().is_none());
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+
+use crate::coin::{Coin, Stack};
+use crate::database::Collection;
+
+// Reason tells why a price was quarantined
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Reason {
+    // Invalid prices are zero, negative or not a number
+    Invalid,
+    // Jump prices moved more than max_jump_pct from the cached price
+    Jump,
+    // Stale prices weren't updated by the provider for max_staleness_secs
+    Stale,
+}
+
+// Rules are the thresholds of the quality stage, 0 disabling a check
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rules {
+    pub max_jump_pct: f64,
+    pub max_staleness_secs: i64,
+}
+
+// Quarantined is a price held out of a tick for review. A stale
+// coin is quarantined as a whole, without currency nor price.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Quarantined {
+    pub id: String,
+    pub provider: String,
+    pub coin: String,
+    pub currency: Option<String>,
+    pub price: Option<f32>,
+    pub previous: Option<f32>,
+    pub last_updated_at: Option<i64>,
+    pub reason: Reason,
+    pub at: i64,
+}
+
+// screen takes the bad prices of stack out, comparing them to the
+// last good prices of cache. Coins left without prices are dropped.
+// A jump within max_jump_pct of the price pending, quarantined the
+// tick before, is confirmed and passes, while a one-off spike, or a
+// second different one, never reaches storage.
+pub fn screen(provider: &str, mut stack: Stack, cache: &Stack, pending: &[Quarantined], rules: &Rules, now: i64) -> (Stack, Vec<Quarantined>) {
+    let mut quarantined = vec![];
+    let (stack_id, at) = (stack.id.to_owned(), stack.created_at);
+    let quarantine = |coin: &str, currency: Option<&str>, price, previous, last_updated_at, reason| Quarantined {
+        id: format!("{}:{}:{}", stack_id, coin, currency.unwrap_or("*")),
+        provider: provider.to_string(),
+        coin: coin.to_string(),
+        currency: currency.map(|c| c.to_string()),
+        price,
+        previous,
+        last_updated_at,
+        reason,
+        at,
+    };
+
+    for coin in stack.coins.values_mut() {
+        let stale = coin.last_updated_at.filter(|t| rules.max_staleness_secs > 0 && now / 1000 - t > rules.max_staleness_secs);
+        if stale.is_some() {
+            quarantined.push(quarantine(&coin.id, None, None, None, stale, Reason::Stale));
+            coin.prices.clear();
+            continue;
+        }
+
+        let mut currencies: Vec<String> = coin.prices.keys().cloned().collect();
+        currencies.sort();
+        for currency in currencies {
+            let price = coin.prices[&currency];
+            let previous = cache.coins.get(&coin.id).and_then(|c| c.prices.get(&currency)).copied();
+            let reason = if !price.is_finite() || price <= 0.0 {
+                Some(Reason::Invalid)
+            } else {
+                let jumps = |from: &f32| *from > 0.0 && ((price - from) / from).abs() as f64 * 100.0 > rules.max_jump_pct;
+                let confirmed = pending
+                    .iter()
+                    .filter(|q| q.reason == Reason::Jump && q.coin == coin.id && q.currency.as_deref() == Some(currency.as_str()))
+                    .filter_map(|q| q.price)
+                    .any(|p| !jumps(&p));
+                previous
+                    .filter(|p| rules.max_jump_pct > 0.0 && jumps(p) && !confirmed)
+                    .map(|_| Reason::Jump)
+            };
+            if let Some(reason) = reason {
+                quarantined.push(quarantine(&coin.id, Some(&currency), Some(price), previous, coin.last_updated_at, reason));
+                coin.prices.remove(&currency);
+            }
+        }
+        let prices = &coin.prices;
+        coin.derived.retain(|c| prices.contains_key(c));
+    }
+    stack.coins.retain(|_, c| !c.prices.is_empty());
+
+    (stack, quarantined)
+}
+
+// keep_last_good puts back into the screened stack the prices of cache
+// its quarantined prices replaced, so the next tick is compared to and
+// portfolios are valued with the last good prices.
+pub fn keep_last_good(cache: &Stack, mut stack: Stack, quarantined: &[Quarantined]) -> Stack {
+    for q in quarantined {
+        let last = match cache.coins.get(&q.coin) {
+            Some(c) => c,
+            None => continue,
+        };
+        let coin = stack.coins.entry(q.coin.to_owned()).or_insert_with(|| Coin {
+            prices: HashMap::new(),
+            derived: vec![],
+            ..last.clone()
+        });
+        for (currency, price) in &last.prices {
+            if q.currency.as_ref().is_none_or(|c| c == currency) && !coin.prices.contains_key(currency) {
+                coin.prices.insert(currency.to_owned(), *price);
+                if last.derived.contains(currency) {
+                    coin.derived.push(currency.to_owned());
+                }
+            }
+        }
+    }
+    stack.coins.retain(|_, c| !c.prices.is_empty());
+    stack
+}
+
+// save_quarantined upserts quarantined prices in coll.
+// Every price is saved even if one fails, the first error is returned.
+pub fn save_quarantined(coll: &impl Collection<Quarantined>, quarantined: &[Quarantined]) -> Result<(), String> {
+    let mut first_err = None;
+    for q in quarantined {
+        if let Err(err) = coll.save(q.id.to_owned(), q) {
+            first_err.get_or_insert(err);
+        }
+    }
+    match first_err {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::coin::{Coin, Stack};
+    use crate::database::{Collection, MemoryDB};
+    use super::{keep_last_good, save_quarantined, screen, Quarantined, Reason, Rules};
+
+    const RULES: Rules = Rules { max_jump_pct: 50.0, max_staleness_secs: 600 };
+
+    fn gen_stack(prices: Vec<(&str, f32)>, last_updated_at: Option<i64>) -> Stack {
+        let mut stack = Stack::new();
+        stack.id = "coingecko:60000".into();
+        stack.created_at = 60_000;
+        for (id, usd) in prices {
+            stack.coins.insert(id.to_string(), Coin {
+                id: id.to_string(),
+                symbol: id[..3].to_string(),
+                prices: vec![("usd".to_string(), usd)].into_iter().collect(),
+                derived: vec![],
+                last_updated_at,
+            });
+        }
+        stack
+    }
+
+    #[test]
+    fn i_should_quarantine_invalid_prices() {
+        let stack = gen_stack(vec![("bitcoin", 0.0), ("ethereum", f32::NAN), ("dogecoin", -1.0), ("litecoin", 100.0)], None);
+        let (stack, quarantined) = screen("coingecko", stack, &Stack::new(), &[], &RULES, 60_000);
+
+        assert_eq!(stack.coins.keys().collect::<Vec<&String>>(), vec!["litecoin"]);
+        assert_eq!(quarantined.len(), 3);
+        assert!(quarantined.iter().all(|q| q.reason == Reason::Invalid));
+    }
+
+    #[test]
+    fn i_should_hold_jumps_back_a_tick() {
+        let cache = gen_stack(vec![("bitcoin", 100.0), ("ethereum", 100.0)], None);
+        let stack = gen_stack(vec![("bitcoin", 1000.0), ("ethereum", 140.0)], None);
+        let (screened, quarantined) = screen("coingecko", stack, &cache, &[], &RULES, 60_000);
+
+        assert!(!screened.coins.contains_key("bitcoin"));
+        assert_eq!(screened.coins["ethereum"].prices["usd"], 140.0);
+        assert_eq!(quarantined[0].reason, Reason::Jump);
+        assert_eq!((quarantined[0].price, quarantined[0].previous), (Some(1000.0), Some(100.0)));
+        assert_eq!(quarantined[0].id, "coingecko:60000:bitcoin:usd");
+
+        // the next cache keeps the last good price
+        let cache = keep_last_good(&cache, screened, &quarantined);
+        assert_eq!(cache.coins["bitcoin"].prices["usd"], 100.0);
+        assert_eq!(cache.coins["ethereum"].prices["usd"], 140.0);
+
+        // a second, different spike is held back again
+        let (next, spiked) = screen("coingecko", gen_stack(vec![("bitcoin", 5000.0)], None), &cache, &quarantined, &RULES, 60_000);
+        assert!(next.coins.is_empty());
+        assert_eq!((spiked[0].price, spiked[0].previous), (Some(5000.0), Some(100.0)));
+
+        // a jump the next tick confirms passes
+        let (next, confirmed) = screen("coingecko", gen_stack(vec![("bitcoin", 1100.0)], None), &cache, &quarantined, &RULES, 60_000);
+        assert_eq!(next.coins["bitcoin"].prices["usd"], 1100.0);
+        assert!(confirmed.is_empty());
+    }
+
+    #[test]
+    fn i_should_quarantine_stale_coins() {
+        let db = MemoryDB::new();
+        let coll = db.new_collection::<Quarantined>("quarantine");
+        let stack = gen_stack(vec![("terra-luna", 1.0)], Some(0));
+        let (stack, quarantined) = screen("coingecko", stack, &Stack::new(), &[], &RULES, 700_000);
+
+        assert!(stack.coins.is_empty());
+        assert_eq!(quarantined[0].reason, Reason::Stale);
+        assert_eq!(quarantined[0].currency, None);
+        let cache = gen_stack(vec![("terra-luna", 2.0)], Some(0));
+        assert_eq!(keep_last_good(&cache, stack, &quarantined).coins["terra-luna"].prices["usd"], 2.0);
+
+        let fresh = gen_stack(vec![("terra-luna", 1.0)], Some(200));
+        assert_eq!(screen("coingecko", fresh, &Stack::new(), &[], &RULES, 700_000).0.coins.len(), 1);
+        let disabled = Rules { max_jump_pct: 0.0, max_staleness_secs: 0 };
+        assert_eq!(screen("coingecko", gen_stack(vec![("terra-luna", 1.0)], Some(0)), &Stack::new(), &[], &disabled, 700_000).0.coins.len(), 1);
+
+        save_quarantined(&coll, &quarantined).unwrap();
+        save_quarantined(&coll, &quarantined).unwrap();
+        let stored: HashMap<String, Quarantined> = coll.find_all().unwrap().into_iter().map(|q| (q.id.to_owned(), q)).collect();
+        assert_eq!(stored.len(), 1);
+        assert!(stored.contains_key("coingecko:60000:terra-luna:*"));
+    }
+}
@@ -0,0 +1,170 @@
+use std::io::Error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+use log::warn;
+
+// SLICE is the granularity at which a sleep checks for a shutdown request
+const SLICE: Duration = Duration::from_millis(250);
+
+// Due tells why every runs its tick: the interval elapsed,
+// or wake told so in between
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Due {
+    Interval,
+    Wake,
+}
+
+// Shutdown is flagged when the process receives SIGTERM or SIGINT.
+// A second signal, received while already shutting down, exits right away.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    flag: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // install registers the signal handlers on the shutdown flag
+    pub fn install() -> Result<Self, Error> {
+        let shutdown = Self::new();
+
+        for sig in &[SIGTERM, SIGINT] {
+            // order matters: the conditional exit must see the flag
+            // before the first signal raises it
+            flag::register_conditional_shutdown(*sig, 1, shutdown.flag.clone())?;
+            flag::register(*sig, shutdown.flag.clone())?;
+        }
+
+        Ok(shutdown)
+    }
+
+    pub fn request(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn requested(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    // sleep waits for duration, returning early if a shutdown
+    // is requested. Returns false if the sleep was interrupted.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        self.sleep_until(duration, || false)
+    }
+
+    // sleep_until is sleep, also returning early once wake tells so.
+    // Returns false only if a shutdown interrupted the sleep.
+    pub fn sleep_until(&self, duration: Duration, wake: impl Fn() -> bool) -> bool {
+        let start = Instant::now();
+
+        while !self.requested() {
+            let elapsed = start.elapsed();
+            if elapsed >= duration || wake() {
+                return true;
+            }
+            thread::sleep(SLICE.min(duration - elapsed));
+        }
+        false
+    }
+
+    // every runs tick, then waits for interval, until a shutdown is
+    // requested. A failed tick waits as well, so a failing provider isn't
+    // retried in a tight loop. When wake tells so, tick runs in between
+    // with Due::Wake, the interval schedule staying as it was.
+    pub fn every(&self, interval: Duration, wake: impl Fn() -> bool, mut tick: impl FnMut(Due) -> Result<(), String>) {
+        let mut due = Due::Interval;
+        let mut next = Instant::now();
+        while !self.requested() {
+            if let Err(err) = tick(due) {
+                warn!("{}", err);
+            }
+            if due == Due::Interval {
+                next = Instant::now() + interval;
+            }
+            if !self.sleep_until(next.saturating_duration_since(Instant::now()), &wake) {
+                break;
+            }
+            due = if Instant::now() >= next { Due::Interval } else { Due::Wake };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use std::cell::Cell;
+    use super::{Due, Shutdown};
+
+    #[test]
+    fn i_should_sleep_the_whole_duration() {
+        let shutdown = Shutdown::new();
+        assert!(shutdown.sleep(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn i_should_stop_sleeping_on_request() {
+        let shutdown = Shutdown::new();
+        let remote = shutdown.clone();
+        let start = Instant::now();
+
+        std::thread::spawn(move || remote.request());
+        assert!(!shutdown.sleep(Duration::from_secs(60)));
+        assert!(start.elapsed() < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn i_should_wait_after_failed_ticks() {
+        let shutdown = Shutdown::new();
+        let remote = shutdown.clone();
+        let mut ticks = 0;
+        let start = Instant::now();
+
+        shutdown.every(Duration::from_millis(20), || false, |_| {
+            ticks += 1;
+            if ticks == 3 {
+                remote.request();
+            }
+            Err("fetch failed".into())
+        });
+        assert_eq!(ticks, 3);
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn i_should_stop_sleeping_on_wake() {
+        let shutdown = Shutdown::new();
+        let start = Instant::now();
+
+        assert!(shutdown.sleep_until(Duration::from_secs(60), || true));
+        assert!(start.elapsed() < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn i_should_keep_the_schedule_on_wake() {
+        let shutdown = Shutdown::new();
+        let remote = shutdown.clone();
+        let woken = Cell::new(false);
+        let mut dues = vec![];
+        let start = Instant::now();
+
+        shutdown.every(Duration::from_millis(300), || !woken.get(), |due| {
+            dues.push(due);
+            match due {
+                Due::Wake => woken.set(true),
+                Due::Interval if dues.len() > 1 => remote.request(),
+                Due::Interval => {},
+            }
+            Ok(())
+        });
+        assert_eq!(dues, vec![Due::Interval, Due::Wake, Due::Interval]);
+        // the wake didn't push the next tick back
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert!(start.elapsed() < Duration::from_millis(600));
+    }
+}
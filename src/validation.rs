@@ -0,0 +1,515 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+
+use crate::fx::FxProvider;
+use crate::provider::{self, Provide, Provider};
+
+// ROUTE_PLACEHOLDERS lists the placeholders each route may hold
+const ROUTE_PLACEHOLDERS: &[(&str, &[&str])] = &[("coins_history", &["id"])];
+
+// SUPPORTED_CURRENCIES are the vs_currencies CoinGecko quotes prices in
+const SUPPORTED_CURRENCIES: &[&str] = &[
+    "btc", "eth", "ltc", "bch", "bnb", "eos", "xrp", "xlm", "link", "dot", "yfi", "usd", "aed", "ars",
+    "aud", "bdt", "bhd", "bmd", "brl", "cad", "chf", "clp", "cny", "czk", "dkk", "eur", "gbp", "hkd",
+    "huf", "idr", "ils", "inr", "jpy", "krw", "kwd", "lkr", "mmk", "mxn", "myr", "ngn", "nok", "nzd",
+    "php", "pkr", "pln", "rub", "sar", "sek", "sgd", "thb", "try", "twd", "uah", "vef", "vnd", "zar",
+    "xdr", "xag", "xau", "bits", "sats",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// Diagnostic is an issue found in a providers file.
+// line is 1-based, if the issue could be located.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub line: Option<usize>,
+    pub provider: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(line) = self.line {
+            write!(f, "line {}: ", line)?;
+        }
+        if self.severity == Severity::Warning {
+            write!(f, "warning: ")?;
+        }
+        if let Some(provider) = &self.provider {
+            write!(f, "{}: ", provider)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+// Report holds the providers of a valid file
+// along with the warnings found in it
+pub struct Report {
+    pub providers: HashMap<String, Provider>,
+    pub fx_providers: HashMap<String, FxProvider>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+// Locator finds the line of tables and keys in a TOML file
+struct Locator<'a> {
+    content: &'a str,
+}
+
+impl<'a> Locator<'a> {
+    // find gives the first line of table matches accepts
+    fn find(&self, table: &str, matches: impl Fn(&str) -> bool) -> Option<usize> {
+        let mut current = String::new();
+        for (idx, line) in self.content.lines().enumerate() {
+            let line = line.trim();
+            if line.starts_with('[') && line.ends_with(']') {
+                current = line.trim_matches(|c| c == '[' || c == ']').trim().to_string();
+                continue;
+            }
+            if current == table && matches(line) {
+                return Some(idx + 1);
+            }
+        }
+        None
+    }
+
+    fn table(&self, table: &str) -> Option<usize> {
+        let header = format!("[{}]", table);
+        self.content.lines().position(|l| l.trim() == header).map(|i| i + 1)
+    }
+
+    // nth_table gives the line of the nth header of an array of tables
+    fn nth_table(&self, table: &str, n: usize) -> Option<usize> {
+        let header = format!("[[{}]]", table);
+        self.content
+            .lines()
+            .enumerate()
+            .filter(|(_, l)| l.trim() == header)
+            .nth(n)
+            .map(|(i, _)| i + 1)
+    }
+
+    fn key(&self, table: &str, key: &str) -> Option<usize> {
+        self.find(table, |line| is_key(line, key)).or_else(|| self.table(table))
+    }
+
+    // value gives the line of the nth occurrence of a quoted value in
+    // the value of key, which spans several lines for multiline arrays
+    fn value(&self, table: &str, key: &str, value: &str, nth: usize) -> Option<usize> {
+        let start = match self.find(table, |line| is_key(line, key)) {
+            Some(l) => l,
+            None => return self.table(table),
+        };
+        let quoted = format!("\"{}\"", value);
+        let mut seen = 0;
+        let mut depth = 0;
+
+        for (idx, line) in self.content.lines().enumerate().skip(start - 1) {
+            let line = match idx + 1 == start {
+                true => line.split_once('=').map(|(_, v)| v).unwrap_or_default(),
+                false => line,
+            };
+            seen += line.matches(&quoted).count();
+            if seen > nth {
+                return Some(idx + 1);
+            }
+            depth += line.matches('[').count() as i64 - line.matches(']').count() as i64;
+            if depth <= 0 {
+                break;
+            }
+        }
+        Some(start)
+    }
+}
+
+// is_key tells if a line assigns key
+fn is_key(line: &str, key: &str) -> bool {
+    match line.split_once('=') {
+        Some((k, _)) => k.trim().trim_matches('"') == key,
+        None => false,
+    }
+}
+
+// check_base_route tells what's wrong with a base_route, if anything
+fn check_base_route(route: &str) -> Option<String> {
+    let rest = match route.strip_prefix("https://").or_else(|| route.strip_prefix("http://")) {
+        Some(r) => r,
+        None => return Some(format!("base_route {} must start with http:// or https://", route)),
+    };
+    let host = rest.split('/').next().unwrap_or_default();
+    if host.is_empty() {
+        return Some(format!("base_route {} has no host", route));
+    }
+    if route.chars().any(|c| c.is_whitespace() || ['?', '#', '{', '}'].contains(&c)) {
+        return Some(format!("base_route {} must not hold whitespaces, a query or placeholders", route));
+    }
+    if route.ends_with('/') {
+        return Some(format!("base_route {} must not end with /, routes start with one", route));
+    }
+    None
+}
+
+// check_route tells what's wrong with the placeholders of a route, if anything
+fn check_route(name: &str, route: &str) -> Option<String> {
+    if !route.starts_with('/') {
+        return Some(format!("route {} must start with /", name));
+    }
+    let allowed = ROUTE_PLACEHOLDERS
+        .iter()
+        .find(|(r, _)| *r == name)
+        .map(|(_, p)| *p)
+        .unwrap_or_default();
+
+    let mut rest = route;
+    while let Some(start) = rest.find(['{', '}']) {
+        if rest[start..].starts_with('}') {
+            return Some(format!("route {} has an unopened }}", name));
+        }
+        let end = match rest[start..].find('}') {
+            Some(e) => start + e,
+            None => return Some(format!("route {} has an unclosed {{", name)),
+        };
+        let placeholder = &rest[start + 1..end];
+        if !allowed.contains(&placeholder) {
+            return Some(format!("route {} has an unknown placeholder {{{}}}", name, placeholder));
+        }
+        rest = &rest[end + 1..];
+    }
+    None
+}
+
+// is_query_safe tells if a coin id can be put in a query string as is
+fn is_query_safe(id: &str) -> bool {
+    !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric() || ['-', '_', '.'].contains(&c))
+}
+
+fn check_provider(key: &str, provider: &Provider, fx_providers: &HashMap<String, FxProvider>, locator: &Locator) -> Vec<Diagnostic> {
+    let table = format!("providers.{}", key);
+    let routes_table = format!("{}.routes", table);
+    let coins_table = format!("{}.coins", table);
+    let windows_table = format!("{}.windows", table);
+    let discovery_table = format!("{}.discovery", table);
+    let mut found = vec![];
+    let mut push = |severity, line, message| found.push(Diagnostic {
+        severity,
+        line,
+        provider: Some(key.to_string()),
+        message,
+    });
+
+    if let Some(message) = check_base_route(provider.get_base_route()) {
+        push(Severity::Error, locator.key(&table, "base_route"), message);
+    }
+
+    if !provider.get_routes().contains_key("simple_price") {
+        let line = locator.table(&routes_table).or_else(|| locator.table(&table));
+        push(Severity::Error, line, "missing required route simple_price".into());
+    }
+    let mut routes: Vec<(&String, &String)> = provider.get_routes().iter().collect();
+    routes.sort();
+    for (name, route) in routes {
+        if let Some(message) = check_route(name, route) {
+            push(Severity::Error, locator.key(&routes_table, name), message);
+        }
+    }
+
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    for currency in provider.get_currencies() {
+        let nth = seen.entry(currency).or_default();
+        if !SUPPORTED_CURRENCIES.contains(&currency.as_str()) {
+            push(Severity::Error, locator.value(&table, "currencies", currency, *nth), format!("unknown currency {}", currency));
+        }
+        *nth += 1;
+    }
+
+    if let Some(base) = provider.get_base_currency() {
+        let line = locator.key(&table, "base_currency");
+        if !provider.get_currencies().contains(base) {
+            push(Severity::Error, line, format!("base_currency {} is not one of the currencies", base));
+        }
+        match provider.get_fx_provider().map(|name| (name, fx_providers.get(name))) {
+            None => push(Severity::Error, line, "base_currency requires an fx_provider".into()),
+            Some((name, None)) => push(Severity::Error, locator.key(&table, "fx_provider"), format!("unknown fx_provider {}", name)),
+            Some((name, Some(fx_provider))) => {
+                let rated = |c: &String| c == fx_provider.get_base() || fx_provider.get_currencies().contains(c);
+                for currency in provider.get_currencies().iter().filter(|c| !rated(c)) {
+                    push(Severity::Warning, locator.key(&table, "fx_provider"), format!("fx_provider {} doesn't rate {}, it won't be derived", name, currency));
+                }
+            },
+        }
+    }
+
+    let mut coins: Vec<(&String, &String)> = provider.get_coins().iter().collect();
+    coins.sort();
+    let mut symbols: HashMap<String, &str> = HashMap::new();
+    for (id, symbol) in coins {
+        let line = locator.key(&coins_table, id);
+        if !is_query_safe(id) {
+            push(Severity::Error, line, format!("coin id {} holds characters breaking the query string", id));
+        }
+        // distinct coins may share a ticker, so as asset collisions a
+        // shared symbol is a warning, to be solved with coins map
+        match symbols.get(&symbol.to_lowercase()) {
+            Some(other) => push(Severity::Warning, line, format!("symbol {} of {} is already used by {}, map them with coins map if they are the same asset", symbol, id, other)),
+            None => {
+                symbols.insert(symbol.to_lowercase(), id);
+            },
+        }
+    }
+
+    if let Some(message) = provider.get_discovery().and_then(|d| d.check()) {
+        push(Severity::Error, locator.key(&discovery_table, "enabled"), message);
+    }
+
+    for (n, policy) in provider.get_windows().iter().enumerate() {
+        if let Some(message) = policy.check() {
+            push(Severity::Error, locator.nth_table(&windows_table, n), message);
+        }
+    }
+
+    found
+}
+
+// check_fx_provider gives the diagnostics of an fx provider
+fn check_fx_provider(key: &str, provider: &FxProvider, locator: &Locator) -> Vec<Diagnostic> {
+    let table = format!("fx_providers.{}", key);
+    let routes_table = format!("{}.routes", table);
+    let mut found = vec![];
+    let mut push = |line, message| found.push(Diagnostic {
+        severity: Severity::Error,
+        line,
+        provider: Some(key.to_string()),
+        message,
+    });
+
+    if let Some(message) = check_base_route(provider.get_base_route()) {
+        push(locator.key(&table, "base_route"), message);
+    }
+    if !provider.get_routes().contains_key("exchange_rates") {
+        let line = locator.table(&routes_table).or_else(|| locator.table(&table));
+        push(line, "missing required route exchange_rates".into());
+    }
+    let mut routes: Vec<(&String, &String)> = provider.get_routes().iter().collect();
+    routes.sort();
+    for (name, route) in routes {
+        if let Some(message) = check_route(name, route) {
+            push(locator.key(&routes_table, name), message);
+        }
+    }
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    for currency in provider.get_currencies() {
+        let nth = seen.entry(currency).or_default();
+        if !SUPPORTED_CURRENCIES.contains(&currency.as_str()) {
+            push(locator.value(&table, "currencies", currency, *nth), format!("unknown currency {}", currency));
+        }
+        *nth += 1;
+    }
+    let base = provider.get_base();
+    if !SUPPORTED_CURRENCIES.contains(&base.as_str()) {
+        push(locator.key(&table, "base"), format!("unknown currency {}", base));
+    }
+
+    found
+}
+
+// validate parses and checks the content of a providers file
+pub fn validate(content: &str) -> Result<Report, Vec<Diagnostic>> {
+    let parse_error = |err: toml::de::Error| vec![Diagnostic {
+        severity: Severity::Error,
+        line: err.line_col().map(|(line, _)| line + 1),
+        provider: None,
+        message: err.to_string(),
+    }];
+    let providers = provider::parse_toml(content).map_err(parse_error)?;
+    let fx_providers = provider::parse_fx_toml(content).map_err(parse_error)?;
+
+    check_located(providers, fx_providers, &Locator { content })
+}
+
+// check checks providers read from elsewhere than a file,
+// their diagnostics having no line
+pub fn check(providers: HashMap<String, Provider>, fx_providers: HashMap<String, FxProvider>) -> Result<Report, Vec<Diagnostic>> {
+    check_located(providers, fx_providers, &Locator { content: "" })
+}
+
+fn check_located(
+    providers: HashMap<String, Provider>,
+    fx_providers: HashMap<String, FxProvider>,
+    locator: &Locator,
+) -> Result<Report, Vec<Diagnostic>> {
+    let mut keys: Vec<&String> = providers.keys().collect();
+    keys.sort();
+    let mut fx_keys: Vec<&String> = fx_providers.keys().collect();
+    fx_keys.sort();
+    let (errors, warnings): (Vec<Diagnostic>, Vec<Diagnostic>) = keys
+        .into_iter()
+        .flat_map(|key| check_provider(key, &providers[key], &fx_providers, locator))
+        .chain(fx_keys.into_iter().flat_map(|key| check_fx_provider(key, &fx_providers[key], locator)))
+        .partition(|d| d.severity == Severity::Error);
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(Report { providers, fx_providers, warnings })
+}
+
+// validate_file validates the providers file at path
+pub fn validate_file(path: &str) -> Result<Report, Vec<Diagnostic>> {
+    let content = fs::read_to_string(path).map_err(|err| vec![Diagnostic {
+        severity: Severity::Error,
+        line: None,
+        provider: None,
+        message: err.to_string(),
+    }])?;
+    validate(&content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_base_route, check_route, Locator, validate, validate_file, Severity};
+
+    const BAD: &str = r#"[providers]
+    [providers.bad]
+        name="bad"
+        currencies = [
+            "usd",
+            "doge"
+        ]
+        base_route = "api.coingecko.com/api/v3"
+        [providers.bad.routes]
+            ping = "/ping"
+            coins_history = "/coins/{coin}/history"
+        [providers.bad.coins]
+            bitcoin="btc"
+            "bit coin"="btc"
+"#;
+
+    #[test]
+    fn i_should_validate_fixtures() {
+        for path in &["./test/providers-test-1.toml", "./test/providers-test-3.toml", "./providers.toml"] {
+            let report = validate_file(path).unwrap_or_else(|d| panic!("{}: {:?}", path, d));
+            assert!(report.warnings.is_empty());
+        }
+    }
+
+    #[test]
+    fn i_should_report_errors_with_lines() {
+        let errors = validate(BAD).err().unwrap();
+        let found: Vec<(Option<usize>, &str)> = errors.iter().map(|d| (d.line, d.message.as_str())).collect();
+
+        assert_eq!(found, vec![
+            (Some(8), "base_route api.coingecko.com/api/v3 must start with http:// or https://"),
+            (Some(9), "missing required route simple_price"),
+            (Some(11), "route coins_history has an unknown placeholder {coin}"),
+            (Some(6), "unknown currency doge"),
+            (Some(14), "coin id bit coin holds characters breaking the query string"),
+        ]);
+        assert!(errors.iter().all(|d| d.severity == Severity::Error));
+
+        assert_eq!(errors[1].to_string(), "line 9: bad: missing required route simple_price");
+
+        let base = BAD.replace("        base_route", "        base_currency = \"jpy\"\n        base_route");
+        let errors = validate(&base).err().unwrap();
+        let found: Vec<(Option<usize>, &str)> = errors.iter().skip(4).take(2).map(|d| (d.line, d.message.as_str())).collect();
+        assert_eq!(found, vec![
+            (Some(8), "base_currency jpy is not one of the currencies"),
+            (Some(8), "base_currency requires an fx_provider"),
+        ]);
+        let fx = base.replace("base_currency = \"jpy\"", "base_currency = \"usd\"\n        fx_provider = \"ecb\"");
+        let errors = validate(&fx).err().unwrap();
+        assert!(errors.iter().any(|d| (d.line, d.message.as_str()) == (Some(9), "unknown fx_provider ecb")));
+
+        let discovery = format!("{}        [providers.bad.discovery]\n            enabled = true\n", BAD);
+        let errors = validate(&discovery).err().unwrap();
+        assert_eq!(errors.last().map(|d| d.line), Some(Some(16)));
+        assert!(errors.last().unwrap().message.starts_with("discovery needs top_n"));
+
+        let windows = format!("{}        [[providers.bad.windows]]\n            coins = [\"bitcoin\"]\n", BAD);
+        let errors = validate(&windows).err().unwrap();
+        assert_eq!(errors.last().map(|d| (d.line, d.message.as_str())), Some((Some(15), "window needs max_len, max_age_secs or both")));
+
+        let empty_window = format!("{}        [[providers.bad.windows]]\n            max_len = 0\n", BAD);
+        let errors = validate(&empty_window).err().unwrap();
+        assert_eq!(errors.last().map(|d| (d.line, d.message.as_str())), Some((Some(15), "window max_len must be greater than 0, got 0")));
+    }
+
+    #[test]
+    fn i_should_report_duplicate_symbols_as_warnings() {
+        let content = BAD
+            .replace("api.coingecko", "https://api.coingecko")
+            .replace("ping", "simple_price")
+            .replace("{coin}", "{id}")
+            .replace("doge", "eur")
+            .replace("\"bit coin\"", "wrapped-bitcoin");
+        let report = validate(&content).unwrap();
+
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(report.warnings[0].line, Some(14));
+    }
+
+    #[test]
+    fn i_should_check_fx_providers() {
+        let content = r#"[providers]
+[fx_providers]
+    [fx_providers.bad]
+        name="bad"
+        base_route = "https://api.coingecko.com/api/v3"
+        base = "usd"
+        currencies = ["eur", "xxx"]
+        [fx_providers.bad.routes]
+            ping = "/ping"
+"#;
+        let errors = validate(content).err().unwrap();
+        let found: Vec<(Option<usize>, &str)> = errors.iter().map(|d| (d.line, d.message.as_str())).collect();
+        assert_eq!(found, vec![
+            (Some(8), "missing required route exchange_rates"),
+            (Some(7), "unknown currency xxx"),
+        ]);
+
+        let report = validate_file("./test/providers-test-3.toml").unwrap();
+        assert!(report.fx_providers.contains_key("ecb"));
+    }
+
+    #[test]
+    fn i_should_locate_repeated_values() {
+        let content = r#"[providers]
+    [providers.x]
+        name = "xxx"
+        currencies = ["usd", "xxx",
+            "xxx"]
+        base_route = "https://api.coingecko.com/api/v3"
+        [providers.x.routes]
+            simple_price = "/simple/price"
+"#;
+        let locator = Locator { content };
+        assert_eq!(locator.value("providers.x", "currencies", "xxx", 2), Some(4));
+        assert_eq!(locator.value("providers.x", "base", "xxx", 0), Some(2));
+
+        let errors = validate(content).err().unwrap();
+        let found: Vec<(Option<usize>, &str)> = errors.iter().map(|d| (d.line, d.message.as_str())).collect();
+        assert_eq!(found, vec![(Some(4), "unknown currency xxx"), (Some(5), "unknown currency xxx")]);
+    }
+
+    #[test]
+    fn i_should_report_toml_errors_with_lines() {
+        let errors = validate("[providers]\n  [providers.x]\n    name = \n").err().unwrap();
+        assert_eq!(errors[0].line, Some(3));
+        assert!(validate_file("pouet").err().unwrap()[0].line.is_none());
+    }
+
+    #[test]
+    fn i_should_check_routes() {
+        assert!(check_base_route("https://api.coingecko.com/api/v3").is_none());
+        assert!(check_base_route("https:///api").is_some());
+        assert!(check_base_route("https://api.coingecko.com/").is_some());
+        assert!(check_route("coins_history", "/coins/{id}/history").is_none());
+        assert!(check_route("simple_price", "/simple/{id}").is_some());
+        assert!(check_route("coins_history", "/coins/{id/history").is_some());
+        assert!(check_route("ping", "ping").is_some());
+    }
+}
@@ -0,0 +1,96 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use log::{info, warn};
+use notify_debouncer_mini::notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{new_debouncer, DebounceEventResult, Debouncer};
+
+// DEBOUNCE gathers the bursts of events a single save produces
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+// FileWatch flags changes of a file. The directory holding the file
+// is watched, so editors replacing the file on save are caught too.
+pub struct FileWatch {
+    changed: Arc<AtomicBool>,
+    // dropping the debouncer stops the watch
+    _debouncer: Debouncer<RecommendedWatcher>,
+}
+
+impl FileWatch {
+    pub fn new(path: &str) -> Result<Self, String> {
+        let file = absolute(Path::new(path))?;
+        let dir = match file.parent() {
+            Some(d) => d.to_path_buf(),
+            None => return Err(format!("{} has no parent directory", path)),
+        };
+
+        let changed = Arc::new(AtomicBool::new(false));
+        let flag = changed.clone();
+        let target = file.to_owned();
+        let mut debouncer = new_debouncer(DEBOUNCE, move |res: DebounceEventResult| match res {
+            Ok(events) => {
+                if events.iter().any(|e| e.path == target) {
+                    flag.store(true, Ordering::SeqCst);
+                }
+            },
+            Err(err) => warn!("Could not watch {}: {}", target.display(), err),
+        })
+        .map_err(|err| err.to_string())?;
+
+        debouncer
+            .watcher()
+            .watch(&dir, RecursiveMode::NonRecursive)
+            .map_err(|err| err.to_string())?;
+        info!("Watching {} for changes", file.display());
+
+        Ok(Self { changed, _debouncer: debouncer })
+    }
+
+    // pending tells if the file changed since the last take
+    pub fn pending(&self) -> bool {
+        self.changed.load(Ordering::SeqCst)
+    }
+
+    // take tells if the file changed, clearing the flag
+    pub fn take(&self) -> bool {
+        self.changed.swap(false, Ordering::SeqCst)
+    }
+}
+
+fn absolute(path: &Path) -> Result<PathBuf, String> {
+    if path.is_absolute() {
+        return Ok(path.to_path_buf());
+    }
+    std::env::current_dir()
+        .map(|dir| dir.join(path))
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::{Duration, Instant};
+    use super::FileWatch;
+
+    #[test]
+    fn i_should_flag_file_changes() {
+        let dir = std::env::temp_dir().join(format!("coinrd-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("providers.toml");
+        fs::write(&file, "[providers]\n").unwrap();
+
+        let watch = FileWatch::new(file.to_str().unwrap()).unwrap();
+        assert!(!watch.take());
+        fs::write(dir.join("other.toml"), "").unwrap();
+        fs::write(&file, "[providers]\n\n").unwrap();
+
+        let start = Instant::now();
+        while !watch.pending() && start.elapsed() < Duration::from_secs(10) {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert!(watch.take());
+        assert!(!watch.pending());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use serde::{Serialize, Deserialize};
+
+use crate::discovery::glob_match;
+
+// Window bounds the prices entries a latest_entries document keeps,
+// by count and/or by age. Unset bounds don't evict anything.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Window {
+    pub max_len: Option<usize>,
+    pub max_age_ms: Option<i64>,
+}
+
+impl Window {
+    pub fn count(max_len: usize) -> Self {
+        Self { max_len: Some(max_len), max_age_ms: None }
+    }
+}
+
+// WindowPolicy sets the window of the coins it lists, as glob patterns
+// (* and ?). A policy without coins applies to every other coin.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct WindowPolicy {
+    #[serde(default)]
+    pub coins: Vec<String>,
+    pub max_len: Option<usize>,
+    pub max_age_secs: Option<i64>,
+}
+
+impl WindowPolicy {
+    fn window(&self) -> Window {
+        Window {
+            max_len: self.max_len,
+            max_age_ms: self.max_age_secs.map(|s| s * 1000),
+        }
+    }
+
+    // check tells what's wrong with the policy, if anything
+    pub fn check(&self) -> Option<String> {
+        if self.max_len.is_none() && self.max_age_secs.is_none() {
+            return Some("window needs max_len, max_age_secs or both".into());
+        }
+        if self.max_len == Some(0) {
+            return Some("window max_len must be greater than 0, got 0".into());
+        }
+        if let Some(age) = self.max_age_secs.filter(|a| *a <= 0) {
+            return Some(format!("window max_age_secs must be greater than 0, got {}", age));
+        }
+        None
+    }
+}
+
+// resolve gives the window of a coin: the one of the first policy
+// listing it, else the one of the first policy without coins, else
+// a window of default_len entries
+pub fn resolve(policies: &[WindowPolicy], coin_id: &str, default_len: usize) -> Window {
+    policies
+        .iter()
+        .find(|p| p.coins.iter().any(|pattern| glob_match(pattern, coin_id)))
+        .or_else(|| policies.iter().find(|p| p.coins.is_empty()))
+        .map(|p| p.window())
+        .unwrap_or_else(|| Window::count(default_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve, Window, WindowPolicy};
+
+    fn policy(coins: Vec<&str>, max_len: Option<usize>, max_age_secs: Option<i64>) -> WindowPolicy {
+        WindowPolicy { coins: coins.into_iter().map(|c| c.to_string()).collect(), max_len, max_age_secs }
+    }
+
+    #[test]
+    fn i_should_resolve_windows() {
+        let policies = vec![
+            policy(vec!["bitcoin", "wrapped-*"], Some(100), None),
+            policy(vec![], None, Some(86400)),
+        ];
+
+        assert_eq!(resolve(&policies, "wrapped-bitcoin", 2), Window::count(100));
+        assert_eq!(resolve(&policies, "dogecoin", 2), Window { max_len: None, max_age_ms: Some(86_400_000) });
+        assert_eq!(resolve(&policies[..1], "dogecoin", 2), Window::count(2));
+    }
+
+    #[test]
+    fn i_should_check_policies() {
+        assert!(policy(vec![], None, None).check().is_some());
+        assert!(policy(vec![], Some(2), Some(0)).check().is_some());
+        assert!(policy(vec![], Some(2), Some(-60)).check().is_some());
+        assert!(policy(vec![], Some(0), None).check().is_some());
+        assert!(policy(vec![], Some(0), Some(60)).check().is_some());
+        assert!(policy(vec![], Some(2), Some(60)).check().is_none());
+    }
+}